@@ -6,10 +6,10 @@
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::{thread, time::Duration};
+use std::thread;
 
-use serde::Deserialize;
-use tauri::api::path;
+use serde::{Deserialize, Serialize};
+use tauri::api::{dialog, path};
 use tauri::command;
 use tauri::{window::Window, RunEvent, State};
 
@@ -17,13 +17,21 @@ use tauri::{window::Window, RunEvent, State};
 use tauri::ActivationPolicy;
 
 use ssh_tunnel::{
-    config::SshConfig,
+    config::{DynamicForward, SshConfig, ANY_PORT},
+    error::{Error, Result},
+    host_keys::{self, HostKey},
     logger,
-    status::{Result, SshStatus},
-    tunnel::{ChildProc, SshTunnel, TunnelChild},
+    profiles::Profiles,
+    reconnect::{self, ReconnectPolicy, Supervisor},
+    ssh_config::{OpenSshConfig, SshHost},
+    status::SshStatus,
+    tunnel::TunnelChild,
     SshHandle,
 };
 
+/// The time (in seconds) that a tunnel is given to connect, before it's reported as timed out
+const CONNECT_TIMEOUT: u32 = 30;
+
 fn main() {
     let mut logpath = path::home_dir().unwrap_or_else(|| PathBuf::from("."));
 
@@ -59,7 +67,15 @@ fn main() {
         // Tells the framework that it needs to manage the context
         .manage(context.clone())
         // Sets the handler functions
-        .invoke_handler(tauri::generate_handler![start_tunnel, end_tunnel,])
+        .invoke_handler(tauri::generate_handler![
+            start_tunnel,
+            end_tunnel,
+            list_profiles,
+            save_profile,
+            start_profile,
+            list_ssh_hosts,
+            tunnel_command,
+        ])
         // Builds the app
         .build(tauri::generate_context!())
         .map_err(|err| {
@@ -82,13 +98,10 @@ fn main() {
     })
 }
 
-/// The maximum number of reconnect tries to make while attempting to reconnect.
-const MAX_RETRIES: u32 = 5;
-
 /// Maintains the app context, with all of the parameters needed to track the system state.
 struct ContextInner {
-    /// Container for the tunnel process
-    tunnel: Option<SshTunnel<TunnelChild>>,
+    /// Supervisor of the tunnel process, which reconnects it when it drops
+    supervisor: Option<Supervisor<TunnelChild>>,
 
     /// Handle for the app window
     window: Option<Window>,
@@ -96,21 +109,18 @@ struct ContextInner {
     /// The current status of the ssh tunnel
     status: SshStatus,
 
-    /// Number of connection retries left
-    retries: u32,
-
-    /// Whether the tunnel is currently trying to reconnect
-    reconnecting: bool,
+    /// Whether the tunnel was ended while it was starting, before it had a supervisor to stop (while the host key dialog
+    /// is open, for instance)
+    cancelled: bool,
 }
 
 impl ContextInner {
     fn new() -> Self {
         ContextInner {
-            tunnel: None,
+            supervisor: None,
             window: None,
             status: SshStatus::Ready,
-            retries: 0,
-            reconnecting: false,
+            cancelled: false,
         }
     }
 }
@@ -136,7 +146,7 @@ impl Context {
     fn lock(&self) -> Result<MutexGuard<ContextInner>> {
         self.0
             .lock()
-            .map_err(|err| SshStatus::AppError(format!("Failed to lock context: {err}")))
+            .map_err(|_| Error::Poisoned("context".to_string()))
     }
 
     /// Locks the inner context and returns its reference
@@ -148,9 +158,9 @@ impl Context {
     fn panic_lock(&self) -> MutexGuard<ContextInner> {
         match self.lock() {
             Ok(inner) => inner,
-            Err(status) => {
-                log::error!("Panicked trying to lock: {status}");
-                panic!("{status}");
+            Err(err) => {
+                log::error!("Panicked trying to lock: {err}");
+                panic!("{err}");
             }
         }
     }
@@ -186,83 +196,66 @@ impl Context {
         };
     }
 
-    /// Sets the context's tunnel
-    ///
-    /// # Panics
-    ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    fn set_tunnel(&self, tunnel: SshTunnel<TunnelChild>) {
-        self.panic_lock().tunnel = Some(tunnel);
-    }
-
-    /// Retrieves the context's tunnel
-    ///
-    /// # Panics
-    ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    fn get_tunnel(&self) -> Option<SshTunnel<TunnelChild>> {
-        self.panic_lock().tunnel.as_ref().cloned()
-    }
-
-    /// Returns the number of retries left
+    /// Emits the local ports that were picked for the tunnel's forwards to the front end
     ///
-    /// # Panics
-    ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    fn get_retries(&self) -> u32 {
-        self.panic_lock().retries
+    /// These are sent on their own `tunnel_ports` event, since they aren't a change in the tunnel's status.
+    fn emit_ports(&self, ports: &[(String, u32)]) {
+        let inner = self.panic_lock();
+        log::info!("Assigned local ports: {:?}", ports);
+        let ports: Vec<AssignedPort> = ports
+            .iter()
+            .map(|(forward, port)| AssignedPort {
+                forward: forward.clone(),
+                port: *port,
+            })
+            .collect();
+        if let Some(window) = inner.window.as_ref() {
+            if let Err(err) = window.emit("tunnel_ports", Some(ports)) {
+                log::error!("Failed to emit assigned ports: {err}");
+            }
+        }
     }
 
-    /// Returns the current number of retires and then decrements it (like the --x operator in c)
-    ///
-    /// # Panics
+    /// Emits a warning that the tunnel reported while running to the front end
     ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    fn decr_retries(&self) -> u32 {
-        let mut inner = self.panic_lock();
-        if inner.retries > 0 {
-            inner.retries -= 1;
+    /// These are sent on their own `tunnel_warning` event, since the tunnel stays connected.
+    fn emit_warning(&self, warning: &str) {
+        let inner = self.panic_lock();
+        log::warn!("Tunnel warning: {warning}");
+        if let Some(window) = inner.window.as_ref() {
+            if let Err(err) = window.emit("tunnel_warning", Some(warning.to_string())) {
+                log::error!("Failed to emit tunnel warning: {err}");
+            }
         }
-        inner.retries
     }
 
-    /// Sets the number of retries left
-    ///
-    /// # Panics
-    ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    // fn set_retries(&self, retries: u32) {
-    //     self.panic_lock().retries = retries;
-    // }
-
-    /// Returns true if we are in the reconnecting state
+    /// Marks whether the tunnel that is starting has been ended
     ///
     /// # Panics
     ///
     /// Panics if the lock on the context fails. (See panic_lock())
-    fn reconnecting(&self) -> bool {
-        self.panic_lock().reconnecting
+    fn set_cancelled(&self, cancelled: bool) {
+        self.panic_lock().cancelled = cancelled;
     }
 
-    /// Puts the context into the reconnecting state
+    /// Checks whether the tunnel that is starting has been ended
     ///
     /// # Panics
     ///
     /// Panics if the lock on the context fails. (See panic_lock())
-    fn start_reconnect(&self) {
-        let mut inner = self.panic_lock();
-        inner.reconnecting = true;
-        inner.retries = MAX_RETRIES;
+    fn is_cancelled(&self) -> bool {
+        self.panic_lock().cancelled
     }
+}
 
-    /// Takes the context out of the reconnecting state
-    ///
-    /// # Panics
-    ///
-    /// Panics if the lock on the context fails. (See panic_lock())
-    fn stop_reconnect(&self) {
-        self.panic_lock().reconnecting = false;
-    }
+/// A local port that was picked for one of the tunnel's forwards, as it's sent to the front end
+#[derive(Serialize, Clone, Debug)]
+struct AssignedPort {
+    /// The forward's ssh cli option, such as `-L 54123:localhost:5432`
+    forward: String,
+
+    /// The local port the forward listens on
+    port: u32,
 }
 
 /// User settings passed down from the GUI
@@ -272,25 +265,39 @@ struct UserSettings<'a> {
     user: &'a str,
     port: &'a str,
     key_path: &'a str,
+
+    /// Local port for an optional SOCKS proxy, opened alongside the port forward
+    #[serde(default, borrow)]
+    socks_port: Option<&'a str>,
 }
 
 impl UserSettings<'_> {
     /// Converts the user settings to an SshConfig object
-    fn to_config(&self) -> result::Result<SshConfig, std::num::ParseIntError> {
-        let port = self.port.parse()?;
-        let flags = vec!["-t", "-t"];
-
-        Ok(SshConfig::new(
-            self.host,
-            self.user,
-            self.key_path,
-            "localhost",
-            port,
-            5432,
-            10,
-            &flags,
-        ))
+    fn to_config(&self) -> Result<SshConfig> {
+        let port = parse_port(self.port)?;
+
+        let mut builder = SshConfig::builder(self.host, self.user, self.key_path)
+            .local_forward(port, 5432)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .flag("-N");
+
+        if let Some(socks_port) = self.socks_port.filter(|p| !p.is_empty()) {
+            builder = builder.dynamic_forward(DynamicForward::new(None, parse_port(socks_port)?));
+        }
+
+        builder.build()
+    }
+}
+
+/// Parses a port number entered in the GUI
+///
+/// A blank port picks any free port (see [ANY_PORT]).
+fn parse_port(port: &str) -> Result<u32> {
+    if port.trim().is_empty() {
+        return Ok(ANY_PORT);
     }
+    port.parse()
+        .map_err(|_| Error::Config("Illegal port value".to_string()))
 }
 
 /// Command hook to start the tunnel process
@@ -302,154 +309,231 @@ impl UserSettings<'_> {
 /// # Returns
 ///
 /// Returns a string containing the status signal that the JS front end can use to check the status of the command. This will
-/// always either be "CONNECTING" or "ERROR: <err message>".
+/// either be "CONNECTING", or the signal of the error if the settings aren't valid. The tunnel is started in the background
+/// (see [start_checked_tunnel]), so any failure to start it is emitted as a status.
 #[command]
 fn start_tunnel(settings: UserSettings<'_>, context: State<'_, Context>) -> String {
     let config = match settings.to_config() {
         Ok(cfg) => cfg,
-        Err(_err) => {
-            let status = SshStatus::ConfigError("Illegal port value".to_string());
+        Err(err) => {
+            let status = err.to_status();
             context.emit_status(status.clone());
             return status.to_signal();
         }
     };
 
     log::info!("Starting tunnel: {:?}", settings);
-    let result = spawn_new_tunnel(config, (*context).clone());
-    manage_spawn_result(result, (*context).clone())
+    start_checked_tunnel(config, (*context).clone())
 }
 
-/// Spawns a new tunnel process
+/// Command hook to list the names of the saved tunnel profiles
 ///
-/// The process status resolving callback that's passed into the process watching thread will check the status
-/// and spawn an attempt_reconnect thread if it receives a `SshStatus::Dropped` status. It will then emit the
-/// captured status back to the front end.
+/// The profiles are shared with the CLI and any other users of the [Profiles] store.
 ///
-/// # Errors
+/// # Returns
 ///
-/// If the tunnel process fails to spawn or if it fails to acquire a lock on the tunnel's mutex, it will return an
-/// [SshStatus::AppError].
+/// Returns the profile names in alphabetical order, or an "ERROR: <err message>" signal if the store can't be loaded.
+#[command]
+fn list_profiles() -> result::Result<Vec<String>, String> {
+    Profiles::load_default()
+        .map(|profiles| profiles.names().map(String::from).collect())
+        .map_err(|err| err.to_status().to_signal())
+}
+
+/// Command hook to save the user settings as a named tunnel profile
 ///
 /// # Returns
 ///
-/// For an explanation of the return values, see the documentation on [ssh_tunnel::start_and_watch_ssh_tunnel].
-fn spawn_new_tunnel(
-    config: SshConfig,
-    context: Context,
-) -> Result<(SshTunnel<TunnelChild>, SshHandle)> {
-    log::debug!("Spawning new tunnel");
-    let context_thread = context.clone();
-    let config_thread = Arc::new(Mutex::new(config.clone()));
-    let callback = Arc::new(Mutex::new(move |status| {
-        let status = match status {
-            SshStatus::Dropped => {
-                // Attempt to reconnect in separate thread
-                log::debug!("Dropped tunnel connection");
-                context.start_reconnect();
-                spawn_reconnect(config_thread.clone(), context_thread.clone());
-                SshStatus::Reconnecting
-            }
-            SshStatus::Unreachable => {
-                if context.decr_retries() > 0 {
-                    // Continue trying to reconnect
-                    log::debug!("Continuing reconnect attempt");
-                    spawn_reconnect(config_thread.clone(), context_thread.clone());
-                    SshStatus::Reconnecting
-                } else if context.reconnecting() {
-                    // We failed to reconnect, so we have dropped
-                    log::debug!("Aborting reconnect attempt");
-                    context.stop_reconnect();
-                    SshStatus::Dropped
-                } else {
-                    SshStatus::Unreachable
-                }
-            }
-            _ => status,
-        };
+/// Returns the "READY" signal if the profile was saved, or the signal of the error status if it wasn't.
+#[command]
+fn save_profile(name: &str, settings: UserSettings<'_>) -> String {
+    let result = settings.to_config().and_then(|config| {
+        let mut profiles = Profiles::load_default()?;
+        profiles.insert(name, config);
+        profiles.save()
+    });
 
-        context_thread.emit_status(status)
-    }));
-    ssh_tunnel::start_and_watch_ssh_tunnel(config, callback, false)
+    match result {
+        Ok(_) => {
+            log::info!("Saved profile {name}");
+            SshStatus::Ready.to_signal()
+        }
+        Err(err) => err.to_status().to_signal(),
+    }
+}
+
+/// Command hook to start the tunnel process from a saved profile
+///
+/// This behaves exactly like [start_tunnel], except that the config is loaded from the named profile.
+#[command]
+fn start_profile(name: &str, context: State<'_, Context>) -> String {
+    let config = Profiles::load_default().and_then(|profiles| {
+        profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::Config(format!("No such profile: {name}")))
+    });
+
+    let config = match config {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            let status = err.to_status();
+            context.emit_status(status.clone());
+            return status.to_signal();
+        }
+    };
+
+    log::info!("Starting tunnel from profile: {name}");
+    start_checked_tunnel(config, (*context).clone())
 }
 
-/// Checks the result sent from [start_tunnel] or [attempt_reconnect] and updates the status on the front end.
+/// Command hook to list the hosts that can be imported from the user's ssh config (`~/.ssh/config`)
 ///
-/// If the process completely failed to spawn, then the error status will be forwarded (emitted) to the front end. Otherwise,
-/// one of the **Transition** states ([SshStatus::Connecting] or [SshStatus::Reconnecting]) will be emitted, depending on the
-/// current state of the context.
+/// Each host carries the settings that ssh would use for it, so that the connect screen can fill in the host, user, key
+/// path and jump hosts.
 ///
 /// # Returns
 ///
-/// A String signal version of the final status. This is only used by [start_tunnel], which returns this status for some
-/// checks by the front end.
-fn manage_spawn_result(
-    result: Result<(SshTunnel<TunnelChild>, SshHandle)>,
-    context: Context,
-) -> String {
-    let status = match result {
-        Ok((tnl, _hndl)) => {
-            context.set_tunnel(tnl);
-            if context.reconnecting() {
-                SshStatus::Reconnecting
-            } else {
-                SshStatus::Connecting
-            }
-        }
-        Err(status) => {
-            log::error!("Error during spawn: {:?}", status);
-            status
-        }
-    };
+/// Returns the hosts in the order that they appear in the ssh config, or an "ERROR: <err message>" signal if the config
+/// can't be loaded.
+#[command]
+fn list_ssh_hosts() -> result::Result<Vec<SshHost>, String> {
+    let ssh_config = OpenSshConfig::load_default().map_err(|err| err.to_status().to_signal())?;
+    Ok(ssh_config
+        .hosts()
+        .iter()
+        .map(|alias| ssh_config.host(alias))
+        .collect())
+}
 
-    context.emit_status(status.clone());
-    status.to_signal()
+/// Command hook to render the user settings as a copy-pasteable `ssh` command line
+///
+/// This lets the user reproduce a misbehaving tunnel in a terminal. Secrets are redacted (see
+/// [SshConfig::to_command_line]).
+///
+/// # Returns
+///
+/// Returns the command line, or the signal of the error status if the settings aren't valid.
+#[command]
+fn tunnel_command(settings: UserSettings<'_>) -> result::Result<String, String> {
+    settings
+        .to_config()
+        .map(|config| config.to_command_line())
+        .map_err(|err| err.to_status().to_signal())
 }
 
-/// Spawns a reconnect attempt thread
-fn spawn_reconnect(config: Arc<Mutex<SshConfig>>, context: Context) {
+/// Makes sure that the end host's key is trusted, and then spawns the tunnel
+///
+/// If the host's key isn't known yet, the user is shown its fingerprint in a dialog (see [ask_host_key]), and the tunnel is
+/// only spawned if they trust it. The dialog would block the main thread, so this all runs on its own thread, and the
+/// result is emitted to the front end by [manage_spawn_result]. If the user ends the tunnel while the dialog is open, it
+/// isn't spawned at all.
+///
+/// # Returns
+///
+/// Returns the "CONNECTING" signal.
+fn start_checked_tunnel(config: SshConfig, context: Context) -> String {
+    context.set_cancelled(false);
+    context.emit_status(SshStatus::Connecting);
     thread::spawn(move || {
-        attempt_reconnect(config, context);
+        let checked = host_keys::check_host_key(&config, |key| ask_host_key(key, &context));
+        if context.is_cancelled() {
+            log::info!("The tunnel was ended before it was spawned");
+            context.emit_status(SshStatus::Ready);
+            return;
+        }
+        let result = checked.and_then(|_| spawn_new_tunnel(config, context.clone()));
+        manage_spawn_result(result, context);
     });
+    SshStatus::Connecting.to_signal()
 }
 
-/// Makes a single attempt to reconnect to the ssh server if the tunnel process is dropped
+/// Asks the user whether to trust a host key that isn't known yet, with a dialog showing its SHA256 fingerprint
+fn ask_host_key(key: &HostKey, context: &Context) -> bool {
+    // The context isn't kept locked while the dialog is open
+    let window = context.panic_lock().window.clone();
+    dialog::blocking::ask(
+        window.as_ref(),
+        "Unknown host key",
+        format!(
+            "The authenticity of host {} can't be established.\n\n\
+             {} key fingerprint:\n{}\n\n\
+             Do you trust this host?",
+            key.host(),
+            key.key_type(),
+            key.fingerprint()
+        ),
+    )
+}
+
+/// Spawns a new tunnel process, supervised so that it's reconnected when it drops
+///
+/// The status callback emits the statuses of the tunnel (including the [SshStatus::Reconnecting] attempts of the supervisor)
+/// to the front end. The local ports that were picked for the forwards and the warnings of a running tunnel are emitted on
+/// their own events.
+///
+/// # Errors
+///
+/// See the errors of [ssh_tunnel::start_and_watch_ssh_tunnel].
+///
+/// # Returns
 ///
-/// The immediate result of the process spawn will be emitted to the JS front end. Once the ssh tunnel is fully connected (or
-/// fails), the final status of that connection will be emitted.
-fn attempt_reconnect(config: Arc<Mutex<SshConfig>>, context: Context) {
-    thread::sleep(Duration::from_secs(3)); // Wait 3 seconds to try to reconnect
+/// For an explanation of the return values, see the documentation on [reconnect::supervise].
+fn spawn_new_tunnel(
+    config: SshConfig,
+    context: Context,
+) -> Result<(Supervisor<TunnelChild>, SshHandle)> {
+    log::debug!("Spawning new tunnel");
 
-    let retries = context.get_retries();
-    log::info!("Attempting to reconnect. {retries} retries left");
+    let callback = Arc::new(Mutex::new(move |status| match status {
+        SshStatus::PortsAssigned(ports) => context.emit_ports(&ports),
+        // The tunnel is still connected, so the status doesn't change
+        SshStatus::Degraded(warning) => context.emit_warning(&warning),
+        _ => context.emit_status(status),
+    }));
+    reconnect::supervise(config, ReconnectPolicy::default(), callback)
+}
 
-    let cfg = match config.lock() {
-        Ok(cfg) => cfg,
+/// Checks the result sent from [start_checked_tunnel] and updates the status on the front end.
+///
+/// If the process completely failed to spawn (or the user didn't trust the host's key), then the error status will be
+/// forwarded (emitted) to the front end. Otherwise, the supervisor is kept, and the front end stays in the **Transition**
+/// state [SshStatus::Connecting] until the tunnel reports its status. A tunnel that was ended while it was spawning is stopped
+/// right away.
+fn manage_spawn_result(result: Result<(Supervisor<TunnelChild>, SshHandle)>, context: Context) {
+    match result {
+        Ok((supervisor, _hndl)) => {
+            let mut inner = context.panic_lock();
+            if inner.cancelled {
+                // Stopping the supervisor reports the tunnel's status, which locks the context
+                drop(inner);
+                log::info!("The tunnel was ended while it was spawning");
+                supervisor.stop();
+            } else {
+                inner.supervisor = Some(supervisor);
+            }
+        }
         Err(err) => {
-            context.emit_status(SshStatus::AppError(format!("Failed to lock config: {err}")));
-            return;
+            log::error!("Error during spawn: {err}");
+            context.emit_status(err.to_status());
         }
-    };
-
-    let result = spawn_new_tunnel(cfg.clone(), context.clone());
-    manage_spawn_result(result, context);
+    }
 }
 
-/// Kills the tunnel process if it's running
+/// Kills the tunnel process if it's running, and stops it from reconnecting
 ///
-/// The exit status of the process will be emitted to the JS front end automatically when the child process ends. If the
-/// tunnel's mutex fails to lock (unlikely), an [SshStatus::AppError] will be emitted instead.
+/// The exit status of the process will be emitted to the JS front end automatically when the child process ends. A tunnel
+/// that is still starting (see [start_checked_tunnel]) is marked as cancelled, so it's never left running.
 fn kill_tunnel(context: Context) {
     log::info!("Killing tunnel");
-    match context.get_tunnel() {
-        Some(tunnel) => match tunnel.lock() {
-            Ok(mut child) => {
-                child.kill();
-            }
-            Err(err) => {
-                context.emit_status(SshStatus::AppError(format!("Failed to kill tunnel: {err}")))
-            }
-        },
-        None => {} // Just ignore it if there is no tunnel
+    let supervisor = {
+        let mut inner = context.panic_lock();
+        inner.cancelled = true;
+        inner.supervisor.clone()
+    };
+    // Just ignore it if there is no tunnel
+    if let Some(supervisor) = supervisor {
+        supervisor.stop();
     }
 }
 
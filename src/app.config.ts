@@ -36,6 +36,16 @@ export const appStatus = {
 		icon: 'ok',
 	},
 
+	/**
+	 *  SSH connected, but the health check can't reach the service behind the tunnel
+	 *  NOTE: Should also contain the reason that the check failed appended by a colon
+	 *  (a passing check is sent as HEALTHY, which is shown as CONNECTED)
+	 * */
+	UNHEALTHY: {
+		status: 'Service Not Responding',
+		icon: 'alert',
+	},
+
 	/**
 	 *  Attempting to reconnect to SSH after connection was dropped
 	 * */
@@ -60,6 +70,14 @@ export const appStatus = {
 		icon: 'err',
 	},
 
+	/**
+	 *  Server accepted the connection, but didn't finish connecting within the connect timeout
+	 * */
+	TIMED_OUT: {
+		status: 'Connection Timed Out',
+		icon: 'err',
+	},
+
 	/**
 	 *  Server at IP address denied the connection
 	 * */
@@ -68,6 +86,110 @@ export const appStatus = {
 		icon: 'err',
 	},
 
+	/**
+	 *  Server at IP address refused the connection (nothing is listening on the ssh port)
+	 * */
+	REFUSED: {
+		status: 'Connection Refused',
+		icon: 'err',
+	},
+
+	/**
+	 *  Server gave up after too many keys were offered
+	 * */
+	TOO_MANY_AUTH_FAILURES: {
+		status: 'Invalid Creds',
+		icon: 'err',
+	},
+
+	/**
+	 *  Server's host key is different from the known one
+	 *  NOTE: Should also contain the fingerprint of the new key appended by a colon
+	 * */
+	HOST_KEY_CHANGED: {
+		status: 'Host Key Changed',
+		icon: 'alert',
+	},
+
+	/**
+	 *  Server's host key isn't known, and couldn't be verified
+	 * */
+	HOST_KEY_UNVERIFIED: {
+		status: 'Unknown Host Key',
+		icon: 'err',
+	},
+
+	/**
+	 *  User didn't trust the server's host key, so the tunnel wasn't started
+	 *  NOTE: Should also contain the fingerprint of the key appended by a colon
+	 * */
+	HOST_KEY_REJECTED: {
+		status: 'Host Key Rejected',
+		icon: 'err',
+	},
+
+	/**
+	 *  SSH key file is accessible by other users, so ssh refused to use it
+	 *  NOTE: Should also contain the key path appended by a colon
+	 * */
+	UNPROTECTED_KEY: {
+		status: 'Unprotected Key',
+		icon: 'err',
+	},
+
+	/**
+	 *  A jump host was unable to be reached
+	 *  NOTE: Should also contain the hop number and host appended by a colon
+	 * */
+	JUMP_UNREACHABLE: {
+		status: 'Jump Host Not Found',
+		icon: 'err',
+	},
+
+	/**
+	 *  A jump host denied the connection
+	 *  NOTE: Should also contain the hop number and host appended by a colon
+	 * */
+	JUMP_DENIED: {
+		status: 'Invalid Jump Host Creds',
+		icon: 'err',
+	},
+
+	/**
+	 *  A port forward failed to start listening
+	 *  NOTE: Should also contain the port appended by a colon
+	 * */
+	FORWARD_FAILED: {
+		status: 'Port Forward Failed',
+		icon: 'err',
+	},
+
+	/**
+	 *  The local port of a forward is already used by another program
+	 *  NOTE: Should also contain the port appended by a colon
+	 * */
+	PORT_IN_USE: {
+		status: 'Port In Use',
+		icon: 'err',
+	},
+
+	/**
+	 *  Server (or a jump host) doesn't allow port forwarding
+	 * */
+	FORWARD_PROHIBITED: {
+		status: 'Forwarding Prohibited',
+		icon: 'err',
+	},
+
+	/**
+	 *  Server failed to listen on the port of a remote forward
+	 *  NOTE: Should also contain the port appended by a colon
+	 * */
+	REMOTE_FORWARD_FAILED: {
+		status: 'Remote Forward Failed',
+		icon: 'err',
+	},
+
 	/**
 	 *  Bad configuration parameters were passed to server
 	 *  NOTE: Should also contain an additional error message appended by a colon
@@ -115,4 +237,32 @@ export const constants = {
 	 *  Server Status Listener
 	 * */
 	tunnelStatus: 'tunnel_status',
+
+	/**
+	 *  Assigned Ports Listener
+	 *  local ports that were picked for forwards left blank (sent as a list of AssignedPort)
+	 * */
+	tunnelPorts: 'tunnel_ports',
+
+	/**
+	 *  Tunnel Warning Listener
+	 *  problems that the connected tunnel reported, e.g. a forwarded connection that was refused
+	 * */
+	tunnelWarning: 'tunnel_warning',
+}
+
+/**
+ *  The number of tunnel warnings that are kept to show the user
+ * */
+export const maxWarnings = 5
+
+/**
+ *  A local port that was picked for one of the tunnel's forwards
+ * */
+export type AssignedPort = {
+	/**
+	 *  The forward's ssh option, e.g. "-L 54123:localhost:5432"
+	 * */
+	forward: string
+	port: number
 }
@@ -16,6 +16,14 @@ export const connectedScreenStyles = css`
 		}
 	}
 
+	.warnings {
+		padding: 1em;
+		margin-bottom: 1em;
+		color: ${props => props.theme.colors.err.val};
+		border: solid 1px;
+		border-radius: 5px;
+	}
+
 	.disconnect-btn {
 		outline: none;
 		box-shadow: none;
@@ -42,7 +50,7 @@ const ConnectedScreenView = styled.div`
 export type ConnectedScreenProps = {}
 
 export const ConnectedScreen = (_: ConnectedScreenProps): JSX.Element => {
-	const { status, userSettings } = useStore()
+	const { status, userSettings, ports, warnings } = useStore()
 	const { port } = userSettings || {}
 
 	const disconnectHandler = async () => {
@@ -59,7 +67,21 @@ export const ConnectedScreen = (_: ConnectedScreenProps): JSX.Element => {
 						Listening on localhost PORT: <span className='port'>{port}</span>
 					</div>
 				) : null}
+				{ports.map(({ forward, port }) => (
+					<div className='port-info' key={forward}>
+						Listening on localhost PORT: <span className='port'>{port}</span> ({forward})
+					</div>
+				))}
 			</div>
+			{warnings.length ? (
+				<div className='warnings'>
+					{warnings.map((warning, i) => (
+						<div className='warning' key={i}>
+							{warning}
+						</div>
+					))}
+				</div>
+			) : null}
 			<button
 				className='disconnect-btn'
 				onClick={disconnectHandler}
@@ -26,7 +26,8 @@ const MainScreenView = styled.div`
 
 export const MainScreen = (): JSX.Element => {
 	const { status } = useStore()
-	const showConnectedScreen = status === 'CONNECTED' || status === 'RETRYING'
+	const showConnectedScreen =
+		status === 'CONNECTED' || status === 'UNHEALTHY' || status === 'RETRYING'
 
 	return (
 		<MainScreenView>
@@ -7,7 +7,7 @@
 * */
 import { listen, UnlistenFn } from '@tauri-apps/api/event'
 import { createContext, Dispatch, ReactNode, useContext, useEffect } from 'react'
-import { constants, ServerStatus } from '../../app.config'
+import { AssignedPort, constants, ServerStatus } from '../../app.config'
 import { UserSettings } from '../../utils/useSettings'
 import { IconType } from '../UI/Icon/fa.defaults'
 import { useAppState } from './useAppState'
@@ -27,6 +27,10 @@ export type Store = {
 	userSettings: UserSettings | null
 	setUserSettings: Dispatch<UserSettings | null>
 	history: StatusHistory[]
+	ports: AssignedPort[]
+	setPorts: Dispatch<AssignedPort[]>
+	warnings: string[]
+	addWarning: Dispatch<string>
 }
 
 const initialStore: Store = {
@@ -44,6 +48,10 @@ const initialStore: Store = {
 			status: 'READY',
 		},
 	],
+	ports: [],
+	setPorts: () => {},
+	warnings: [],
+	addWarning: () => {},
 }
 
 export const context = createContext(initialStore)
@@ -61,6 +69,8 @@ export const StoreProvider = ({ children }: StoreProviderProps): JSX.Element =>
 
 	useEffect(() => {
 		let cleanupSuccessListener: UnlistenFn
+		let cleanupPortsListener: UnlistenFn
+		let cleanupWarningListener: UnlistenFn
 
 		/**
 		 *  Server Status Listener
@@ -68,6 +78,13 @@ export const StoreProvider = ({ children }: StoreProviderProps): JSX.Element =>
 		listen(constants.tunnelStatus, e => {
 			const signal = e.payload as string
 
+			// A passing health check (e.g. "HEALTHY: 12ms") only confirms that the tunnel is connected
+			if (signal.startsWith('HEALTHY')) {
+				state.setStatus('CONNECTED')
+				state.setSystemErr(null)
+				return
+			}
+
 			let serverStatus = signal
 
 			// This means that the signal contains an error message
@@ -85,8 +102,24 @@ export const StoreProvider = ({ children }: StoreProviderProps): JSX.Element =>
 			// Assign the unregister listener function for clean up purposes
 		}).then(handler => (cleanupSuccessListener = handler))
 
+		/**
+		 *  Assigned Ports Listener
+		 * */
+		listen(constants.tunnelPorts, e => {
+			state.setPorts(e.payload as AssignedPort[])
+		}).then(handler => (cleanupPortsListener = handler))
+
+		/**
+		 *  Tunnel Warning Listener
+		 * */
+		listen(constants.tunnelWarning, e => {
+			state.addWarning(e.payload as string)
+		}).then(handler => (cleanupWarningListener = handler))
+
 		return () => {
 			if (typeof cleanupSuccessListener === 'function') cleanupSuccessListener()
+			if (typeof cleanupPortsListener === 'function') cleanupPortsListener()
+			if (typeof cleanupWarningListener === 'function') cleanupWarningListener()
 		}
 
 		// eslint-disable-next-line react-hooks/exhaustive-deps
@@ -7,12 +7,41 @@
 import { BaseDirectory, writeTextFile } from '@tauri-apps/api/fs'
 import { sendNotification } from '@tauri-apps/api/notification'
 import { Reducer, useEffect, useReducer, useRef, useState } from 'react'
-import { appStatus, ServerStatus, userSettingsPath } from '../../app.config'
+import {
+	appStatus,
+	AssignedPort,
+	maxWarnings,
+	ServerStatus,
+	userSettingsPath,
+} from '../../app.config'
 import { useGetNotificationPermission } from '../../utils/useGetNotificationPermission'
 import { UserSettings } from '../../utils/useSettings'
 import { IconType } from '../UI/Icon/fa.defaults'
 import { StatusHistory, Store } from './Store.provider'
 
+/**
+ *  Error messages for the statuses that the user can do something about
+ *  Each one is given the detail that came with the signal (e.g. the port or key path), if there was one
+ * */
+const errorMessages: Partial<Record<ServerStatus, (detail: string | null) => string>> = {
+	REFUSED: () => 'The server refused the connection. Check the IP address and that ssh is running',
+	TIMED_OUT: () => "The server didn't finish connecting in time. It may be overloaded or restarting",
+	TOO_MANY_AUTH_FAILURES: () => 'Too many keys were offered. Check the ssh key',
+	HOST_KEY_CHANGED: detail =>
+		`The server's host key has changed (${detail}). It could be an attack, or the server was reinstalled`,
+	HOST_KEY_UNVERIFIED: () => "The server's host key is unknown, and couldn't be verified",
+	HOST_KEY_REJECTED: detail =>
+		`The server's host key (${detail}) wasn't trusted, so the tunnel wasn't started`,
+	UNPROTECTED_KEY: detail => `The ssh key is accessible by other users. Run: chmod 600 ${detail}`,
+	JUMP_UNREACHABLE: detail => `Jump host unreachable (hop ${detail})`,
+	JUMP_DENIED: detail => `Jump host denied the connection (hop ${detail})`,
+	FORWARD_FAILED: detail => `Failed to forward port ${detail}`,
+	PORT_IN_USE: detail => `Port ${detail} is already in use by another program`,
+	FORWARD_PROHIBITED: () => "The server doesn't allow port forwarding",
+	REMOTE_FORWARD_FAILED: detail => `The server failed to listen on port ${detail}`,
+	UNHEALTHY: detail => `The tunnel is connected, but the service isn't responding: ${detail}`,
+}
+
 type ReducerState = {
 	statusMsg: string
 	statusIcon: IconType
@@ -52,11 +81,23 @@ export const useAppState = (): Store => {
 
 	const [status, setStatus] = useState<ServerStatus>('READY')
 	const [systemErr, setSystemErr] = useState<string | null>(null)
+	const [ports, setPorts] = useState<AssignedPort[]>([])
+	const [warnings, setWarnings] = useState<string[]>([])
 	const [userSettings, setUserSettings] = useState<UserSettings | null>(null)
 	const { granted } = useGetNotificationPermission()
 
 	const writing = useRef(false)
 
+	const addWarning = (warning: string) => {
+		setWarnings(warnings => [...warnings, warning].slice(-maxWarnings))
+
+		if (granted)
+			sendNotification({
+				title: 'WARNING',
+				body: warning,
+			})
+	}
+
 	useEffect(() => {
 		const writeSettingsFile = async () => {
 			writing.current = true
@@ -87,7 +128,11 @@ export const useAppState = (): Store => {
 		dispatch(status)
 
 		// Do some extra stuff depending on the status...
-		if (status === 'CONNECTED') {
+		if (status === 'READY') {
+			// The ports are picked again for the next tunnel
+			setPorts([])
+			setWarnings([])
+		} else if (status === 'CONNECTED') {
 			if (granted)
 				sendNotification({
 					title: 'SUCCESS',
@@ -121,6 +166,18 @@ export const useAppState = (): Store => {
 					title: 'INTERRUPTION',
 					body: 'SSH Connection Interrupted!',
 				})
+		} else {
+			const errorMessage = errorMessages[status]
+			if (errorMessage) {
+				// The signal's detail (if any) has already been set as the error by the listener
+				setSystemErr(detail => errorMessage(detail))
+
+				if (granted)
+					sendNotification({
+						title: 'ERROR',
+						body: appStatus[status].status,
+					})
+			}
 		}
 
 		// eslint-disable-next-line react-hooks/exhaustive-deps
@@ -133,8 +190,12 @@ export const useAppState = (): Store => {
 		systemErr,
 		userSettings,
 		history,
+		ports,
+		warnings,
 		setStatus,
 		setSystemErr,
 		setUserSettings,
+		setPorts,
+		addWarning,
 	}
 }
@@ -1,6 +1,672 @@
+use std::net::TcpListener;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::Error;
+
+/// The line that ssh prints on its stdout once it has connected (see [SshConfig::to_args])
+pub(crate) const READY_MARKER: &str = "SSH_TUNNEL_READY";
+
+/// The ssh options that print the [READY_MARKER], which can't be given as extra options
+const RESERVED_OPTIONS: [&str; 2] = ["LocalCommand", "PermitLocalCommand"];
+
+/// The number of the next control socket picked by [unique_control_path]
+static NEXT_CONTROL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The local port that asks for any free port to be picked when the tunnel starts (see [SshConfig::assign_free_ports])
+pub const ANY_PORT: u32 = 0;
+
+/// The direction of a port forward
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardDirection {
+    /// Listens on a local port and forwards connections through the end host (`ssh -L`)
+    Local,
+
+    /// Listens on a port on the end host and forwards connections back through the local machine (`ssh -R`)
+    Remote,
+}
+
+impl ForwardDirection {
+    /// The ssh cli flag for the direction
+    pub fn flag(&self) -> &'static str {
+        match self {
+            ForwardDirection::Local => "-L",
+            ForwardDirection::Remote => "-R",
+        }
+    }
+}
+
+/// A single port forward carried by the ssh connection
+///
+/// A [local](ForwardDirection::Local) forward listens on a local port and forwards any connections to a port on the "to
+/// host", as seen from the end host. A [remote](ForwardDirection::Remote) forward does the opposite: it listens on a port
+/// on the end host and forwards any connections to a port on the "to host", as seen from the local machine. Any number of
+/// forwards, in either direction, can ride the same ssh connection.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forward {
+    /// The direction of the forward
+    direction: ForwardDirection,
+
+    /// The address to bind the listening port to. If not given, ssh binds to the loopback address.
+    #[cfg_attr(feature = "serde", serde(default))]
+    bind_address: Option<String>,
+
+    /// The port on the local side of the tunnel. Local forwards listen on this port, remote forwards connect to it.
+    local_port: u32,
+
+    /// The host to forward the tunnel to (probably `localhost`)
+    to_host: String,
+
+    /// The port on the remote side of the tunnel. Remote forwards listen on this port, local forwards connect to it.
+    remote_port: u32,
+}
+
+impl Forward {
+    /// Construct a local Forward object
+    ///
+    /// # Params
+    /// * `local_port`: The local port to listen on.
+    ///
+    /// * `to_host`: The address of the host to forward to, as resolved by the end host (probably "localhost").
+    ///
+    /// * `remote_port`: The port on the to host to forward to.
+    pub fn new(local_port: u32, to_host: &str, remote_port: u32) -> Self {
+        Forward {
+            direction: ForwardDirection::Local,
+            bind_address: None,
+            local_port,
+            to_host: String::from(to_host),
+            remote_port,
+        }
+    }
+
+    /// Construct a remote Forward object
+    ///
+    /// # Params
+    /// * `remote_port`: The port to listen on at the end host.
+    ///
+    /// * `to_host`: The address of the host to forward to, as resolved by the local machine (probably "localhost").
+    ///
+    /// * `local_port`: The port on the to host to forward to.
+    pub fn remote(remote_port: u32, to_host: &str, local_port: u32) -> Self {
+        Forward {
+            direction: ForwardDirection::Remote,
+            bind_address: None,
+            local_port,
+            to_host: String::from(to_host),
+            remote_port,
+        }
+    }
+
+    /// Binds the listening port to the given address (on the local machine for local forwards, and on the end host for
+    /// remote forwards), rather than the loopback address
+    pub fn with_bind_address(mut self, bind_address: &str) -> Self {
+        self.bind_address = Some(bind_address.to_string());
+        self
+    }
+
+    /// Parses a forward specification in the order that ssh expects for the given direction
+    ///
+    /// Local forwards are given as `[bind_address:]local_port:to_host:remote_port` (as for `ssh -L`), and remote forwards
+    /// are given as `[bind_address:]remote_port:to_host:local_port` (as for `ssh -R`). IPv6 addresses are given in
+    /// brackets.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the specification is malformed.
+    pub fn parse(spec: &str, direction: ForwardDirection) -> Result<Self, Error> {
+        let bad_spec = || Error::Config(format!("Bad forward specification: {spec}"));
+
+        let parts = split_spec(spec);
+        let (bind_address, listen_port, to_host, to_port) = match parts[..] {
+            [listen_port, to_host, to_port] => (None, listen_port, to_host, to_port),
+            [bind_address, listen_port, to_host, to_port] => {
+                (Some(bind_address), listen_port, to_host, to_port)
+            }
+            _ => return Err(bad_spec()),
+        };
+
+        let listen_port = listen_port.parse().map_err(|_| bad_spec())?;
+        let to_port = to_port.parse().map_err(|_| bad_spec())?;
+        let forward = match direction {
+            ForwardDirection::Local => Forward::new(listen_port, to_host, to_port),
+            ForwardDirection::Remote => Forward::remote(listen_port, to_host, to_port),
+        };
+        Ok(match bind_address {
+            Some(bind_address) => forward.with_bind_address(bind_address),
+            None => forward,
+        })
+    }
+
+    /// The direction of the forward
+    pub fn direction(&self) -> ForwardDirection {
+        self.direction
+    }
+
+    /// The address the listening port is bound to, if one was given
+    pub fn bind_address(&self) -> Option<&str> {
+        self.bind_address.as_deref()
+    }
+
+    /// The local address that a local forward listens on, in a form that can be bound with [TcpListener::bind]
+    pub(crate) fn listen_address(&self) -> &str {
+        listen_address(self.bind_address.as_deref())
+    }
+
+    /// The port on the local side of the forward
+    pub fn local_port(&self) -> u32 {
+        self.local_port
+    }
+
+    /// The host the forward connects to
+    pub fn to_host(&self) -> &str {
+        &self.to_host
+    }
+
+    /// The port on the remote side of the forward
+    pub fn remote_port(&self) -> u32 {
+        self.remote_port
+    }
+
+    /// Converts the forward to the specification used by the ssh cli
+    ///
+    /// This is `[bind_address:]local_port:to_host:remote_port` for local forwards and
+    /// `[bind_address:]remote_port:to_host:local_port` for remote forwards, with any IPv6 addresses in brackets.
+    pub fn to_spec(&self) -> String {
+        format!("{}:{}", self.listen_spec(), self.target_spec())
+    }
+
+    /// The `[bind_address:]port` that the forward listens on, as given to ssh
+    fn listen_spec(&self) -> String {
+        let port = match self.direction {
+            ForwardDirection::Local => self.local_port,
+            ForwardDirection::Remote => self.remote_port,
+        };
+        match &self.bind_address {
+            Some(addr) => format!("{}:{port}", bracket_address(addr)),
+            None => port.to_string(),
+        }
+    }
+
+    /// The `to_host:port` that the forward connects to, as given to ssh
+    fn target_spec(&self) -> String {
+        let port = match self.direction {
+            ForwardDirection::Local => self.remote_port,
+            ForwardDirection::Remote => self.local_port,
+        };
+        format!("{}:{port}", bracket_address(&self.to_host))
+    }
+}
+
+impl FromStr for Forward {
+    type Err = Error;
+
+    /// Parses a `local_port:to_host:remote_port` specification, as it would be given to `ssh -L`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        Forward::parse(spec, ForwardDirection::Local)
+    }
+}
+
+/// A dynamic port forward, which turns the tunnel into a local SOCKS proxy
+///
+/// Rather than forwarding a single port, the ssh process listens on the local port and acts as a SOCKS4/SOCKS5 proxy, so any
+/// host reachable from the end host can be reached through the tunnel.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicForward {
+    /// The local address to bind the proxy to. If not given, ssh binds to the loopback address.
+    #[cfg_attr(feature = "serde", serde(default))]
+    bind_address: Option<String>,
+
+    /// The local port the proxy listens on
+    port: u32,
+}
+
+impl DynamicForward {
+    /// Construct a DynamicForward object
+    ///
+    /// # Params
+    /// * `bind_address`: The local address to bind the proxy to, or [None] for the loopback address.
+    ///
+    /// * `port`: The local port the proxy listens on.
+    pub fn new(bind_address: Option<&str>, port: u32) -> Self {
+        DynamicForward {
+            bind_address: bind_address.map(String::from),
+            port,
+        }
+    }
+
+    /// The local address the proxy is bound to, if one was given
+    pub fn bind_address(&self) -> Option<&str> {
+        self.bind_address.as_deref()
+    }
+
+    /// The local port the proxy listens on
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// The local address that the proxy listens on, in a form that can be bound with [TcpListener::bind]
+    pub(crate) fn listen_address(&self) -> &str {
+        listen_address(self.bind_address.as_deref())
+    }
+
+    /// Converts the forward to the `[bind_address:]port` specification used by the ssh cli
+    pub fn to_spec(&self) -> String {
+        match &self.bind_address {
+            Some(addr) => format!("{}:{}", bracket_address(addr), self.port),
+            None => self.port.to_string(),
+        }
+    }
+}
+
+/// The local address that a forward with the given bind address listens on, in a form that can be bound with
+/// [TcpListener::bind]
+fn listen_address(bind_address: Option<&str>) -> &str {
+    match bind_address {
+        None | Some("") | Some("localhost") => "127.0.0.1",
+        Some("*") => "0.0.0.0",
+        Some(addr) => addr.trim_start_matches('[').trim_end_matches(']'),
+    }
+}
+
+/// Picks a control socket for a [multiplexed](SshConfigBuilder::multiplexed) tunnel, which no other tunnel uses
+///
+/// Tunnels to the same host would otherwise share a socket, and only the first of them could run a ControlMaster session
+/// on it. So the socket is named after this process and a counter, followed by ssh's `%C` hash of the connection, such as
+/// `~/.ssh/ssh-tunnel-4242-1-%C`.
+pub fn unique_control_path() -> String {
+    let id = NEXT_CONTROL_ID.fetch_add(1, Ordering::Relaxed);
+    format!("~/.ssh/ssh-tunnel-{}-{id}-%C", process::id())
+}
+
+/// Wraps an IPv6 address in brackets, as ssh expects it in forward specifications
+fn bracket_address(address: &str) -> String {
+    if address.contains(':') && !address.starts_with('[') {
+        format!("[{address}]")
+    } else {
+        address.to_string()
+    }
+}
+
+/// Splits a forward specification on the colons that aren't inside brackets, and removes the brackets around IPv6
+/// addresses
+fn split_spec(spec: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut bracketed = false;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            ':' if !bracketed => {
+                parts.push(&spec[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&spec[start..]);
+
+    parts
+        .into_iter()
+        .map(|part| part.trim_start_matches('[').trim_end_matches(']'))
+        .collect()
+}
+
+impl FromStr for DynamicForward {
+    type Err = Error;
+
+    /// Parses a `[bind_address:]port` specification, as it would be given to `ssh -D`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let bad_spec = || Error::Config(format!("Bad dynamic forward specification: {spec}"));
+
+        let (bind_address, port) = match spec.rsplit_once(':') {
+            Some((addr, port)) => (Some(addr), port),
+            None => (None, spec),
+        };
+        Ok(DynamicForward::new(
+            bind_address,
+            port.parse().map_err(|_| bad_spec())?,
+        ))
+    }
+}
+
+/// A jump host (bastion) that the tunnel hops through on its way to the end host
+///
+/// Jump hosts are connected to in order, with each one reached through the ones before it (see `ProxyJump` in the
+/// [ssh_config(5) man](https://linux.die.net/man/5/ssh_config) page). Each hop may use its own username, port and identity
+/// file. If these are not given, ssh falls back on the user's ssh config and its defaults.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpHost {
+    /// The address of the jump host
+    host: String,
+
+    /// The username to log in to the jump host with
+    #[cfg_attr(feature = "serde", serde(default))]
+    username: Option<String>,
+
+    /// The ssh port of the jump host
+    #[cfg_attr(feature = "serde", serde(default))]
+    port: Option<u32>,
+
+    /// A path to the key file to use for the jump host. This must not be password encrypted
+    #[cfg_attr(feature = "serde", serde(default))]
+    key_path: Option<String>,
+}
+
+impl JumpHost {
+    /// Construct a JumpHost object
+    ///
+    /// # Params
+    /// * `host`: The address of the jump host.
+    ///
+    /// * `username`: The username for the jump host, or [None] to use ssh's default.
+    ///
+    /// * `port`: The ssh port of the jump host, or [None] to use ssh's default.
+    ///
+    /// * `key_path`: The path to the private key to use for the jump host, or [None] to use ssh's default.
+    pub fn new(
+        host: &str,
+        username: Option<&str>,
+        port: Option<u32>,
+        key_path: Option<&str>,
+    ) -> Self {
+        JumpHost {
+            host: String::from(host),
+            username: username.map(String::from),
+            port,
+            key_path: key_path.map(normalize_key_path),
+        }
+    }
+
+    /// The address of the jump host
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The username for the jump host, if one was given
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// The ssh port of the jump host, if one was given
+    pub fn port(&self) -> Option<u32> {
+        self.port
+    }
+
+    /// The path to the jump host's private key, if one was given
+    pub fn key_path(&self) -> Option<&str> {
+        self.key_path.as_deref()
+    }
+
+    /// Converts the jump host to the `[user@]host[:port]` destination used by `ssh -J`
+    pub fn to_destination(&self) -> String {
+        let mut dest = String::new();
+        if let Some(user) = &self.username {
+            dest.push_str(user);
+            dest.push('@');
+        }
+        if self.host.contains(':') {
+            dest.push_str(&format!("[{}]", self.host));
+        } else {
+            dest.push_str(&self.host);
+        }
+        if let Some(port) = self.port {
+            dest.push_str(&format!(":{port}"));
+        }
+        dest
+    }
+
+    /// Builds the `ProxyCommand` that reaches the host after this hop, going through this hop and all of the `previous` ones
+    ///
+    /// Each hop gets its own ssh process, running `ssh -W %h:%p` on the hop, with the previous hops nested in its own
+    /// `ProxyCommand`. Since ssh expands `%` tokens in each `ProxyCommand` before running it, the nested commands have their
+    /// `%` characters escaped once for each level that they are nested in.
+    fn to_proxy_command(&self, previous: &[JumpHost]) -> String {
+        let mut args = vec!["ssh".to_string()];
+        if let Some(key_path) = &self.key_path {
+            args.push("-i".to_string());
+            args.push(shell_quote(key_path));
+        }
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        if let Some((hop, rest)) = previous.split_last() {
+            let inner = hop.to_proxy_command(rest).replace('%', "%%");
+            args.push("-o".to_string());
+            args.push(shell_quote(&format!("ProxyCommand={inner}")));
+        }
+        args.push("-W".to_string());
+        args.push("%h:%p".to_string());
+
+        let host = match &self.username {
+            Some(user) => format!("{user}@{}", self.host),
+            None => self.host.clone(),
+        };
+        args.push(shell_quote(&host));
+        args.join(" ")
+    }
+}
+
+impl FromStr for JumpHost {
+    type Err = Error;
+
+    /// Parses a `[user@]host[:port]` destination, as it would be given to `ssh -J`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let bad_spec = || Error::Config(format!("Bad jump host specification: {spec}"));
+
+        let (username, dest) = match spec.rsplit_once('@') {
+            Some((user, dest)) => (Some(user), dest),
+            None => (None, spec),
+        };
+
+        let (host, port) = if let Some(rest) = dest.strip_prefix('[') {
+            // Bracketed IPv6 address, with an optional port
+            let (host, rest) = rest.split_once(']').ok_or_else(bad_spec)?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(bad_spec()),
+            }
+        } else {
+            match dest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (dest, None),
+            }
+        };
+
+        if host.is_empty() || username == Some("") {
+            return Err(bad_spec());
+        }
+
+        let port = match port {
+            Some(port) => Some(port.parse().map_err(|_| bad_spec())?),
+            None => None,
+        };
+
+        Ok(JumpHost::new(host, username, port, None))
+    }
+}
+
+/// Decides how ssh verifies the end host's key
+///
+/// Jump hosts are always verified with ssh's defaults (against the user's known hosts file), since the policy's options
+/// only apply to the connection to the end host.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum HostKeyPolicy {
+    /// Only connects if the host's key is already in the user's known hosts file
+    Strict,
+
+    /// Adds the keys of new hosts to the user's known hosts file, but refuses keys that have changed. This lets the tunnel
+    /// connect to a new host without any interaction, while still catching man-in-the-middle attacks on known hosts.
+    #[default]
+    AcceptNew,
+
+    /// Only connects if the host's key has one of these SHA256 fingerprints (as printed by `ssh-keygen -l`), whatever the
+    /// known hosts files say. This needs OpenSSH 8.5 or later, and isn't supported on Windows.
+    Pinned(Vec<String>),
+
+    /// Checks the host's key against the given known hosts file instead of the user's, adding the keys of new hosts to it
+    /// (as [HostKeyPolicy::AcceptNew] does). Giving each profile its own file keeps their trusted keys apart.
+    KnownHostsFile(String),
+}
+
+impl HostKeyPolicy {
+    /// The ssh options that apply the policy, as key/value pairs
+    ///
+    /// A [pinned](HostKeyPolicy::Pinned) key is checked by a `KnownHostsCommand`, which ssh runs with the fingerprint of the
+    /// key that the host presented. The command vouches for the key if its fingerprint is pinned, and otherwise prints the
+    /// fingerprint, so that the failure is reported as
+    /// [SshStatus::HostKeyChanged](crate::status::SshStatus::HostKeyChanged). ssh also runs the command before it has a key,
+    /// to order its host key algorithms, with a fingerprint of `NONE`; the command prints nothing then.
+    pub fn to_options(&self) -> Vec<(&'static str, String)> {
+        match self {
+            HostKeyPolicy::Strict => vec![("StrictHostKeyChecking", "yes".to_string())],
+            HostKeyPolicy::AcceptNew => {
+                vec![("StrictHostKeyChecking", "accept-new".to_string())]
+            }
+            HostKeyPolicy::Pinned(fingerprints) => vec![
+                ("StrictHostKeyChecking", "yes".to_string()),
+                ("UserKnownHostsFile", "none".to_string()),
+                ("GlobalKnownHostsFile", "none".to_string()),
+                (
+                    "KnownHostsCommand",
+                    format!(
+                        "/bin/sh -c \"case %f in {}) echo '%H' %t %K ;; \
+                         SHA256:*) echo '{NOT_PINNED_MARKER}' %f >&2 ;; esac\"",
+                        fingerprints.join("|")
+                    ),
+                ),
+            ],
+            HostKeyPolicy::KnownHostsFile(path) => vec![
+                ("StrictHostKeyChecking", "accept-new".to_string()),
+                ("UserKnownHostsFile", path.clone()),
+            ],
+        }
+    }
+
+    /// Checks that the policy can be used
+    fn validate(&self) -> Result<(), Error> {
+        let config_error = |msg: String| Err(Error::Config(msg));
+
+        match self {
+            HostKeyPolicy::Pinned(fingerprints) => {
+                if fingerprints.is_empty() {
+                    return config_error("At least one host key must be pinned".to_string());
+                }
+                if let Some(fingerprint) = fingerprints.iter().find(|f| !is_fingerprint(f)) {
+                    return config_error(format!("Bad host key fingerprint: '{fingerprint}'"));
+                }
+                if cfg!(target_os = "windows") {
+                    return config_error(
+                        "Pinned host keys aren't supported on Windows".to_string(),
+                    );
+                }
+            }
+            HostKeyPolicy::KnownHostsFile(path) if path.is_empty() => {
+                return config_error("The known hosts file must not be empty".to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = Error;
+
+    /// Parses `strict`, `accept-new`, `pin:<fingerprint>[,<fingerprint>...]` or `known-hosts:<path>`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            None if spec == "strict" => Ok(HostKeyPolicy::Strict),
+            None if spec == "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            Some(("pin", fingerprints)) => Ok(HostKeyPolicy::Pinned(
+                fingerprints.split(',').map(str::to_string).collect(),
+            )),
+            Some(("known-hosts", path)) => Ok(HostKeyPolicy::KnownHostsFile(path.to_string())),
+            _ => Err(Error::Config(format!("Bad host key policy: {spec}"))),
+        }
+    }
+}
+
+/// The message that the check for a [pinned](HostKeyPolicy::Pinned) host key prints (followed by the fingerprint) when the
+/// host presents a key that isn't pinned
+pub(crate) const NOT_PINNED_MARKER: &str = "Host key is not pinned:";
+
+/// Checks whether the text is a SHA256 key fingerprint, as printed by ssh
+fn is_fingerprint(text: &str) -> bool {
+    match text.strip_prefix("SHA256:") {
+        Some(hash) => {
+            !hash.is_empty()
+                && hash
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+        }
+        None => false,
+    }
+}
+
+/// The default keepalive time (in seconds)
+fn default_keepalive() -> u32 {
+    10
+}
+
+/// Ensures that the path conforms to the unix-y paths that ssh prefers
+fn normalize_key_path(key_path: &str) -> String {
+    key_path.to_string().replace("C:", "").replace('\\', "/")
+}
+
+/// Quotes an argument for a POSIX shell, if it needs quoting
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes an argument for a Windows command line, if it needs quoting
+///
+/// Windows passes a process its command line as a single string, which ssh splits into arguments by the rules of the C
+/// runtime: whitespace separates arguments unless it's inside double quotes, and backslashes only escape a double quote.
+/// So an argument with whitespace or quotes is wrapped in double quotes, its quotes are escaped, and the backslashes before
+/// them (or before the closing quote) are doubled.
+#[cfg(any(target_os = "windows", test))]
+pub(crate) fn windows_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+        } else {
+            if c == '"' {
+                quoted.push_str(&"\\".repeat(backslashes + 1));
+            }
+            backslashes = 0;
+        }
+        quoted.push(c);
+    }
+    quoted.push_str(&"\\".repeat(backslashes));
+    quoted.push('"');
+    quoted
+}
+
 /// Configuration parameters for the ssh tunnel
 ///
 /// This struct provides all of the parameters necessary for launching an ssh tunnel.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub struct SshConfig {
     /// The end host (most likely an ip address)
@@ -12,28 +678,55 @@ pub struct SshConfig {
     /// A path to the key file to use. This must not be password encrypted
     key_path: String,
 
-    /// The host to forward the tunnel to (probably `localhost`)
-    to_host: String,
+    /// The port forwards carried by the tunnel
+    forwards: Vec<Forward>,
 
-    /// The port to use on the to host
-    local_port: u32,
+    /// The dynamic (SOCKS proxy) forwards carried by the tunnel
+    #[cfg_attr(feature = "serde", serde(default))]
+    dynamic_forwards: Vec<DynamicForward>,
 
-    /// The port to use on the end host
-    remote_port: u32,
+    /// The jump hosts to hop through on the way to the end host, in order
+    #[cfg_attr(feature = "serde", serde(default))]
+    jump_hosts: Vec<JumpHost>,
 
     /// The keepalive time (in seconds). If the connection is interrupted for longer than this interval, the tunnel process
     /// will exit
+    #[cfg_attr(feature = "serde", serde(default = "default_keepalive"))]
     keepalive: u32,
 
+    /// The ssh port of the end host. If not given, ssh uses its default (22, unless the user's ssh config says otherwise).
+    #[cfg_attr(feature = "serde", serde(default))]
+    port: Option<u32>,
+
+    /// The time (in seconds) to wait for the tunnel to connect. ssh uses it for the connection to the end host, and the
+    /// library kills a tunnel that hasn't finished connecting by then.
+    #[cfg_attr(feature = "serde", serde(default))]
+    connect_timeout: Option<u32>,
+
+    /// The control socket of the tunnel's ControlMaster session, if the tunnel runs one (see [control](crate::control))
+    #[cfg_attr(feature = "serde", serde(default))]
+    control_path: Option<String>,
+
+    /// How ssh verifies the end host's key
+    #[cfg_attr(feature = "serde", serde(default))]
+    host_key_policy: HostKeyPolicy,
+
+    /// Additional `-o key=value` options, passed to ssh ahead of the default options so that they take precedence
+    #[cfg_attr(feature = "serde", serde(default))]
+    options: Vec<(String, String)>,
+
     /// Any additional flags required
+    #[cfg_attr(feature = "serde", serde(default))]
     flags: Vec<String>,
 }
 
 impl SshConfig {
     /// Construct an SshConfig object
     ///
-    /// This function parameters provide all of those necessary for launching an ssh tunnel. Any extra parameters that are
-    /// needed can be passed in the flags parameter.
+    /// This function parameters provide all of those necessary for launching an ssh tunnel with a single port forward.
+    /// Additional forwards can be added with [SshConfig::add_forward]. Any extra parameters that are needed can be passed
+    /// in the flags parameter. For anything beyond this, [SshConfig::builder] is more convenient, and validates the
+    /// configuration.
     ///
     /// # Params
     /// * `end_host`: The address of the end (or remote) host.
@@ -49,7 +742,7 @@ impl SshConfig {
     /// * `remote_port`: The remote port to use.
     ///
     /// * `keepalive`: The keepalive time (in seconds). If the connection is interrupted for longer than this interval, the
-    ///    tunnel process.
+    ///   tunnel process will exit.
     ///
     /// * `flags`: A vector of additional flags or options to pass to the process cli.
     #[allow(clippy::too_many_arguments)]
@@ -63,70 +756,819 @@ impl SshConfig {
         keepalive: u32,
         flags: &[&str],
     ) -> Self {
-        // Ensure that the path conforms to the unix-y paths that ssh prefers
-        let kp = key_path.to_string().replace("C:", "").replace('\\', "/");
         SshConfig {
             end_host: String::from(end_host),
             username: String::from(username),
-            key_path: kp,
-            to_host: String::from(to_host),
-            local_port,
-            remote_port,
+            key_path: normalize_key_path(key_path),
+            forwards: vec![Forward::new(local_port, to_host, remote_port)],
+            dynamic_forwards: Vec::new(),
+            jump_hosts: Vec::new(),
             keepalive,
+            port: None,
+            connect_timeout: None,
+            control_path: None,
+            host_key_policy: HostKeyPolicy::default(),
+            options: Vec::new(),
             flags: flags.iter().map(|f| f.to_string()).collect(),
         }
     }
 
-    /// Converts the config object to an argument vector useful for passing to the ssh cli.
+    /// Starts building an SshConfig object with an [SshConfigBuilder]
     ///
-    /// This provides all of the arguments necessary for creating an ssh tunnel connection. Additional arguments provided by
-    /// the `flags` parameter to [SshConfig::new] are prepended to the front of the default arguments. Here's a brief
-    /// explanation of each of the default arguments (see the [ssh(1) man](https://linux.die.net/man/1/ssh) and
-    /// [ssh_config(5) man ](https://linux.die.net/man/5/ssh_config) pages):
+    /// # Params
+    /// * `end_host`: The address of the end (or remote) host.
     ///
-    /// * **-o StrictHostKeyChecking=accept-new**: Automatically adds new host keys to the user known host file, but does not
-    ///   permit connections to hosts with changed host keys. This setting allows the app to connect without needing to
-    ///   a query on whether to add a new host, but also keeps the security risk from man-in-the-middle attacks low.
+    /// * `username`: The username for the remote host.
     ///
-    /// * **-o ServerAliveCountMax=1**: Instructs the tunnel to shut down after the first alive message is missed.
+    /// * `key_path`: The path to the private key to use.
+    pub fn builder(end_host: &str, username: &str, key_path: &str) -> SshConfigBuilder {
+        SshConfigBuilder::new(end_host, username, key_path)
+    }
+
+    /// Adds another port forward to the tunnel
     ///
-    /// * **-o ServerAliveInterval=<keepalive>**: Sets the interval for the alive message. This defines the response time to a
-    ///   server disconnect event.
+    /// All of the forwards share the same ssh connection. If any of them fails to bind, the whole tunnel will exit with an
+    /// [SshStatus::ForwardFailed](crate::status::SshStatus::ForwardFailed) status naming the failed port.
+    pub fn add_forward(&mut self, forward: Forward) {
+        self.forwards.push(forward);
+    }
+
+    /// Replaces all of the tunnel's port forwards
     ///
-    /// * **-L local_port:local_host:remote_port**: Forwards the local host & port to the remote port. This is the option that
-    ///   makes this a tunnel.
+    /// This is useful for tunnels that only need [remote](ForwardDirection::Remote) forwards, since [SshConfig::new] always
+    /// creates a local forward.
+    pub fn set_forwards(&mut self, forwards: Vec<Forward>) {
+        self.forwards = forwards;
+    }
+
+    /// Returns the port forwards carried by the tunnel
+    pub fn forwards(&self) -> &[Forward] {
+        &self.forwards
+    }
+
+    /// Adds a dynamic (SOCKS proxy) forward to the tunnel
     ///
-    /// * **-i identity_file**: Path to the private key that will be used.
+    /// Once the tunnel has connected, each dynamic forward is probed with a SOCKS5 handshake before the tunnel is reported as
+    /// [Connected](crate::status::SshStatus::Connected). A tunnel that only needs to act as a proxy can drop its port
+    /// forwards with [SshConfig::set_forwards].
+    pub fn add_dynamic_forward(&mut self, forward: DynamicForward) {
+        self.dynamic_forwards.push(forward);
+    }
+
+    /// Returns the dynamic (SOCKS proxy) forwards carried by the tunnel
+    pub fn dynamic_forwards(&self) -> &[DynamicForward] {
+        &self.dynamic_forwards
+    }
+
+    /// Returns the local ports that the tunnel listens on, for its local and dynamic forwards
     ///
-    /// * **user@host**: The username and host address for the remote host.
-    pub fn to_args(&self) -> Vec<String> {
-        let mut args = self.flags.clone();
-        args.append(
-            &mut vec![
-                "-o",
-                "StrictHostKeyChecking=accept-new",
-                "-o",
-                "ServerAliveInterval=1",
-                "-o",
-                &format!("ServerAliveCountMax={}", self.keepalive),
-                "-L",
-                &format!("{}:{}:{}", self.local_port, self.to_host, self.remote_port),
-                "-i",
-                &self.key_path,
-                &format!("{}@{}", self.username, self.end_host),
-            ]
+    /// Forwards whose port is [ANY_PORT] are left out, since they don't claim a port until one is
+    /// [assigned](SshConfig::assign_free_ports).
+    pub fn local_ports(&self) -> Vec<u32> {
+        let local_ports = self
+            .forwards
             .iter()
-            .map(|a| a.to_string())
-            .collect::<Vec<String>>(),
-        );
-        log::debug!("Args: {:?}", args);
-        args
+            .filter(|forward| forward.direction == ForwardDirection::Local)
+            .map(|forward| forward.local_port);
+        let dynamic_ports = self.dynamic_forwards.iter().map(|forward| forward.port);
+
+        local_ports
+            .chain(dynamic_ports)
+            .filter(|&port| port != ANY_PORT)
+            .collect()
     }
-}
 
-#[cfg(test)]
+    /// Returns the local address and port that the tunnel listens on, for each of its local and dynamic forwards
+    ///
+    /// As with [SshConfig::local_ports], forwards whose port is [ANY_PORT] are left out.
+    pub(crate) fn local_listeners(&self) -> Vec<(&str, u32)> {
+        let local_listeners = self
+            .forwards
+            .iter()
+            .filter(|forward| forward.direction == ForwardDirection::Local)
+            .map(|forward| (forward.listen_address(), forward.local_port));
+        let dynamic_listeners = self
+            .dynamic_forwards
+            .iter()
+            .map(|forward| (forward.listen_address(), forward.port));
+
+        local_listeners
+            .chain(dynamic_listeners)
+            .filter(|&(_, port)| port != ANY_PORT)
+            .collect()
+    }
+
+    /// Adds a jump host to the end of the tunnel's chain of hops
+    ///
+    /// The tunnel connects through the jump hosts in the order that they are added. If one of them fails, the tunnel will exit
+    /// with an [SshStatus::JumpUnreachable](crate::status::SshStatus::JumpUnreachable) or
+    /// [SshStatus::JumpDenied](crate::status::SshStatus::JumpDenied) status naming the failed hop.
+    pub fn add_jump_host(&mut self, jump_host: JumpHost) {
+        self.jump_hosts.push(jump_host);
+    }
+
+    /// Returns the jump hosts that the tunnel hops through, in order
+    pub fn jump_hosts(&self) -> &[JumpHost] {
+        &self.jump_hosts
+    }
+
+    /// Returns the end host
+    pub fn end_host(&self) -> &str {
+        &self.end_host
+    }
+
+    /// Returns the username for the end host
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the path to the private key
+    pub fn key_path(&self) -> &str {
+        &self.key_path
+    }
+
+    /// Returns the keepalive time (in seconds)
+    pub fn keepalive(&self) -> u32 {
+        self.keepalive
+    }
+
+    /// Returns the ssh port of the end host, if one was given
+    pub fn port(&self) -> Option<u32> {
+        self.port
+    }
+
+    /// Returns the connect timeout (in seconds), if one was given
+    pub fn connect_timeout(&self) -> Option<u32> {
+        self.connect_timeout
+    }
+
+    /// Returns the control socket of the tunnel's ControlMaster session, if the tunnel runs one
+    pub fn control_path(&self) -> Option<&str> {
+        self.control_path.as_deref()
+    }
+
+    /// Returns how ssh verifies the end host's key
+    pub fn host_key_policy(&self) -> &HostKeyPolicy {
+        &self.host_key_policy
+    }
+
+    /// Sets how ssh verifies the end host's key
+    pub fn set_host_key_policy(&mut self, policy: HostKeyPolicy) {
+        self.host_key_policy = policy;
+    }
+
+    /// Returns the additional `-o key=value` options
+    pub fn options(&self) -> &[(String, String)] {
+        &self.options
+    }
+
+    /// Validates the configuration
+    ///
+    /// This is done automatically by [SshConfigBuilder::build], but configs that were constructed some other way (such as
+    /// loaded from a [profile](crate::profiles)) should be validated before they are used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] describing the first problem found, if:
+    ///
+    /// * The end host, username or key path is empty, or the end host or username contains whitespace.
+    /// * There are no port forwards of any kind.
+    /// * Any port is outside of the range 1-65535, or a forward's `to_host` is empty. The local ports of local and dynamic
+    ///   forwards may also be [ANY_PORT].
+    /// * A jump host is empty or has a port outside of the range 1-65535.
+    /// * The keepalive time or connect timeout is 0.
+    /// * The control path is empty, or is given on Windows (where ssh doesn't support ControlMaster sessions).
+    /// * The host key policy pins no fingerprints or a malformed one (or is used on Windows), or gives an empty known hosts
+    ///   file.
+    /// * An extra option has an invalid name or an empty value, or is `LocalCommand` or `PermitLocalCommand`, which the
+    ///   library needs for itself (see [SshConfig::to_args]).
+    pub fn validate(&self) -> Result<(), Error> {
+        let config_error = |msg: String| Err(Error::Config(msg));
+
+        if self.end_host.is_empty() {
+            return config_error("The end host must not be empty".to_string());
+        }
+        if self.end_host.contains(char::is_whitespace) {
+            return config_error(format!("Bad end host: '{}'", self.end_host));
+        }
+        if self.username.is_empty() {
+            return config_error("The username must not be empty".to_string());
+        }
+        if self.username.contains(char::is_whitespace) {
+            return config_error(format!("Bad username: '{}'", self.username));
+        }
+        if self.key_path.is_empty() {
+            return config_error("The key path must not be empty".to_string());
+        }
+        if self.forwards.is_empty() && self.dynamic_forwards.is_empty() {
+            return config_error("At least one port forward is required".to_string());
+        }
+
+        for forward in &self.forwards {
+            // Local forwards listen on the local port, so it may be left for the library to pick
+            if forward.direction == ForwardDirection::Remote || forward.local_port != ANY_PORT {
+                check_port("Forward local port", forward.local_port)?;
+            }
+            check_port("Forward remote port", forward.remote_port)?;
+            if forward.to_host.is_empty() {
+                return config_error(format!("Forward {} has an empty host", forward.to_spec()));
+            }
+        }
+        for forward in &self.dynamic_forwards {
+            if forward.port != ANY_PORT {
+                check_port("Dynamic forward port", forward.port)?;
+            }
+        }
+        for (i, hop) in self.jump_hosts.iter().enumerate() {
+            if hop.host.is_empty() {
+                return config_error(format!("Jump host {} must not be empty", i + 1));
+            }
+            if let Some(port) = hop.port {
+                check_port(&format!("Jump host {} port", i + 1), port)?;
+            }
+        }
+        if let Some(port) = self.port {
+            check_port("SSH port", port)?;
+        }
+
+        if self.keepalive == 0 {
+            return config_error("The keepalive time must be at least 1 second".to_string());
+        }
+        if self.connect_timeout == Some(0) {
+            return config_error("The connect timeout must be at least 1 second".to_string());
+        }
+        if let Some(control_path) = &self.control_path {
+            if control_path.is_empty() {
+                return config_error("The control path must not be empty".to_string());
+            }
+            if cfg!(target_os = "windows") {
+                return config_error("Multiplexed tunnels aren't supported on Windows".to_string());
+            }
+        }
+        self.host_key_policy.validate()?;
+
+        for (key, value) in &self.options {
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return config_error(format!("Bad option name: '{key}'"));
+            }
+            if value.is_empty() {
+                return config_error(format!("Option {key} has an empty value"));
+            }
+            // ssh keeps the first value it's given for an option, so these would override the ready marker
+            if RESERVED_OPTIONS
+                .iter()
+                .any(|reserved| key.eq_ignore_ascii_case(reserved))
+            {
+                return config_error(format!(
+                    "Option {key} is reserved for detecting the connection"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Picks a free local port for each local and dynamic forward whose port is [ANY_PORT]
+    ///
+    /// Each port is found by binding an ephemeral port on the forward's bind address (the loopback address, unless the
+    /// forward gives one), and then releasing it for ssh to bind. Another process could grab the port in between, but if
+    /// that happens, ssh fails to bind it and the tunnel fails with [SshStatus::ForwardFailed](crate::status::SshStatus::ForwardFailed).
+    ///
+    /// This is done automatically when the tunnel starts (see [start_and_watch_ssh_tunnel](crate::start_and_watch_ssh_tunnel)).
+    ///
+    /// # Returns
+    ///
+    /// Returns each forward that was given a port (as its ssh cli option, such as `-L 54123:localhost:5432` or
+    /// `-D 54124`) along with the port that was picked, in the order of the forwards (local forwards first, then dynamic
+    /// forwards).
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] if a free port can't be found.
+    pub fn assign_free_ports(&mut self) -> Result<Vec<(String, u32)>, Error> {
+        let mut assigned = Vec::new();
+
+        for forward in &mut self.forwards {
+            if forward.direction == ForwardDirection::Local && forward.local_port == ANY_PORT {
+                forward.local_port = free_port(forward.listen_address())?;
+                assigned.push((format!("-L {}", forward.to_spec()), forward.local_port));
+            }
+        }
+        for forward in &mut self.dynamic_forwards {
+            if forward.port == ANY_PORT {
+                forward.port = free_port(forward.listen_address())?;
+                assigned.push((format!("-D {}", forward.to_spec()), forward.port));
+            }
+        }
+
+        if !assigned.is_empty() {
+            log::debug!("Assigned free ports: {:?}", assigned);
+        }
+        Ok(assigned)
+    }
+
+    /// Converts the config object to an argument vector useful for passing to the ssh cli.
+    ///
+    /// This provides all of the arguments necessary for creating an ssh tunnel connection. Additional arguments provided by
+    /// the `flags` parameter to [SshConfig::new] are prepended to the front of the default arguments, followed by any additional
+    /// options given to the [SshConfigBuilder]. Since ssh uses the first value it finds for each option, these take precedence
+    /// over the defaults. Here's a brief
+    /// explanation of each of the default arguments (see the [ssh(1) man](https://linux.die.net/man/1/ssh) and
+    /// [ssh_config(5) man ](https://linux.die.net/man/5/ssh_config) pages):
+    ///
+    /// * **-o ConnectTimeout=<connect_timeout>**: The time to wait for the connection to be established, if one was given.
+    ///
+    /// * **-o StrictHostKeyChecking=accept-new**: Automatically adds new host keys to the user known host file, but does not
+    ///   permit connections to hosts with changed host keys. This setting allows the app to connect without needing to
+    ///   a query on whether to add a new host, but also keeps the security risk from man-in-the-middle attacks low. This is
+    ///   the default [HostKeyPolicy], and the other policies replace it with their own options (see
+    ///   [HostKeyPolicy::to_options]).
+    ///
+    /// * **-o ServerAliveInterval=1**: Sends an alive message to the server every second.
+    ///
+    /// * **-o ServerAliveCountMax=<keepalive>**: Instructs the tunnel to shut down after this many alive messages are missed.
+    ///   This defines the response time to a server disconnect event.
+    ///
+    /// * **-o ExitOnForwardFailure=yes**: Instructs the tunnel to shut down if any of the port forwards fails to bind, so that
+    ///   the failure is reported instead of silently running with fewer forwards than requested.
+    ///
+    /// * **-o PermitLocalCommand=yes -o LocalCommand=echo SSH_TUNNEL_READY**: Prints a marker on ssh's stdout once the
+    ///   connection is authenticated and the local forwards are listening. This is how the library knows that the tunnel has
+    ///   connected, whether or not a remote shell is started (so it works with `-N`). Neither option may be set in the extra
+    ///   options, and since options given on the command line take precedence over ssh's config files, a `LocalCommand`
+    ///   there doesn't interfere either.
+    ///
+    /// * **-o ControlMaster=yes -o ControlPath=<control_path> -o ControlPersist=no**: Runs a ControlMaster session on the
+    ///   control socket, if the tunnel is [multiplexed](SshConfigBuilder::multiplexed). The session ends with the tunnel.
+    ///
+    /// * **-L local_port:to_host:remote_port**: Forwards the local port to the remote port. This is the option that makes this
+    ///   a tunnel, and it is repeated for each local [Forward].
+    ///
+    /// * **-R remote_port:to_host:local_port**: Forwards the remote port back to the local port. This is repeated for each
+    ///   remote [Forward].
+    ///
+    /// * **-D \[bind_address:\]port**: Opens a local SOCKS proxy on the port. This is repeated for each [DynamicForward].
+    ///
+    /// * **-J jump_host,...**: The chain of [jump hosts](JumpHost), if there are any. If any of the jump hosts needs its own
+    ///   identity file, which `-J` can't express, the chain is given as nested **-o ProxyCommand** options instead.
+    ///
+    /// * **-p port**: The ssh port of the end host, if one was given.
+    ///
+    /// * **-i identity_file**: Path to the private key that will be used.
+    ///
+    /// * **user@host**: The username and host address for the remote host.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = self.flags.clone();
+        for (key, value) in &self.options {
+            args.push("-o".to_string());
+            args.push(format!("{key}={value}"));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            args.push("-o".to_string());
+            args.push(format!("ConnectTimeout={timeout}"));
+        }
+        for (key, value) in self.host_key_policy.to_options() {
+            args.push("-o".to_string());
+            args.push(format!("{key}={value}"));
+        }
+        args.extend(
+            [
+                "-o",
+                "ServerAliveInterval=1",
+                "-o",
+                &format!("ServerAliveCountMax={}", self.keepalive),
+                "-o",
+                "ExitOnForwardFailure=yes",
+                "-o",
+                "PermitLocalCommand=yes",
+                "-o",
+                &format!("LocalCommand=echo {READY_MARKER}"),
+            ]
+            .iter()
+            .map(|a| a.to_string()),
+        );
+        if let Some(control_path) = &self.control_path {
+            args.extend(
+                [
+                    "-o",
+                    "ControlMaster=yes",
+                    "-o",
+                    &format!("ControlPath={control_path}"),
+                    "-o",
+                    "ControlPersist=no",
+                ]
+                .iter()
+                .map(|a| a.to_string()),
+            );
+        }
+        for forward in &self.forwards {
+            args.push(forward.direction().flag().to_string());
+            args.push(forward.to_spec());
+        }
+        for forward in &self.dynamic_forwards {
+            args.push("-D".to_string());
+            args.push(forward.to_spec());
+        }
+        if let Some((last, previous)) = self.jump_hosts.split_last() {
+            if self.jump_hosts.iter().any(|hop| hop.key_path.is_some()) {
+                args.push("-o".to_string());
+                args.push(format!("ProxyCommand={}", last.to_proxy_command(previous)));
+            } else {
+                let hops: Vec<String> = self
+                    .jump_hosts
+                    .iter()
+                    .map(|hop| hop.to_destination())
+                    .collect();
+                args.push("-J".to_string());
+                args.push(hops.join(","));
+            }
+        }
+        if let Some(port) = self.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        args.extend(
+            [
+                "-i",
+                &self.key_path,
+                &format!("{}@{}", self.username, self.end_host),
+            ]
+            .iter()
+            .map(|a| a.to_string()),
+        );
+        log::debug!("Args: {:?}", args);
+        args
+    }
+
+    /// Renders the config as an `ssh` command line that can be pasted into a POSIX shell
+    ///
+    /// This runs ssh with exactly the arguments given by [SshConfig::to_args], quoted for the shell, so that a misbehaving
+    /// tunnel can be reproduced in a terminal. Secrets in the extra options are [redacted](REDACTED).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ssh_tunnel::config::SshConfig;
+    /// let config = SshConfig::builder("bastion.example.com", "alice", "/keys/my key")
+    ///     .local_forward(5432, 5432)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     config.to_command_line(),
+    ///     "ssh -o StrictHostKeyChecking=accept-new -o ServerAliveInterval=1 -o ServerAliveCountMax=10 \
+    ///      -o ExitOnForwardFailure=yes -o PermitLocalCommand=yes -o 'LocalCommand=echo SSH_TUNNEL_READY' \
+    ///      -L 5432:localhost:5432 -i '/keys/my key' alice@bastion.example.com"
+    /// );
+    /// ```
+    pub fn to_command_line(&self) -> String {
+        let mut command = vec!["ssh".to_string()];
+        let mut is_option = false;
+        for arg in self.to_args() {
+            let arg = if is_option {
+                match arg.split_once('=') {
+                    Some((key, value)) => format!("{key}={}", redact_option(key, value)),
+                    None => arg,
+                }
+            } else {
+                arg
+            };
+            is_option = arg == "-o";
+            command.push(shell_quote(&arg));
+        }
+        command.join(" ")
+    }
+
+    /// Renders the config as a `Host` block for an OpenSSH client config (`~/.ssh/config`)
+    ///
+    /// With the block in place, `ssh <alias>` opens the same tunnel as this config. The extra options come first, followed
+    /// by the tunnel's own settings, matching the precedence of [SshConfig::to_args]. The `flags` have no config file
+    /// equivalent, so they are left out. Secrets in the extra options are [redacted](REDACTED).
+    pub fn to_host_block(&self, alias: &str) -> String {
+        let mut lines = vec![format!("Host {}", config_quote(alias))];
+        let mut push = |key: &str, value: &str| {
+            // A command takes the rest of the line, quoted for ssh already, so it's written out unquoted
+            let value = if key == "KnownHostsCommand" {
+                value.to_string()
+            } else {
+                config_quote(value)
+            };
+            lines.push(format!("    {key} {value}"));
+        };
+
+        push("HostName", &self.end_host);
+        push("User", &self.username);
+        if let Some(port) = self.port {
+            push("Port", &port.to_string());
+        }
+        push("IdentityFile", &self.key_path);
+        for (key, value) in &self.options {
+            push(key, &redact_option(key, value));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            push("ConnectTimeout", &timeout.to_string());
+        }
+        for (key, value) in self.host_key_policy.to_options() {
+            push(key, &value);
+        }
+        push("ServerAliveInterval", "1");
+        push("ServerAliveCountMax", &self.keepalive.to_string());
+        push("ExitOnForwardFailure", "yes");
+
+        for forward in &self.forwards {
+            let key = match forward.direction {
+                ForwardDirection::Local => "LocalForward",
+                ForwardDirection::Remote => "RemoteForward",
+            };
+            lines.push(format!(
+                "    {key} {} {}",
+                forward.listen_spec(),
+                forward.target_spec()
+            ));
+        }
+        for forward in &self.dynamic_forwards {
+            lines.push(format!("    DynamicForward {}", forward.to_spec()));
+        }
+
+        if let Some((last, previous)) = self.jump_hosts.split_last() {
+            if self.jump_hosts.iter().any(|hop| hop.key_path.is_some()) {
+                // The command is quoted for the shell already, so it's written out unquoted
+                lines.push(format!(
+                    "    ProxyCommand {}",
+                    last.to_proxy_command(previous)
+                ));
+            } else {
+                let hops: Vec<String> = self
+                    .jump_hosts
+                    .iter()
+                    .map(|hop| hop.to_destination())
+                    .collect();
+                lines.push(format!("    ProxyJump {}", hops.join(",")));
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+}
+
+/// The text that replaces secrets in [SshConfig::to_command_line] and [SshConfig::to_host_block]
+pub const REDACTED: &str = "<redacted>";
+
+/// The words that mark an environment variable as holding a secret, when they're one of the words of its name (such as
+/// `DB_PASSWORD` or `GITHUB_TOKEN`)
+const SECRET_WORDS: [&str; 8] = [
+    "PASS",
+    "PASSWD",
+    "PASSWORD",
+    "PASSPHRASE",
+    "SECRET",
+    "TOKEN",
+    "CREDENTIALS",
+    "APIKEY",
+];
+
+/// Redacts the secrets in the value of an extra option
+///
+/// None of ssh's options takes a secret itself, so only the variables of `SetEnv` options, which are a common way of passing
+/// credentials to the remote side, are redacted: a variable keeps its name, but has its value redacted if the name says that
+/// it's a secret (see [SECRET_WORDS]). Everything else is left as it is, so that the command still reproduces the tunnel.
+fn redact_option(key: &str, value: &str) -> String {
+    if !key.eq_ignore_ascii_case("SetEnv") {
+        return value.to_string();
+    }
+
+    value
+        .split_whitespace()
+        .map(|var| match var.split_once('=') {
+            Some((name, _)) if is_secret_variable(name) => format!("{name}={REDACTED}"),
+            _ => var.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Checks whether the name of an environment variable says that it holds a secret
+fn is_secret_variable(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    let words: Vec<&str> = name.split(['_', '-']).collect();
+    words.iter().any(|word| SECRET_WORDS.contains(word))
+        || words.windows(2).any(|pair| pair == ["API", "KEY"])
+}
+
+/// Quotes a value for an OpenSSH client config, if it needs quoting
+///
+/// ssh_config has no escape characters, so values are simply wrapped in double quotes when they contain whitespace.
+fn config_quote(value: &str) -> String {
+    if value.contains(char::is_whitespace) {
+        format!("\"{value}\"")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Builds an [SshConfig] object, with sensible defaults for everything but the end host, username and key
+///
+/// The defaults are:
+///
+/// * No port forwards, although at least one forward (of any kind) must be added before the config can be built.
+/// * A `to_host` of `localhost` for forwards added with [SshConfigBuilder::local_forward].
+/// * A keepalive time of 10 seconds.
+/// * ssh's own defaults for the port and connect timeout.
+///
+/// # Examples
+///
+/// ```
+/// # use ssh_tunnel::config::SshConfig;
+/// let config = SshConfig::builder("bastion.example.com", "alice", "~/.ssh/id_ed25519")
+///     .local_forward(5432, 5432)
+///     .local_forward(6379, 6379)
+///     .port(2222)
+///     .connect_timeout(15)
+///     .option("Compression", "yes")
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(config.forwards().len(), 2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SshConfigBuilder {
+    end_host: String,
+    username: String,
+    key_path: String,
+    to_host: String,
+    forwards: Vec<Forward>,
+    dynamic_forwards: Vec<DynamicForward>,
+    jump_hosts: Vec<JumpHost>,
+    keepalive: u32,
+    port: Option<u32>,
+    connect_timeout: Option<u32>,
+    control_path: Option<String>,
+    host_key_policy: HostKeyPolicy,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+}
+
+impl SshConfigBuilder {
+    /// Starts building an SshConfig object
+    ///
+    /// # Params
+    /// * `end_host`: The address of the end (or remote) host.
+    ///
+    /// * `username`: The username for the remote host.
+    ///
+    /// * `key_path`: The path to the private key to use.
+    pub fn new(end_host: &str, username: &str, key_path: &str) -> Self {
+        SshConfigBuilder {
+            end_host: String::from(end_host),
+            username: String::from(username),
+            key_path: normalize_key_path(key_path),
+            to_host: String::from("localhost"),
+            forwards: Vec::new(),
+            dynamic_forwards: Vec::new(),
+            jump_hosts: Vec::new(),
+            keepalive: default_keepalive(),
+            port: None,
+            connect_timeout: None,
+            control_path: None,
+            host_key_policy: HostKeyPolicy::default(),
+            options: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+
+    /// Sets the host that forwards added with [SshConfigBuilder::local_forward] connect to (defaults to `localhost`)
+    pub fn to_host(mut self, to_host: &str) -> Self {
+        self.to_host = String::from(to_host);
+        self
+    }
+
+    /// Adds a local forward from the local port to the remote port on the `to_host`
+    pub fn local_forward(mut self, local_port: u32, remote_port: u32) -> Self {
+        self.forwards
+            .push(Forward::new(local_port, &self.to_host, remote_port));
+        self
+    }
+
+    /// Adds a port forward, in either direction
+    pub fn forward(mut self, forward: Forward) -> Self {
+        self.forwards.push(forward);
+        self
+    }
+
+    /// Adds a dynamic (SOCKS proxy) forward
+    pub fn dynamic_forward(mut self, forward: DynamicForward) -> Self {
+        self.dynamic_forwards.push(forward);
+        self
+    }
+
+    /// Adds a jump host to the end of the chain of hops
+    pub fn jump_host(mut self, jump_host: JumpHost) -> Self {
+        self.jump_hosts.push(jump_host);
+        self
+    }
+
+    /// Sets the keepalive time (in seconds, defaults to 10)
+    pub fn keepalive(mut self, keepalive: u32) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Sets the ssh port of the end host
+    pub fn port(mut self, port: u32) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Sets the time (in seconds) to wait for the tunnel to connect
+    pub fn connect_timeout(mut self, timeout: u32) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Runs a ControlMaster session for the tunnel, on a control socket of its own (see [unique_control_path]), so that
+    /// forwards can be added to it and cancelled while it's running (see [control](crate::control))
+    pub fn multiplexed(self) -> Self {
+        self.control_path(&unique_control_path())
+    }
+
+    /// Runs a ControlMaster session for the tunnel, on the given control socket
+    ///
+    /// The path may use the tokens that ssh expands in `ControlPath`. Only one tunnel can use a control socket at a time.
+    pub fn control_path(mut self, path: &str) -> Self {
+        self.control_path = Some(String::from(path));
+        self
+    }
+
+    /// Adds an extra `-o key=value` option
+    pub fn option(mut self, key: &str, value: &str) -> Self {
+        self.options.push((String::from(key), String::from(value)));
+        self
+    }
+
+    /// Sets how ssh verifies the end host's key (defaults to [HostKeyPolicy::AcceptNew])
+    pub fn host_key_policy(mut self, policy: HostKeyPolicy) -> Self {
+        self.host_key_policy = policy;
+        self
+    }
+
+    /// Adds an extra flag
+    pub fn flag(mut self, flag: &str) -> Self {
+        self.flags.push(String::from(flag));
+        self
+    }
+
+    /// Validates the configuration and builds the SshConfig object
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] describing the first problem found. See [SshConfig::validate] for the checks.
+    pub fn build(self) -> Result<SshConfig, Error> {
+        let config = SshConfig {
+            end_host: self.end_host,
+            username: self.username,
+            key_path: self.key_path,
+            forwards: self.forwards,
+            dynamic_forwards: self.dynamic_forwards,
+            jump_hosts: self.jump_hosts,
+            keepalive: self.keepalive,
+            port: self.port,
+            connect_timeout: self.connect_timeout,
+            control_path: self.control_path,
+            host_key_policy: self.host_key_policy,
+            options: self.options,
+            flags: self.flags,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Finds a free port on the given local address, by binding an ephemeral port and releasing it
+pub(crate) fn free_port(address: &str) -> Result<u32, Error> {
+    TcpListener::bind((address, 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port() as u32)
+        .map_err(|err| Error::Io(format!("Failed to find a free port on {address}"), err))
+}
+
+/// Checks that the port is in the range 1-65535
+fn check_port(name: &str, port: u32) -> Result<(), Error> {
+    if (1..=65535).contains(&port) {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "{name} must be between 1 and 65535, not {port}"
+        )))
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use super::SshConfig;
+    use super::{
+        redact_option, windows_quote, DynamicForward, Forward, ForwardDirection, HostKeyPolicy,
+        JumpHost, SshConfig, ANY_PORT,
+    };
+    use crate::error::Error;
 
     #[test]
     fn test_config() {
@@ -144,13 +1586,17 @@ mod tests {
         let expected: Vec<String> = vec![
             "-T",
             "-o",
-            "UserKnownHostsFile=/dev/null",
+            "StrictHostKeyChecking=accept-new",
+            "-o",
+            "ServerAliveInterval=1",
+            "-o",
+            "ServerAliveCountMax=10",
             "-o",
-            "StrictHostKeyChecking=no",
+            "ExitOnForwardFailure=yes",
             "-o",
-            "ServerAliveCountMax=1",
+            "PermitLocalCommand=yes",
             "-o",
-            "ServerAliveInterval=10",
+            "LocalCommand=echo SSH_TUNNEL_READY",
             "-L",
             "1:tohost:2",
             "-i",
@@ -163,4 +1609,527 @@ mod tests {
         println!("{:?}", args);
         assert!(args == expected);
     }
+
+    #[test]
+    fn test_multiple_forwards() {
+        let mut config = SshConfig::new(
+            "endhost",
+            "username",
+            "keypath",
+            "localhost",
+            5432,
+            5432,
+            10,
+            &[],
+        );
+        config.add_forward(Forward::new(6379, "redis.internal", 6379));
+        config.add_forward(Forward::new(8080, "api.internal", 80));
+
+        let args = config.to_args();
+        let forwards: Vec<&str> = args
+            .windows(2)
+            .filter(|w| w[0] == "-L")
+            .map(|w| w[1].as_str())
+            .collect();
+        assert_eq!(
+            forwards,
+            vec![
+                "5432:localhost:5432",
+                "6379:redis.internal:6379",
+                "8080:api.internal:80"
+            ]
+        );
+        assert_eq!(args.last().unwrap(), "username@endhost");
+    }
+
+    #[test]
+    fn test_parse_forward() {
+        let forward: Forward = "6379:redis.internal:6380".parse().unwrap();
+        assert_eq!(forward, Forward::new(6379, "redis.internal", 6380));
+
+        let forward: Forward = "[::1]:6379:[fd00::5]:6379".parse().unwrap();
+        assert_eq!(
+            forward,
+            Forward::new(6379, "fd00::5", 6379).with_bind_address("::1")
+        );
+        assert_eq!(forward.to_spec(), "[::1]:6379:[fd00::5]:6379");
+
+        assert!("6379:redis.internal".parse::<Forward>().is_err());
+        assert!("abc:redis.internal:6379".parse::<Forward>().is_err());
+    }
+
+    #[test]
+    fn test_remote_forwards() {
+        let mut config = SshConfig::new(
+            "endhost",
+            "username",
+            "keypath",
+            "localhost",
+            5432,
+            5432,
+            10,
+            &[],
+        );
+        config
+            .add_forward(Forward::parse("9000:localhost:3000", ForwardDirection::Remote).unwrap());
+
+        let args = config.to_args();
+        let forwards: Vec<(&str, &str)> = args
+            .windows(2)
+            .filter(|w| w[0] == "-L" || w[0] == "-R")
+            .map(|w| (w[0].as_str(), w[1].as_str()))
+            .collect();
+        assert_eq!(
+            forwards,
+            vec![("-L", "5432:localhost:5432"), ("-R", "9000:localhost:3000")]
+        );
+
+        config.set_forwards(vec![Forward::remote(9000, "localhost", 3000)]);
+        assert!(!config.to_args().contains(&"-L".to_string()));
+    }
+
+    #[test]
+    fn test_dynamic_forwards() {
+        let mut config = SshConfig::new(
+            "endhost",
+            "username",
+            "keypath",
+            "localhost",
+            5432,
+            5432,
+            10,
+            &[],
+        );
+        config.set_forwards(vec![]);
+        config.add_dynamic_forward("1080".parse().unwrap());
+        config.add_dynamic_forward("127.0.0.1:1081".parse().unwrap());
+
+        assert_eq!(
+            config.dynamic_forwards()[1],
+            DynamicForward::new(Some("127.0.0.1"), 1081)
+        );
+
+        let args = config.to_args();
+        let forwards: Vec<(&str, &str)> = args
+            .windows(2)
+            .filter(|w| w[0].starts_with('-') && w[0] != "-o" && w[0] != "-i")
+            .map(|w| (w[0].as_str(), w[1].as_str()))
+            .collect();
+        assert_eq!(forwards, vec![("-D", "1080"), ("-D", "127.0.0.1:1081")]);
+        assert!("localhost:".parse::<DynamicForward>().is_err());
+    }
+
+    #[test]
+    fn test_jump_hosts() {
+        let mut config = SshConfig::new(
+            "endhost",
+            "username",
+            "keypath",
+            "localhost",
+            5432,
+            5432,
+            10,
+            &[],
+        );
+        config.add_jump_host("alice@bastion1:2222".parse().unwrap());
+        config.add_jump_host("bastion2".parse().unwrap());
+
+        let args = config.to_args();
+        let jump = args.iter().position(|a| a == "-J").unwrap();
+        assert_eq!(args[jump + 1], "alice@bastion1:2222,bastion2");
+
+        assert_eq!(
+            "bob@[fe80::1]:22".parse::<JumpHost>().unwrap(),
+            JumpHost::new("fe80::1", Some("bob"), Some(22), None)
+        );
+        assert!("alice@".parse::<JumpHost>().is_err());
+        assert!("bastion:ssh".parse::<JumpHost>().is_err());
+    }
+
+    #[test]
+    fn test_jump_hosts_with_keys() {
+        let mut config = SshConfig::new(
+            "endhost",
+            "username",
+            "keypath",
+            "localhost",
+            5432,
+            5432,
+            10,
+            &[],
+        );
+        config.add_jump_host(JumpHost::new(
+            "bastion1",
+            Some("alice"),
+            None,
+            Some("/keys/one"),
+        ));
+        config.add_jump_host(JumpHost::new(
+            "bastion2",
+            Some("bob"),
+            Some(2222),
+            Some("/keys/my two"),
+        ));
+
+        let args = config.to_args();
+        assert!(!args.contains(&"-J".to_string()));
+        let proxy = args
+            .iter()
+            .find(|a| a.starts_with("ProxyCommand="))
+            .unwrap();
+        assert_eq!(
+            proxy,
+            "ProxyCommand=ssh -i '/keys/my two' -p 2222 \
+             -o 'ProxyCommand=ssh -i /keys/one -W %%h:%%p alice@bastion1' \
+             -W %h:%p bob@bastion2"
+        );
+
+        // On Windows, the nested commands have to reach ssh as a single argument
+        let command_line: Vec<String> = args.iter().map(|a| windows_quote(a)).collect();
+        assert_eq!(split_windows_command_line(&command_line.join(" ")), args);
+    }
+
+    /// Splits a Windows command line into arguments by the rules of the C runtime, as ssh does
+    fn split_windows_command_line(line: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut arg = String::new();
+        let (mut in_arg, mut in_quotes, mut backslashes) = (false, false, 0);
+        for c in line.chars() {
+            match c {
+                '\\' => {
+                    backslashes += 1;
+                    in_arg = true;
+                    continue;
+                }
+                '"' => {
+                    arg.push_str(&"\\".repeat(backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        arg.push('"');
+                    } else {
+                        in_quotes = !in_quotes;
+                    }
+                    in_arg = true;
+                }
+                ' ' | '\t' if !in_quotes => {
+                    arg.push_str(&"\\".repeat(backslashes));
+                    if in_arg {
+                        args.push(std::mem::take(&mut arg));
+                    }
+                    in_arg = false;
+                }
+                _ => {
+                    arg.push_str(&"\\".repeat(backslashes));
+                    arg.push(c);
+                    in_arg = true;
+                }
+            }
+            backslashes = 0;
+        }
+        arg.push_str(&"\\".repeat(backslashes));
+        if in_arg {
+            args.push(arg);
+        }
+        args
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .port(2222)
+            .connect_timeout(15)
+            .option("Compression", "yes")
+            .build()
+            .unwrap();
+
+        let args = config.to_args();
+        assert_eq!(
+            &args[..4],
+            ["-o", "Compression=yes", "-o", "ConnectTimeout=15"]
+        );
+        assert!(args.windows(2).any(|w| w == ["-L", "5432:localhost:5432"]));
+        assert!(args.windows(2).any(|w| w == ["-p", "2222"]));
+        assert!(args
+            .windows(2)
+            .any(|w| w == ["-o", "ServerAliveCountMax=10"]));
+        assert!(!args.iter().any(|arg| arg.starts_with("ControlMaster")));
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn test_multiplexed() {
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .control_path("/tmp/tunnel-%C")
+            .build()
+            .unwrap();
+
+        assert_eq!(config.control_path(), Some("/tmp/tunnel-%C"));
+        let args = config.to_args().join(" ");
+        assert!(args.contains(
+            "-o ControlMaster=yes -o ControlPath=/tmp/tunnel-%C -o ControlPersist=no -L 5432:localhost:5432"
+        ));
+
+        // Tunnels to the same host get control sockets of their own
+        let multiplexed = || {
+            SshConfig::builder("endhost", "username", "keypath")
+                .local_forward(5432, 5432)
+                .multiplexed()
+                .build()
+                .unwrap()
+        };
+        let (first, second) = (multiplexed(), multiplexed());
+        assert!(first.control_path().unwrap().ends_with("-%C"));
+        assert_ne!(first.control_path(), second.control_path());
+
+        let error = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .control_path("")
+            .build();
+        assert!(matches!(error, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let error = |builder: super::SshConfigBuilder| match builder.build() {
+            Err(Error::Config(msg)) => msg,
+            other => panic!("Expected a config error, got {:?}", other),
+        };
+
+        let builder = || SshConfig::builder("endhost", "username", "keypath");
+
+        assert_eq!(error(builder()), "At least one port forward is required");
+        assert_eq!(
+            error(SshConfig::builder("", "username", "keypath").local_forward(1, 2)),
+            "The end host must not be empty"
+        );
+        assert_eq!(
+            error(builder().local_forward(70000, 5432)),
+            "Forward local port must be between 1 and 65535, not 70000"
+        );
+        assert_eq!(
+            error(builder().local_forward(1, 2).port(0)),
+            "SSH port must be between 1 and 65535, not 0"
+        );
+        assert_eq!(
+            error(builder().local_forward(1, 2).connect_timeout(0)),
+            "The connect timeout must be at least 1 second"
+        );
+        assert_eq!(
+            error(builder().local_forward(1, 2).option("Bad Option", "yes")),
+            "Bad option name: 'Bad Option'"
+        );
+        assert_eq!(
+            error(builder().local_forward(1, 2).option("LocalCommand", "true")),
+            "Option LocalCommand is reserved for detecting the connection"
+        );
+        assert_eq!(
+            error(
+                builder()
+                    .local_forward(1, 2)
+                    .option("permitlocalcommand", "no")
+            ),
+            "Option permitlocalcommand is reserved for detecting the connection"
+        );
+        assert_eq!(
+            error(
+                builder()
+                    .local_forward(1, 2)
+                    .host_key_policy(HostKeyPolicy::Pinned(vec![]))
+            ),
+            "At least one host key must be pinned"
+        );
+        assert_eq!(
+            error(
+                builder()
+                    .local_forward(1, 2)
+                    .host_key_policy(HostKeyPolicy::Pinned(vec!["abc".to_string()]))
+            ),
+            "Bad host key fingerprint: 'abc'"
+        );
+    }
+
+    #[test]
+    fn test_host_key_policy() {
+        let args = |policy: HostKeyPolicy| {
+            SshConfig::builder("endhost", "username", "keypath")
+                .local_forward(5432, 5432)
+                .host_key_policy(policy)
+                .build()
+                .unwrap()
+                .to_args()
+                .join(" ")
+        };
+
+        assert!(args(HostKeyPolicy::default()).starts_with("-o StrictHostKeyChecking=accept-new"));
+        assert!(args(HostKeyPolicy::Strict).starts_with("-o StrictHostKeyChecking=yes"));
+        assert!(args(HostKeyPolicy::KnownHostsFile(
+            "~/.ssh/db_known_hosts".to_string()
+        ))
+        .starts_with(
+            "-o StrictHostKeyChecking=accept-new -o UserKnownHostsFile=~/.ssh/db_known_hosts"
+        ));
+
+        let pinned = HostKeyPolicy::Pinned(vec![
+            "SHA256:Jx8m3bDPfE5ZQbHx0rK2pT0A4x3Sx+9YwS4mIXmV0Lk".to_string(),
+            "SHA256:q7Vt2fJ0mXcXr8a1nLbD0Zk6w9sYpE3uHh5oTgC4RzM".to_string(),
+        ]);
+        assert!(args(pinned).starts_with(
+            "-o StrictHostKeyChecking=yes -o UserKnownHostsFile=none -o GlobalKnownHostsFile=none \
+             -o KnownHostsCommand=/bin/sh -c \"case %f in \
+             SHA256:Jx8m3bDPfE5ZQbHx0rK2pT0A4x3Sx+9YwS4mIXmV0Lk|SHA256:q7Vt2fJ0mXcXr8a1nLbD0Zk6w9sYpE3uHh5oTgC4RzM) \
+             echo '%H' %t %K ;; SHA256:*) echo 'Host key is not pinned:' %f >&2 ;; esac\""
+        ));
+
+        assert_eq!(
+            "strict".parse::<HostKeyPolicy>().unwrap(),
+            HostKeyPolicy::Strict
+        );
+        assert_eq!(
+            "pin:SHA256:abc,SHA256:def"
+                .parse::<HostKeyPolicy>()
+                .unwrap(),
+            HostKeyPolicy::Pinned(vec!["SHA256:abc".to_string(), "SHA256:def".to_string()])
+        );
+        assert_eq!(
+            "known-hosts:/tmp/known_hosts"
+                .parse::<HostKeyPolicy>()
+                .unwrap(),
+            HostKeyPolicy::KnownHostsFile("/tmp/known_hosts".to_string())
+        );
+        assert!("accept-all".parse::<HostKeyPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_command_line() {
+        let config = SshConfig::builder("endhost", "username", "/keys/it's mine")
+            .local_forward(5432, 5432)
+            .option("SetEnv", "DB_PASSWORD=hunter2 REGION=eu")
+            .option("PasswordAuthentication", "no")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            config.to_command_line(),
+            "ssh -o 'SetEnv=DB_PASSWORD=<redacted> REGION=eu' -o PasswordAuthentication=no \
+             -o StrictHostKeyChecking=accept-new -o ServerAliveInterval=1 -o ServerAliveCountMax=10 \
+             -o ExitOnForwardFailure=yes -o PermitLocalCommand=yes -o 'LocalCommand=echo SSH_TUNNEL_READY' \
+             -L 5432:localhost:5432 -i '/keys/it'\\''s mine' username@endhost"
+        );
+    }
+
+    #[test]
+    fn test_redact_option() {
+        assert_eq!(redact_option("PasswordAuthentication", "no"), "no");
+        assert_eq!(redact_option("PubkeyAuthentication", "yes"), "yes");
+        assert_eq!(
+            redact_option("SetEnv", "LANG=C.UTF-8 TZ=UTC PASSENGER_ENV=prod"),
+            "LANG=C.UTF-8 TZ=UTC PASSENGER_ENV=prod"
+        );
+        assert_eq!(
+            redact_option(
+                "setenv",
+                "DB_PASS=a github_token=b STRIPE_API_KEY=c REGION=eu"
+            ),
+            "DB_PASS=<redacted> github_token=<redacted> STRIPE_API_KEY=<redacted> REGION=eu"
+        );
+    }
+
+    #[test]
+    fn test_windows_command_line() {
+        assert_eq!(windows_quote("-L"), "-L");
+        assert_eq!(windows_quote(r"C:\keys\id"), r"C:\keys\id");
+        assert_eq!(windows_quote(""), r#""""#);
+        assert_eq!(windows_quote(r"C:\my keys\"), r#""C:\my keys\\""#);
+        assert_eq!(windows_quote(r#"say "hi\""#), r#""say \"hi\\\"""#);
+
+        let config = SshConfig::builder("endhost", "username", "/keys/my key")
+            .local_forward(5432, 5432)
+            .build()
+            .unwrap();
+        let args: Vec<String> = config.to_args().iter().map(|a| windows_quote(a)).collect();
+        assert_eq!(
+            args.join(" "),
+            "-o StrictHostKeyChecking=accept-new -o ServerAliveInterval=1 -o ServerAliveCountMax=10 \
+             -o ExitOnForwardFailure=yes -o PermitLocalCommand=yes -o \"LocalCommand=echo SSH_TUNNEL_READY\" \
+             -L 5432:localhost:5432 -i \"/keys/my key\" username@endhost"
+        );
+    }
+
+    #[test]
+    fn test_host_block() {
+        let config = SshConfig::builder("endhost", "username", "/keys/my key")
+            .local_forward(5432, 5432)
+            .forward(Forward::remote(9000, "::1", 3000))
+            .dynamic_forward(DynamicForward::new(None, 1080))
+            .jump_host(JumpHost::new("bastion", Some("alice"), Some(2222), None))
+            .port(2200)
+            .option("IdentityAgent", "none")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            config.to_host_block("db"),
+            "Host db
+    HostName endhost
+    User username
+    Port 2200
+    IdentityFile \"/keys/my key\"
+    IdentityAgent none
+    StrictHostKeyChecking accept-new
+    ServerAliveInterval 1
+    ServerAliveCountMax 10
+    ExitOnForwardFailure yes
+    LocalForward 5432 localhost:5432
+    RemoteForward 9000 [::1]:3000
+    DynamicForward 1080
+    ProxyJump alice@bastion:2222
+"
+        );
+    }
+
+    #[test]
+    fn test_assign_free_ports() {
+        let mut config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(ANY_PORT, 5432)
+            .local_forward(6379, 6379)
+            .forward(Forward::remote(9000, "localhost", 3000))
+            .dynamic_forward(DynamicForward::new(None, ANY_PORT))
+            .build()
+            .unwrap();
+        assert_eq!(config.local_ports(), vec![6379]);
+
+        let assigned = config.assign_free_ports().unwrap();
+        assert_eq!(assigned.len(), 2);
+        assert!(assigned.iter().all(|&(_, port)| port != ANY_PORT));
+        let (local, dynamic) = (assigned[0].1, assigned[1].1);
+        assert_eq!(config.forwards()[0].local_port(), local);
+        assert_eq!(config.forwards()[1].local_port(), 6379);
+        assert_eq!(config.dynamic_forwards()[0].port(), dynamic);
+        assert_eq!(config.local_ports(), vec![local, 6379, dynamic]);
+        assert_eq!(
+            config.local_listeners(),
+            vec![
+                ("127.0.0.1", local),
+                ("127.0.0.1", 6379),
+                ("127.0.0.1", dynamic)
+            ]
+        );
+        assert_eq!(
+            assigned,
+            vec![
+                (format!("-L {local}:localhost:5432"), local),
+                (format!("-D {dynamic}"), dynamic)
+            ]
+        );
+
+        // Once assigned, there's nothing left to pick
+        assert!(config.assign_free_ports().unwrap().is_empty());
+
+        // The local port of a remote forward is connected to, so it can't be picked
+        let result = SshConfig::builder("endhost", "username", "keypath")
+            .forward(Forward::remote(9000, "localhost", ANY_PORT))
+            .build();
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
 }
@@ -0,0 +1,264 @@
+//! Changing the forwards of a running tunnel
+//!
+//! A tunnel that is [multiplexed](crate::config::SshConfigBuilder::multiplexed) runs a ControlMaster session, which other
+//! ssh processes can send commands to through its control socket. This module uses that to add port forwards to a running
+//! tunnel and to cancel them (`ssh -O forward` and `ssh -O cancel`), which takes a moment rather than a new connection, and
+//! leaves the other forwards alone.
+//!
+//! [add_forward] and [cancel_forward] work on a running tunnel, and keep its config up to date (through
+//! [ChildProc::add_forward] and [ChildProc::cancel_forward]). The tunnel isn't kept locked while ssh runs the command, so
+//! its watcher, its supervisor and anything else that uses it can carry on in the meantime.
+
+use std::io;
+use std::process;
+
+use crate::config::{self, Forward, ForwardDirection, SshConfig, ANY_PORT};
+use crate::error::{Error, Result};
+use crate::lock_tunnel;
+use crate::status::SshStatus;
+use crate::tunnel::{ChildProc, SshTunnel};
+
+/// A command for a tunnel's ControlMaster session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Adds a forward (`ssh -O forward`)
+    Forward,
+
+    /// Cancels a forward (`ssh -O cancel`)
+    Cancel,
+}
+
+impl ControlCommand {
+    /// The name of the command, as given to `ssh -O`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Forward => "forward",
+            ControlCommand::Cancel => "cancel",
+        }
+    }
+}
+
+/// Builds the arguments that send a command about a forward to the tunnel's ControlMaster session
+///
+/// The destination is given along with the control socket, so that ssh expands any tokens in the control path just as it
+/// did for the tunnel.
+///
+/// # Errors
+///
+/// Returns an [Error::Config] if the tunnel isn't multiplexed.
+pub fn control_args(
+    config: &SshConfig,
+    command: ControlCommand,
+    forward: &Forward,
+) -> Result<Vec<String>> {
+    let control_path = config
+        .control_path()
+        .ok_or_else(|| Error::Config("The tunnel isn't multiplexed".to_string()))?;
+
+    let mut args = vec![
+        "-o".to_string(),
+        format!("ControlPath={control_path}"),
+        "-O".to_string(),
+        command.name().to_string(),
+        forward.direction().flag().to_string(),
+        forward.to_spec(),
+    ];
+    if let Some(port) = config.port() {
+        args.push("-p".to_string());
+        args.push(port.to_string());
+    }
+    args.push(format!("{}@{}", config.username(), config.end_host()));
+    Ok(args)
+}
+
+/// Sends a command about a forward to the tunnel's ControlMaster session, and waits for it to be done
+///
+/// # Errors
+///
+/// * Returns an [Error::Config] if the tunnel isn't multiplexed.
+/// * Returns an [Error::Spawn] if ssh fails to spawn.
+/// * Returns an [Error::Ssh] with [SshStatus::ForwardFailed] (or [SshStatus::RemoteForwardFailed], for a remote forward)
+///   if the session couldn't set up a new forward.
+/// * Returns an [Error::Io] with ssh's message if the command fails for any other reason, such as the session not running.
+pub fn send(config: &SshConfig, command: ControlCommand, forward: &Forward) -> Result<()> {
+    let args = control_args(config, command, forward)?;
+    log::debug!("Control args: {:?}", args);
+    let output = process::Command::new("ssh")
+        .args(args)
+        .stdin(process::Stdio::null())
+        .output()
+        .map_err(Error::Spawn)?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let msg = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    log::debug!("Control command {} failed: {msg}", command.name());
+    if command == ControlCommand::Forward && msg.contains("forwarding request failed") {
+        return Err(Error::Ssh(match forward.direction() {
+            ForwardDirection::Local => SshStatus::ForwardFailed(forward.local_port()),
+            ForwardDirection::Remote => SshStatus::RemoteForwardFailed(forward.remote_port()),
+        }));
+    }
+    Err(Error::io(
+        &format!("Failed to {} forward {}", command.name(), forward.to_spec()),
+        io::Error::other(msg),
+    ))
+}
+
+/// Adds a forward to a running tunnel, and records it in the tunnel's config
+///
+/// A local forward whose local port is [ANY_PORT] is given a free port first. The tunnel is only locked to read its config
+/// and to record the forward, not while ssh adds it.
+///
+/// # Returns
+///
+/// Returns the forward that was added, with the port that it listens on.
+///
+/// # Errors
+///
+/// * Returns the errors of [send], or an [Error::Io] if a free port can't be found.
+/// * Returns an [Error::Poisoned] if the tunnel can't be locked.
+/// * Returns the errors of [ChildProc::add_forward] if the forward can't be recorded.
+pub fn add_forward<T: ChildProc>(tunnel: &SshTunnel<T>, forward: Forward) -> Result<Forward> {
+    let config = lock_tunnel(tunnel)?.config().clone();
+    let forward =
+        if forward.direction() == ForwardDirection::Local && forward.local_port() == ANY_PORT {
+            let port = config::free_port(forward.listen_address())?;
+            let assigned = Forward::new(port, forward.to_host(), forward.remote_port());
+            match forward.bind_address() {
+                Some(bind_address) => assigned.with_bind_address(bind_address),
+                None => assigned,
+            }
+        } else {
+            forward
+        };
+
+    send(&config, ControlCommand::Forward, &forward)?;
+    log::info!("Added forward {}", forward.to_spec());
+    lock_tunnel(tunnel)?.add_forward(forward.clone())?;
+    Ok(forward)
+}
+
+/// Cancels one of a running tunnel's forwards, and removes it from the tunnel's config
+///
+/// As with [add_forward], the tunnel isn't locked while ssh cancels the forward.
+///
+/// # Errors
+///
+/// * Returns an [Error::Config] if the tunnel has no such forward, or the errors of [send].
+/// * Returns an [Error::Poisoned] if the tunnel can't be locked.
+/// * Returns the errors of [ChildProc::cancel_forward] if the change can't be recorded.
+pub fn cancel_forward<T: ChildProc>(tunnel: &SshTunnel<T>, forward: &Forward) -> Result<()> {
+    let config = lock_tunnel(tunnel)?.config().clone();
+    if !config.forwards().contains(forward) {
+        return Err(Error::Config(format!(
+            "The tunnel has no forward {}",
+            forward.to_spec()
+        )));
+    }
+
+    send(&config, ControlCommand::Cancel, forward)?;
+    log::info!("Cancelled forward {}", forward.to_spec());
+    lock_tunnel(tunnel)?.cancel_forward(forward)
+}
+
+/// Removes a forward from a config, leaving the other forwards in order
+pub(crate) fn remove_forward(config: &mut SshConfig, forward: &Forward) {
+    let forwards = config
+        .forwards()
+        .iter()
+        .filter(|&f| f != forward)
+        .cloned()
+        .collect();
+    config.set_forwards(forwards);
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use super::{add_forward, cancel_forward};
+    use super::{control_args, ControlCommand};
+    #[cfg(unix)]
+    use crate::config::ANY_PORT;
+    use crate::config::{Forward, SshConfig};
+    use crate::error::Error;
+    #[cfg(unix)]
+    use crate::tunnel::{mock::MockChild, ChildProc};
+
+    #[test]
+    fn test_control_args() {
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .port(2222)
+            .multiplexed()
+            .build()
+            .unwrap();
+
+        let args = control_args(
+            &config,
+            ControlCommand::Forward,
+            &Forward::new(6379, "localhost", 6379),
+        )
+        .unwrap();
+        let control_path = format!("ControlPath={}", config.control_path().unwrap());
+        assert_eq!(
+            args,
+            vec![
+                "-o",
+                &control_path,
+                "-O",
+                "forward",
+                "-L",
+                "6379:localhost:6379",
+                "-p",
+                "2222",
+                "username@endhost",
+            ]
+        );
+
+        let args = control_args(
+            &config,
+            ControlCommand::Cancel,
+            &Forward::remote(9000, "localhost", 3000),
+        )
+        .unwrap();
+        assert_eq!(args[2..6], ["-O", "cancel", "-R", "9000:localhost:3000"]);
+
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .build()
+            .unwrap();
+        let forward = Forward::new(6379, "localhost", 6379);
+        assert!(matches!(
+            control_args(&config, ControlCommand::Forward, &forward),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unchanged_forwards() {
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(ANY_PORT, 5432)
+            .build()
+            .unwrap();
+        let tunnel = MockChild::new(config).unwrap();
+        let forward = Forward::new(6379, "localhost", 6379);
+
+        // Neither of these gets as far as running ssh
+        assert!(matches!(
+            add_forward(&tunnel, forward.clone()),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            cancel_forward(&tunnel, &forward),
+            Err(Error::Config(_))
+        ));
+        let mut tunnel = tunnel.lock().unwrap();
+        assert_eq!(tunnel.config().forwards().len(), 1);
+        assert!(matches!(tunnel.add_forward(forward), Err(Error::Config(_))));
+        tunnel.kill();
+    }
+}
@@ -0,0 +1,121 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use crate::preflight::PreflightFailure;
+use crate::status::SshStatus;
+
+/// Standard [std::result::Result] type for most ssh-tunnel functions.
+///
+/// We're following the [std::io::Result] practice here, which trades specificity for readability. Since the vast majority of
+/// the functions in this library return [result::Result<T, Error>] values, this seems like a valid trade-off.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The errors returned by the ssh-tunnel library
+///
+/// Unlike [SshStatus], which is the state of a tunnel as shown to the user, this says what went wrong in a form that callers
+/// can handle programmatically. It implements [std::error::Error], with the underlying [io::Error] as the source where there
+/// is one, so it works with `?` and error crates like `anyhow`. An error can be converted to the [SshStatus] to show the
+/// user with [SshStatus::from].
+#[derive(Debug)]
+pub enum Error {
+    /// The ssh process failed to spawn
+    Spawn(io::Error),
+
+    /// An io operation failed. Gives a description of the operation, and the underlying error.
+    Io(String, io::Error),
+
+    /// A lock was poisoned by a thread that panicked while holding it. Gives the name of the locked object.
+    Poisoned(String),
+
+    /// The configuration is invalid
+    Config(String),
+
+    /// A [preflight](crate::preflight) check failed
+    Preflight(PreflightFailure),
+
+    /// ssh reported an error, or the tunnel failed after ssh started. Gives the resulting status.
+    Ssh(SshStatus),
+}
+
+impl Error {
+    /// Builds an [Error::Io] from a description of the failed operation and the underlying error
+    pub(crate) fn io(operation: &str, err: io::Error) -> Self {
+        Error::Io(operation.to_string(), err)
+    }
+
+    /// Converts the error to the [SshStatus] that represents it to the user
+    ///
+    /// Config and preflight errors become [SshStatus::ConfigError] and [SshStatus::PreflightFailed], ssh errors carry their
+    /// own status, and everything else is an [SshStatus::AppError].
+    pub fn to_status(&self) -> SshStatus {
+        match self {
+            Error::Config(msg) => SshStatus::ConfigError(msg.clone()),
+            Error::Preflight(failure) => SshStatus::PreflightFailed(failure.clone()),
+            Error::Ssh(status) => status.clone(),
+            _ => SshStatus::AppError(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Spawn(err) => write!(f, "Failed to spawn ssh: {err}"),
+            Error::Io(operation, err) => write!(f, "{operation}: {err}"),
+            Error::Poisoned(name) => write!(f, "Failed to lock {name}: the lock is poisoned"),
+            Error::Config(msg) => write!(f, "{msg}"),
+            Error::Preflight(failure) => write!(f, "{failure}"),
+            Error::Ssh(status) => write!(f, "ssh failed: {status}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Spawn(err) | Error::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for SshStatus {
+    fn from(err: Error) -> Self {
+        err.to_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use std::io;
+
+    use super::Error;
+    use crate::status::SshStatus;
+
+    #[test]
+    fn test_error_status() {
+        let err = Error::io(
+            "Failed to read from stdout",
+            io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"),
+        );
+        assert!(err.source().is_some());
+        assert_eq!(
+            SshStatus::from(err),
+            SshStatus::AppError("Failed to read from stdout: broken pipe".to_string())
+        );
+
+        let err = Error::Config("Bad option name: 'X'".to_string());
+        assert!(err.source().is_none());
+        assert_eq!(
+            SshStatus::from(err),
+            SshStatus::ConfigError("Bad option name: 'X'".to_string())
+        );
+
+        let err = Error::Ssh(SshStatus::ForwardFailed(5432));
+        assert_eq!(err.to_string(), "ssh failed: ForwardFailed(5432)");
+        assert_eq!(SshStatus::from(err), SshStatus::ForwardFailed(5432));
+    }
+}
@@ -0,0 +1,146 @@
+//! Checking that a connected tunnel actually reaches its service
+//!
+//! A tunnel is reported as [Connected](SshStatus::Connected) as soon as the ssh session is up, but that doesn't mean that the
+//! service behind a forward can be reached: it could be down, or the end host could be unable to reach it. A [HealthCheck]
+//! probes a forwarded port periodically while a supervised tunnel (see [reconnect](crate::reconnect)) is connected, and
+//! reports [SshStatus::Unhealthy] when the probe keeps failing. The [Probe] can be as simple as checking that the
+//! connection stays open, or it can speak the service's protocol (see [ProbeKind]).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::call_status_callback;
+use crate::config::ForwardDirection;
+use crate::probe::{Probe, ProbeKind};
+use crate::reconnect::Supervisor;
+use crate::status::SshStatus;
+use crate::tunnel::{ChildProc, SshTunnel};
+
+/// Decides how a supervised tunnel is checked while it's connected
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// The local port to probe, or [None] for the local port of the tunnel's first local forward (dynamic forwards are
+    /// skipped, since they lead to a SOCKS proxy rather than a service)
+    pub port: Option<u32>,
+
+    /// The time between probes
+    pub interval: Duration,
+
+    /// How long each probe may take
+    pub timeout: Duration,
+
+    /// The number of probes in a row that have to fail before the tunnel is reported as unhealthy
+    pub failures: u32,
+
+    /// Whether an unhealthy tunnel is restarted. If it is, the tunnel exits with its [SshStatus::Unhealthy] status, and
+    /// the supervisor reconnects it if its policy allows.
+    pub reconnect: bool,
+
+    /// Probes the service behind the port. Defaults to [ProbeKind::Tcp].
+    pub probe: Arc<dyn Probe>,
+}
+
+impl Default for HealthCheck {
+    /// Probes the first local forward with [ProbeKind::Tcp] every 30 seconds, reporting the tunnel as unhealthy (without
+    /// restarting it) after 3 failures
+    fn default() -> Self {
+        HealthCheck {
+            port: None,
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            failures: 3,
+            reconnect: false,
+            probe: Arc::new(ProbeKind::Tcp),
+        }
+    }
+}
+
+impl HealthCheck {
+    /// The local port that the check probes for the given tunnel, if it has one
+    fn port_of<T: ChildProc>(&self, tunnel: &SshTunnel<T>) -> Option<u32> {
+        if self.port.is_some() {
+            return self.port;
+        }
+        let tunnel = crate::lock_tunnel(tunnel).ok()?;
+        tunnel
+            .config()
+            .forwards()
+            .iter()
+            .find(|forward| forward.direction() == ForwardDirection::Local)
+            .map(|forward| forward.local_port())
+    }
+}
+
+/// Starts a thread that checks the health of a supervised tunnel process, until the process exits or the supervisor stops
+///
+/// Nothing is probed until `connected` is set. Only changes in the tunnel's health are reported to the status_callback:
+/// [SshStatus::Healthy] the first time the probe passes, [SshStatus::Unhealthy] once it has failed enough times in a row,
+/// and [SshStatus::Connected] followed by [SshStatus::Healthy] when it passes again after that. If the check
+/// [restarts](HealthCheck::reconnect) unhealthy tunnels, the process is killed by the supervisor instead.
+pub(crate) fn spawn_monitor<T, F>(
+    check: HealthCheck,
+    supervisor: Supervisor<T>,
+    tunnel: SshTunnel<T>,
+    connected: Arc<AtomicBool>,
+    status_callback: Arc<Mutex<F>>,
+) where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    let port = match check.port_of(&tunnel) {
+        Some(port) => port,
+        None => {
+            log::warn!("The tunnel has no local port to check");
+            return;
+        }
+    };
+
+    log::debug!("Spawning health monitor for port {port}");
+    thread::spawn(move || {
+        let mut failures = 0;
+        // Whether the last report was healthy, or [None] before the first one
+        let mut healthy = None;
+        while !supervisor.wait_stopped(check.interval) {
+            match crate::lock_tunnel(&tunnel).map(|mut tunnel| tunnel.exited()) {
+                Ok(None) => {}
+                _ => return,
+            }
+            if !connected.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            match check.probe.probe(port, check.timeout) {
+                Ok(report) => {
+                    failures = 0;
+                    match healthy {
+                        Some(true) => continue,
+                        Some(false) => {
+                            log::info!("Health check of port {port} passed again");
+                            call_status_callback(status_callback.clone(), SshStatus::Connected);
+                        }
+                        None => {}
+                    }
+                    healthy = Some(true);
+                    call_status_callback(status_callback.clone(), SshStatus::Healthy(report));
+                }
+                Err(err) => {
+                    failures += 1;
+                    log::debug!("Health check of port {port} failed ({failures}): {err}");
+                    if failures < check.failures || healthy == Some(false) {
+                        continue;
+                    }
+
+                    let status = SshStatus::Unhealthy(err.to_string());
+                    if check.reconnect {
+                        supervisor.restart_unhealthy(&tunnel, status);
+                        return;
+                    }
+                    healthy = Some(false);
+                    call_status_callback(status_callback.clone(), status);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,597 @@
+//! Asking the user to trust a host's key before connecting to it
+//!
+//! With the default [HostKeyPolicy::AcceptNew], ssh trusts the key of any host that it hasn't seen before, so the user never
+//! gets to check its fingerprint. [check_host_key] fills that gap: before the tunnel starts, it looks the end host up in the
+//! known hosts files that ssh reads, and if the host isn't there, it scans the host's key (with `ssh-keyscan`) and asks the
+//! caller to approve it. The key is only added to a file if it's approved, and since ssh then finds it there, a different
+//! key presented when the tunnel connects is reported as [SshStatus::HostKeyChanged].
+//!
+//! The end host and its known hosts files are looked up as ssh resolves them (with `ssh -G`), so a `HostName`, `Port`,
+//! `HostKeyAlias`, `UserKnownHostsFile` or `GlobalKnownHostsFile` from the user's ssh config is used the same way that ssh
+//! will use it.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+
+#[cfg(target_os = "windows")]
+use std::os::windows::process::CommandExt;
+
+use crate::config::{HostKeyPolicy, SshConfig};
+use crate::error::{Error, Result};
+use crate::status::SshStatus;
+
+/// The key types that are scanned, in the order that they're preferred
+const KEY_TYPES: [&str; 3] = ["ssh-ed25519", "ecdsa-sha2-nistp256", "ssh-rsa"];
+
+/// The time (in seconds) that a scan waits for the host, if the config has no connect timeout
+const SCAN_TIMEOUT: u32 = 5;
+
+/// A host key, as presented by the host
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HostKey {
+    /// The host, as it's written in the known hosts file (`[host]:port` for a non-default port)
+    host: String,
+
+    /// The type of the key, such as `ssh-ed25519`
+    key_type: String,
+
+    /// The SHA256 fingerprint of the key, as printed by ssh
+    fingerprint: String,
+
+    /// The line that trusts the key in a known hosts file
+    entry: String,
+}
+
+impl HostKey {
+    /// The host, as it's written in the known hosts file (`[host]:port` for a non-default port)
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The type of the key, such as `ssh-ed25519`
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    /// The SHA256 fingerprint of the key, as printed by ssh (`SHA256:...`)
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+/// Makes sure that the end host's key is trusted before the tunnel connects, asking the `approve` callback about a key that
+/// isn't known yet
+///
+/// Nothing is asked if the host is already in one of the known hosts files that ssh reads (see [known_hosts_files]), even
+/// if its key has changed since (which ssh will report), or if its keys are [pinned](HostKeyPolicy::Pinned). Hosts behind
+/// jump hosts (whether they're in the config or in the user's ssh config) can't be scanned, and keys can't be added if ssh
+/// has no user known hosts file, so those are left to the tunnel's [HostKeyPolicy]. Otherwise, the approved key is added
+/// to the first user known hosts file, which is the one that ssh adds keys to as well.
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] with [SshStatus::HostKeyRejected] if the callback doesn't approve the key.
+/// * Returns an [Error::Ssh] with the reason that the host's key can't be scanned (see [scan]).
+/// * Returns an [Error::Ssh] if ssh can't resolve the host from its config.
+/// * Returns an [Error::Spawn] if `ssh`, `ssh-keygen` or `ssh-keyscan` fails to spawn.
+/// * Returns an [Error::Io] if the known hosts file can't be written.
+pub fn check_host_key<F>(config: &SshConfig, mut approve: F) -> Result<()>
+where
+    F: FnMut(&HostKey) -> bool,
+{
+    if let HostKeyPolicy::Pinned(_) = config.host_key_policy() {
+        return Ok(());
+    }
+    let host = ResolvedHost::resolve(config)?;
+    if host.proxied {
+        log::debug!("Not scanning the key of a host behind jump hosts");
+        return Ok(());
+    }
+    if is_known_host(&host)? {
+        return Ok(());
+    }
+    let path = match host.user_known_hosts.first() {
+        Some(path) => path,
+        None => {
+            log::debug!("Not scanning the key of a host without a user known hosts file");
+            return Ok(());
+        }
+    };
+
+    let key = scan_host(&host, config)?;
+    log::info!(
+        "New host key for {}: {} {}",
+        key.host,
+        key.key_type,
+        key.fingerprint
+    );
+    if !approve(&key) {
+        log::warn!("Host key {} was rejected", key.fingerprint);
+        return Err(Error::Ssh(SshStatus::HostKeyRejected(key.fingerprint)));
+    }
+    trust(&key, path)
+}
+
+/// The known hosts files that ssh checks the end host's key against, as it resolves them from the tunnel's options and the
+/// user's ssh config: the user files (`UserKnownHostsFile`) first, followed by the global ones (`GlobalKnownHostsFile`)
+///
+/// There are none if the host's keys are [pinned](HostKeyPolicy::Pinned).
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] if ssh can't resolve the host from its config.
+/// * Returns an [Error::Spawn] if `ssh` fails to spawn.
+pub fn known_hosts_files(config: &SshConfig) -> Result<Vec<PathBuf>> {
+    if let HostKeyPolicy::Pinned(_) = config.host_key_policy() {
+        return Ok(Vec::new());
+    }
+    let host = ResolvedHost::resolve(config)?;
+    Ok(host.known_hosts_files().cloned().collect())
+}
+
+/// Checks whether any of the known hosts files that ssh reads has a key for the end host
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] if ssh can't resolve the host from its config.
+/// * Returns an [Error::Spawn] if `ssh` or `ssh-keygen` fails to spawn.
+pub fn is_known(config: &SshConfig) -> Result<bool> {
+    is_known_host(&ResolvedHost::resolve(config)?)
+}
+
+/// Scans the end host's key with `ssh-keyscan`
+///
+/// If the host has keys of several types, the one that ssh prefers is returned.
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] with the reason parsed from `ssh-keyscan`'s messages if the host doesn't present a key, or
+///   with [SshStatus::Unreachable] if they don't give one.
+/// * Returns an [Error::Ssh] if ssh can't resolve the host from its config.
+/// * Returns an [Error::Spawn] if `ssh`, `ssh-keyscan` or `ssh-keygen` fails to spawn.
+pub fn scan(config: &SshConfig) -> Result<HostKey> {
+    scan_host(&ResolvedHost::resolve(config)?, config)
+}
+
+/// The end host, as ssh resolves it from the tunnel's options and the user's ssh config
+#[derive(Debug, Clone, PartialEq)]
+struct ResolvedHost {
+    /// The address that ssh connects to (the `HostName`, if one is set)
+    host_name: String,
+
+    /// The port that ssh connects to
+    port: u32,
+
+    /// The name that ssh looks the host's key up by instead of its address (the `HostKeyAlias`)
+    alias: Option<String>,
+
+    /// Whether ssh reaches the host through a jump host or a proxy command
+    proxied: bool,
+
+    /// The known hosts files that ssh reads and adds new keys to (the `UserKnownHostsFile`)
+    user_known_hosts: Vec<PathBuf>,
+
+    /// The known hosts files that ssh only reads (the `GlobalKnownHostsFile`)
+    global_known_hosts: Vec<PathBuf>,
+}
+
+impl ResolvedHost {
+    /// Asks ssh how it would connect to the end host, with `ssh -G`
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Spawn] if `ssh` fails to spawn, or an [Error::Ssh] if it can't parse its config.
+    fn resolve(config: &SshConfig) -> Result<Self> {
+        let output = command("ssh")
+            .arg("-G")
+            .args(config.to_args())
+            .stdin(Stdio::null())
+            .output()
+            .map_err(Error::Spawn)?;
+        if !output.status.success() {
+            let msg = String::from_utf8_lossy(&output.stderr);
+            return Err(Error::Ssh(SshStatus::ConfigError(msg.trim().to_string())));
+        }
+
+        Ok(Self::parse(
+            &String::from_utf8_lossy(&output.stdout),
+            config,
+        ))
+    }
+
+    /// Parses the output of `ssh -G`, which has one lowercase option and its value per line
+    fn parse(output: &str, config: &SshConfig) -> Self {
+        let mut host = ResolvedHost {
+            host_name: config.end_host().to_string(),
+            port: config.port().unwrap_or(22),
+            alias: None,
+            proxied: !config.jump_hosts().is_empty(),
+            user_known_hosts: Vec::new(),
+            global_known_hosts: Vec::new(),
+        };
+        // ssh gives the known hosts files with `~` expanded, or as `none` if there aren't any
+        let paths = |value: &str| -> Vec<PathBuf> {
+            value
+                .split_whitespace()
+                .filter(|path| *path != "none")
+                .map(PathBuf::from)
+                .collect()
+        };
+        for (key, value) in output
+            .lines()
+            .filter_map(|line| line.trim().split_once(' '))
+        {
+            match key {
+                "hostname" => host.host_name = value.to_string(),
+                "port" => host.port = value.parse().unwrap_or(host.port),
+                "hostkeyalias" => host.alias = Some(value.to_string()),
+                "proxyjump" | "proxycommand" if value != "none" => host.proxied = true,
+                "userknownhostsfile" => host.user_known_hosts = paths(value),
+                "globalknownhostsfile" => host.global_known_hosts = paths(value),
+                _ => {}
+            }
+        }
+        host
+    }
+
+    /// The known hosts files that ssh reads, in the order that it reads them
+    fn known_hosts_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.user_known_hosts.iter().chain(&self.global_known_hosts)
+    }
+
+    /// The host, as it's written in a known hosts file
+    fn known_host(&self) -> String {
+        let name = self.alias.as_deref().unwrap_or(&self.host_name);
+        match self.port {
+            22 => name.to_string(),
+            port => format!("[{name}]:{port}"),
+        }
+    }
+}
+
+/// Checks whether any of the known hosts files that ssh reads has a key for the resolved end host
+///
+/// # Errors
+///
+/// Returns an [Error::Spawn] if `ssh-keygen` fails to spawn.
+fn is_known_host(host: &ResolvedHost) -> Result<bool> {
+    for path in host.known_hosts_files().filter(|path| path.exists()) {
+        let status = command("ssh-keygen")
+            .arg("-F")
+            .arg(host.known_host())
+            .arg("-f")
+            .arg(path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(Error::Spawn)?;
+        if status.success() {
+            log::debug!("{} is known in {}", host.known_host(), path.display());
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Scans the resolved end host's key with `ssh-keyscan`, naming it in the known hosts line as ssh will look it up
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] if the host doesn't present a key (see [scan]).
+/// * Returns an [Error::Spawn] if `ssh-keyscan` or `ssh-keygen` fails to spawn.
+fn scan_host(host: &ResolvedHost, config: &SshConfig) -> Result<HostKey> {
+    let output = command("ssh-keyscan")
+        .arg("-T")
+        .arg(config.connect_timeout().unwrap_or(SCAN_TIMEOUT).to_string())
+        .arg("-t")
+        .arg("ed25519,ecdsa,rsa")
+        .arg("-p")
+        .arg(host.port.to_string())
+        .arg(&host.host_name)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(Error::Spawn)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let entries = parse_scan(&stdout);
+    let (scanned_host, key_type, entry) = KEY_TYPES
+        .iter()
+        .find_map(|&preferred| {
+            entries
+                .iter()
+                .find(|(_, key_type, _)| *key_type == preferred)
+        })
+        .or_else(|| entries.first())
+        .ok_or_else(|| {
+            let msg = String::from_utf8_lossy(&output.stderr);
+            log::debug!(
+                "Failed to scan the key of {}: {}",
+                host.host_name,
+                msg.trim()
+            );
+            // ssh-keyscan is silent about most failures, and its other messages aren't all ones that ssh would print
+            match SshStatus::from_stderr(msg.trim()) {
+                SshStatus::Ready | SshStatus::Unknown(_) => Error::Ssh(SshStatus::Unreachable),
+                status => Error::Ssh(status),
+            }
+        })?;
+
+    // ssh-keyscan names the host by the address it scanned, which isn't what ssh looks up if the host has an alias
+    let known_host = host.known_host();
+    let entry = format!("{known_host} {}", entry[scanned_host.len()..].trim_start());
+    Ok(HostKey {
+        host: known_host,
+        key_type: key_type.to_string(),
+        fingerprint: fingerprint(&entry)?,
+        entry,
+    })
+}
+
+/// Adds the key to the known hosts file, creating the file if needed
+///
+/// # Errors
+///
+/// Returns an [Error::Io] if the file can't be written.
+pub fn trust(key: &HostKey, path: &Path) -> Result<()> {
+    let write = || -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", key.entry)
+    };
+
+    write().map_err(|err| Error::io(&format!("Failed to write {}", path.display()), err))?;
+    log::info!("Added host key {} to {}", key.fingerprint, path.display());
+    Ok(())
+}
+
+/// Builds a command that runs one of ssh's tools without a terminal window
+fn command(program: &str) -> process::Command {
+    #[allow(unused_mut)]
+    let mut cmd = process::Command::new(program);
+    #[cfg(target_os = "windows")]
+    cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
+    cmd
+}
+
+/// Splits the output of `ssh-keyscan` into the host, key type and known hosts line of each key
+fn parse_scan(output: &str) -> Vec<(&str, &str, &str)> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(host), Some(key_type), Some(_)) => Some((host, key_type, line)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Gets the SHA256 fingerprint of a known hosts line from `ssh-keygen`
+///
+/// # Errors
+///
+/// Returns an [Error::Spawn] if `ssh-keygen` fails to spawn, or an [Error::Io] if it can't read the key.
+fn fingerprint(entry: &str) -> Result<String> {
+    let mut child = command("ssh-keygen")
+        .args(["-l", "-E", "sha256", "-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::Spawn)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{entry}")
+            .map_err(|err| Error::io("Failed to write to ssh-keygen", err))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|err| Error::io("Failed to read from ssh-keygen", err))?;
+
+    // The output is "<bits> <fingerprint> <host> (<type>)"
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .filter(|fingerprint| fingerprint.starts_with("SHA256:"))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            let msg = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            Error::io("Failed to fingerprint the host key", io::Error::other(msg))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{
+        check_host_key, fingerprint, is_known, known_hosts_files, parse_scan, trust, HostKey,
+        ResolvedHost,
+    };
+    use crate::config::{HostKeyPolicy, SshConfig};
+    use crate::error::Error;
+    use crate::status::SshStatus;
+
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIGXu3g4zlujlB1C+7rHw8SDqsZPoTf40yjOuKbmAWQcN";
+
+    /// Whether ssh's tools are on the PATH, since these tests run them
+    fn installed(tools: &[&str]) -> bool {
+        let found = tools.iter().all(|tool| {
+            std::env::var_os("PATH").is_some_and(|paths| {
+                std::env::split_paths(&paths).any(|dir| dir.join(tool).is_file())
+            })
+        });
+        if !found {
+            eprintln!("Skipping the test, since {tools:?} aren't all installed");
+        }
+        found
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config = SshConfig::builder("db.example.com", "username", "keypath")
+            .local_forward(5432, 5432)
+            .port(2222)
+            .build()
+            .unwrap();
+        let output =
+            "user username\nhostname 10.0.0.5\nport 2222\nhostkeyalias db\nproxycommand none\n";
+        let host = ResolvedHost::parse(output, &config);
+        assert_eq!(host.host_name, "10.0.0.5");
+        assert_eq!(host.known_host(), "[db]:2222");
+        assert!(!host.proxied);
+
+        let host = ResolvedHost::parse(
+            "hostname db.example.com\nport 22\nproxyjump bastion\n\
+             globalknownhostsfile /etc/ssh/ssh_known_hosts /etc/ssh/ssh_known_hosts2\n\
+             userknownhostsfile /home/me/.ssh/known_hosts /home/me/.ssh/known_hosts2\n",
+            &config,
+        );
+        assert_eq!(host.known_host(), "db.example.com");
+        assert!(host.proxied);
+        assert_eq!(
+            host.known_hosts_files().collect::<Vec<_>>(),
+            [
+                "/home/me/.ssh/known_hosts",
+                "/home/me/.ssh/known_hosts2",
+                "/etc/ssh/ssh_known_hosts",
+                "/etc/ssh/ssh_known_hosts2"
+            ]
+            .map(PathBuf::from)
+            .iter()
+            .collect::<Vec<_>>()
+        );
+
+        let host = ResolvedHost::parse("userknownhostsfile none\n", &config);
+        assert_eq!(host.known_hosts_files().count(), 0);
+
+        if !installed(&["ssh"]) {
+            return;
+        }
+        let config = SshConfig::builder("db.example.com", "username", "keypath")
+            .local_forward(5432, 5432)
+            .option("HostKeyAlias", "db")
+            .build()
+            .unwrap();
+        let host = ResolvedHost::resolve(&config).unwrap();
+        assert_eq!(host.host_name, "db.example.com");
+        assert_eq!(host.known_host(), "db");
+    }
+
+    #[test]
+    fn test_parse_scan() {
+        if !installed(&["ssh-keygen"]) {
+            return;
+        }
+        let output = format!(
+            "# db.example.com:22 SSH-2.0-OpenSSH_9.6\n\
+             db.example.com ssh-rsa AAAAB3NzaC1yc2E\n\
+             db.example.com ssh-ed25519 {ED25519_KEY}\n"
+        );
+        let entries = parse_scan(&output);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].0, "db.example.com");
+        assert_eq!(entries[1].1, "ssh-ed25519");
+        assert_eq!(
+            entries[1].2,
+            format!("db.example.com ssh-ed25519 {ED25519_KEY}")
+        );
+        assert_eq!(
+            fingerprint(entries[1].2).unwrap(),
+            "SHA256:NLvZ7bFtlN+64HZnJWA+YFCF0kjznCrV4l2rcD+IJt4"
+        );
+    }
+
+    #[test]
+    fn test_known_hosts() {
+        if !installed(&["ssh", "ssh-keygen"]) {
+            return;
+        }
+        let path =
+            std::env::temp_dir().join(format!("ssh-tunnel-known-hosts-{}", std::process::id()));
+        let global_path = std::env::temp_dir().join(format!(
+            "ssh-tunnel-global-known-hosts-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&global_path);
+        let config = SshConfig::builder("db.example.com", "username", "keypath")
+            .local_forward(5432, 5432)
+            .port(2222)
+            .host_key_policy(HostKeyPolicy::KnownHostsFile(
+                path.to_string_lossy().to_string(),
+            ))
+            .option("GlobalKnownHostsFile", &global_path.to_string_lossy())
+            .build()
+            .unwrap();
+        assert_eq!(
+            known_hosts_files(&config).unwrap(),
+            [path.clone(), global_path.clone()]
+        );
+
+        let other_host = HostKey {
+            host: "db.example.com".to_string(),
+            key_type: "ssh-ed25519".to_string(),
+            fingerprint: String::new(),
+            entry: format!("db.example.com ssh-ed25519 {ED25519_KEY}"),
+        };
+        trust(&other_host, &path).unwrap();
+        assert!(!is_known(&config).unwrap());
+
+        // A host that's only known globally counts as known
+        let key = HostKey {
+            host: "[db.example.com]:2222".to_string(),
+            entry: format!("[db.example.com]:2222 ssh-ed25519 {ED25519_KEY}"),
+            ..other_host
+        };
+        trust(&key, &global_path).unwrap();
+        assert!(is_known(&config).unwrap());
+
+        // A known host is never scanned or asked about
+        check_host_key(&config, |_| panic!("The host is already known")).unwrap();
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&global_path);
+    }
+
+    #[test]
+    fn test_check_host_key() {
+        let config = SshConfig::builder("db.example.com", "username", "keypath")
+            .local_forward(5432, 5432)
+            .host_key_policy(HostKeyPolicy::Pinned(vec![
+                "SHA256:Jx8m3bDPfE5ZQbHx0rK2pT0A4x3Sx+9YwS4mIXmV0Lk".to_string(),
+            ]))
+            .build()
+            .unwrap();
+        check_host_key(&config, |_| panic!("Pinned keys are never asked about")).unwrap();
+
+        if !installed(&["ssh", "ssh-keyscan"]) {
+            return;
+        }
+
+        // Nothing listens on port 1, so there's no key to approve
+        let path =
+            std::env::temp_dir().join(format!("ssh-tunnel-unknown-host-{}", std::process::id()));
+        let config = SshConfig::builder("127.0.0.1", "username", "keypath")
+            .local_forward(5432, 5432)
+            .port(1)
+            .host_key_policy(HostKeyPolicy::KnownHostsFile(
+                path.to_string_lossy().to_string(),
+            ))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            check_host_key(&config, |_| true),
+            Err(Error::Ssh(SshStatus::Unreachable))
+        ));
+        assert!(!path.exists());
+    }
+}
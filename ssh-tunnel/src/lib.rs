@@ -17,6 +17,12 @@ doc = ::embed_doc_image::embed_image!("abnormal_sequence", "doc/abnormal-seq.png
 //! The status of the SSH process is communicated using the [SshStatus](crate::status::SshStatus) enum and is determined by
 //! parsing the text captured from the process's stderr stream.
 //!
+//! With the `tokio` feature, the `async_tunnel` module offers the same tunnels through an async API, which publishes the
+//! status on a channel rather than calling a callback.
+//!
+//! To run several tunnels side by side, a [TunnelManager](crate::manager::TunnelManager) keeps them by name, and makes sure
+//! that they don't claim the same local ports.
+//!
 //! # Successful Connection and Disconnection
 //!
 //! A normal life-cycle, in which the SSH tunnel successfully connects to the host, and cleanly disconnects when the user is
@@ -26,27 +32,45 @@ doc = ::embed_doc_image::embed_image!("abnormal_sequence", "doc/abnormal-seq.png
 //!
 //! # Connection Failures and Dropped Tunnels
 //!
-//! The following sequence shows how the library manages connection failures. It includes a suggested design for managing
-//! dropped connections, in which the connection is initially established, and then is dropped due to a network or server
-//! failure. This dropped-connection handling is done in the `status_callback` callback, which internally calls
-//! [start_and_watch_ssh_tunnel] to attempt to re-establish a connection. In this example, the application will make five
-//! attempts to reconnect before giving up and accepting the [SshStatus::Dropped](crate::status::SshStatus::Dropped) status.
+//! The following sequence shows how the library manages connection failures, and how dropped connections are handled, in
+//! which the connection is initially established, and then is dropped due to a network or server failure. This
+//! dropped-connection handling is done by a [Supervisor](crate::reconnect::Supervisor) (see [reconnect::supervise]), which
+//! calls [start_and_watch_ssh_tunnel] again to attempt to re-establish the connection, as directed by its
+//! [ReconnectPolicy](crate::reconnect::ReconnectPolicy). With the default policy, it will make five attempts to reconnect
+//! before giving up and accepting the [SshStatus::Dropped](crate::status::SshStatus::Dropped) status.
 //!
 //! ![Lifecycle With Errors Sequence][abnormal_sequence]
 
-use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::io::{self, BufRead, BufReader};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
 use std::{thread, time::Duration};
 
+#[cfg(feature = "tokio")]
+pub mod async_tunnel;
 pub mod config;
+pub mod control;
+pub mod error;
+pub mod health;
+pub mod host_keys;
 pub mod logger;
+pub mod manager;
+pub mod preflight;
+pub mod probe;
+#[cfg(feature = "serde")]
+pub mod profiles;
+pub mod reconnect;
+pub mod ssh_config;
 pub mod status;
 pub mod tunnel;
 
 use crate::{
-    config::SshConfig,
-    status::{ExitCondition, Result, SshStatus},
-    tunnel::{ChildProc, SshTunnel},
+    config::{DynamicForward, Forward, ForwardDirection, SshConfig},
+    error::{Error, Result},
+    status::{ExitCondition, SshStatus, StatusEvent},
+    tunnel::{ChildEvent, ChildProc, SshTunnel},
 };
 
 /// A handle for a watcher thread
@@ -58,7 +82,7 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 
 /// Starts a tunnel process and a watcher thread
 ///
-/// This is the only public entry function to the library. It will spawn a new ssh child process, as well as a thread to
+/// This is the principal entry function to the library. It will spawn a new ssh child process, as well as a thread to
 /// watch that process. It will always return a handle to the [tunnel](SshTunnel) object, as well as a handle to the watcher
 /// thread.
 ///
@@ -69,10 +93,36 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 /// The function can either wait for the ssh tunnel to complete (or fail), by setting the wait parameter to true, or it can
 /// spawn the process asynchronously and allow the system status to be updated through the status_callback.
 ///
+/// Before anything is spawned, the process's [preflight](ChildProc::preflight) checks are run (the [preflight] checks, for a
+/// [TunnelChild](tunnel::TunnelChild)), and the first one that fails is returned as an [Error::Preflight] error.
+///
+/// Any local or dynamic forwards with a port of [ANY_PORT](crate::config::ANY_PORT) are given free local ports before the
+/// process is spawned. The picked ports are reported to the status_callback with [SshStatus::PortsAssigned] (before any other
+/// status), and the tunnel's [config](ChildProc::config) holds the ports that were used.
+///
+/// The tunnel is connected once ssh has authenticated and set up its forwards, which it signals on its stdout (so no remote
+/// shell is needed, and `-N` works). If the config gives a [connect timeout](SshConfig::connect_timeout), a tunnel that
+/// hasn't connected a few seconds after that (which leaves ssh time to report its own timeout) is killed, and the status
+/// will be [SshStatus::TimedOut].
+///
+/// Once the process has connected, ssh should be listening on the local port of each local forward, and if the config has any
+/// [dynamic forwards](crate::config::DynamicForward), each SOCKS proxy is probed. The tunnel is only reported as
+/// [SshStatus::Connected] if they're all up. Otherwise, the tunnel is killed and the status will be
+/// [SshStatus::ForwardFailed].
+///
+/// While the tunnel is running, ssh's stderr is watched for problems that don't bring the tunnel down (such as a forwarded
+/// connection being refused by the service on the other end). Each one is reported to the status_callback as an
+/// [SshStatus::Degraded] warning, which doesn't change the state of the tunnel: it stays connected until a different
+/// status is reported.
+///
 /// # Errors
 ///
-/// If the tunnel process fails to spawn or if it fails to acquire a lock on the tunnel's mutex, it will return an
-/// [SshStatus::AppError].
+/// * If a preflight check fails, it will return an [Error::Preflight].
+/// * If the tunnel process fails to spawn, it will return an [Error::Spawn].
+/// * If a free port can't be found, or the process's output can't be read, it will return an [Error::Io].
+/// * If it fails to acquire a lock on the tunnel's mutex, it will return an [Error::Poisoned].
+/// * In wait mode, if ssh fails to connect (or a forward isn't listening), it will return an [Error::Ssh]
+///   with the resulting status.
 ///
 /// # Examples
 ///
@@ -81,8 +131,10 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 /// # use std::sync::{Arc, Mutex};
 /// # use ssh_tunnel::{
 /// #    config::SshConfig,
-/// #    status::{Result, SshStatus},
-/// #    tunnel::{ChildProc, SshHandle, SshTunnel, TunnelChild},
+/// #    error::Result,
+/// #    status::SshStatus,
+/// #    tunnel::{ChildProc, SshTunnel, TunnelChild},
+/// #    SshHandle,
 /// # };
 /// # fn spawn_proc() -> Result<()> {
 /// # let config = SshConfig::new(
@@ -113,15 +165,17 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 /// # }
 /// ```
 ///
-/// Start tunnel and update status asynchronously:
+/// Start tunnel and update status asynchronously (to reconnect the tunnel when it drops, use [reconnect::supervise]
+/// instead):
 /// ```no_run
 /// # use std::sync::{Arc, Mutex};
 /// # use ssh_tunnel::{
 /// #    config::SshConfig,
-/// #    status::{Result, SshStatus},
-/// #    tunnel::{ChildProc, SshHandle, SshTunnel, TunnelChild},
+/// #    error::Result,
+/// #    status::SshStatus,
+/// #    tunnel::{ChildProc, SshTunnel, TunnelChild},
+/// #    SshHandle,
 /// # };
-/// # fn spawn_reconnect(config: Arc<Mutex<SshConfig>>) { }
 /// # fn emit_status(status: SshStatus) {}
 /// # fn spawn_proc() -> Result<()> {
 /// # let config = SshConfig::new(
@@ -134,19 +188,7 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 /// #     10,
 /// #     &["-T"],
 /// # );
-/// let config_thread = Arc::new(Mutex::new(config.clone()));
-/// let callback = Arc::new(Mutex::new(move |status| {
-///     let status = match status {
-///         SshStatus::Dropped => {
-///             // Attempt to reconnect in separate thread
-///             println!("Dropped tunnel connection");
-///             spawn_reconnect(config_thread.clone());
-///             SshStatus::Reconnecting
-///         }
-///         _ => status,
-///     };
-///     emit_status(status)
-/// }));
+/// let callback = Arc::new(Mutex::new(|status| emit_status(status)));
 ///
 /// let tunnel: SshTunnel<TunnelChild>;
 /// let handle: SshHandle;
@@ -156,7 +198,7 @@ pub type SshHandle = thread::JoinHandle<(SshStatus, ExitCondition)>;
 /// # }
 /// ```
 pub fn start_and_watch_ssh_tunnel<T, F>(
-    config: SshConfig,
+    mut config: SshConfig,
     status_callback: Arc<Mutex<F>>,
     wait: bool,
 ) -> Result<(SshTunnel<T>, SshHandle)>
@@ -164,109 +206,338 @@ where
     T: ChildProc + Send + 'static,
     F: FnMut(SshStatus) + Send + 'static,
 {
+    T::preflight(&config)?;
+
+    let assigned_ports = config.assign_free_ports()?;
+    if !assigned_ports.is_empty() {
+        call_status_callback(
+            status_callback.clone(),
+            SshStatus::PortsAssigned(assigned_ports),
+        );
+    }
+
+    let start_failure = Arc::new(Mutex::new(None));
     let tunnel = if wait {
         start_wait_ssh_tunnel(config)
     } else {
-        start_ssh_tunnel(config, status_callback.clone())
+        start_ssh_tunnel(config, status_callback.clone(), start_failure.clone())
     }?;
     let watched_tunnel = tunnel.clone();
     log::debug!("Spawning watcher thread");
-    let handle = std::thread::spawn(move || ssh_watch_loop(watched_tunnel, status_callback));
+    let handle =
+        std::thread::spawn(move || ssh_watch_loop(watched_tunnel, status_callback, start_failure));
 
     Ok((tunnel, handle))
 }
 
+/// The id of the next tunnel started with [start_and_watch_ssh_tunnel_with_sender]
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Starts a tunnel process and a watcher thread, and returns a channel that delivers the tunnel's statuses
+///
+/// This is the same as [start_and_watch_ssh_tunnel], but rather than calling a callback, each status is sent on the
+/// returned channel as a [StatusEvent]. To watch several tunnels on a single channel, use
+/// [start_and_watch_ssh_tunnel_with_sender].
+///
+/// # Errors
+///
+/// Returns the same errors as [start_and_watch_ssh_tunnel].
+pub fn start_and_watch_ssh_tunnel_with_events<T>(
+    config: SshConfig,
+    wait: bool,
+) -> Result<(SshTunnel<T>, SshHandle, mpsc::Receiver<StatusEvent>)>
+where
+    T: ChildProc + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let (_, tunnel, handle) = start_and_watch_ssh_tunnel_with_sender(config, sender, wait)?;
+    Ok((tunnel, handle, receiver))
+}
+
+/// Starts a tunnel process and a watcher thread, and sends the tunnel's statuses on the given channel
+///
+/// This is the same as [start_and_watch_ssh_tunnel], but rather than calling a callback, each status is sent on the channel
+/// as a [StatusEvent]. The sender can be shared by several tunnels, and the returned tunnel id tells their events apart.
+/// Once the receiver is dropped, the statuses are discarded.
+///
+/// # Errors
+///
+/// Returns the same errors as [start_and_watch_ssh_tunnel].
+pub fn start_and_watch_ssh_tunnel_with_sender<T>(
+    config: SshConfig,
+    sender: mpsc::Sender<StatusEvent>,
+    wait: bool,
+) -> Result<(u64, SshTunnel<T>, SshHandle)>
+where
+    T: ChildProc + Send + 'static,
+{
+    let tunnel_id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+    let callback = Arc::new(Mutex::new(move |status| {
+        // The receiver may have been dropped, in which case nobody is listening
+        let _ = sender.send(StatusEvent::new(tunnel_id, status));
+    }));
+
+    let (tunnel, handle) = start_and_watch_ssh_tunnel(config, callback, wait)?;
+    Ok((tunnel_id, tunnel, handle))
+}
+
 /// Starts a tunnel process and waits for the tunnel to connect (or fail), and returns a handle to the process
 ///
 /// # Errors
 ///
-/// If the tunnel process fails to spawn or if it fails to acquire a lock on the tunnel's mutex, it will return an
-/// [SshStatus::AppError].
+/// * If the tunnel process fails to spawn, it will return an [Error::Spawn].
+/// * If the process's output can't be read, it will return an [Error::Io].
+/// * If it fails to acquire a lock on the tunnel's mutex, it will return an [Error::Poisoned].
+/// * If ssh fails to connect (or times out), or a forward isn't listening, it will return an [Error::Ssh].
 fn start_wait_ssh_tunnel<T>(config: SshConfig) -> Result<SshTunnel<T>>
 where
     T: ChildProc + Send + 'static,
 {
+    let forwards = config.forwards().to_vec();
+    let dynamic_forwards = config.dynamic_forwards().to_vec();
+    let timeout = start_timeout(&config);
     let tunnel = T::new(config)?;
-    match wait_for_start(tunnel.clone()) {
-        Ok(_) => Ok(tunnel),
-        Err(_) => Err(tunnel
-            .lock()
-            .map_err(|err| SshStatus::AppError(format!("Failed to lock tunnel: {err}")))?
-            .exit_status()),
+    match wait_for_connection(tunnel.clone(), timeout, &forwards, &dynamic_forwards) {
+        Ok(true) => {
+            lock_tunnel(&tunnel)?.set_connected();
+            Ok(tunnel)
+        }
+        Ok(false) => Err(Error::Ssh(lock_tunnel(&tunnel)?.exit_status())),
+        Err(err) => {
+            lock_tunnel(&tunnel)?.kill();
+            Err(err)
+        }
     }
 }
 
 /// Starts a tunnel process and returns a handle to the process immediately.
 ///
 /// The status of the ssh connection will be returned through the status_callback once the tunnel has connected (or failed to
-/// connect).
+/// connect). If the tunnel times out, or a forward isn't listening, the tunnel is killed and the failure is left in `start_failure` for
+/// the watcher thread to report in place of the exit status.
 ///
 /// # Errors
 ///
-/// If the tunnel process fails to spawn or if it fails to acquire a lock on the tunnel's mutex, it will return an
-/// [SshStatus::AppError].
-fn start_ssh_tunnel<T, F>(config: SshConfig, status_callback: Arc<Mutex<F>>) -> Result<SshTunnel<T>>
+/// If the tunnel process fails to spawn, it will return an [Error::Spawn].
+fn start_ssh_tunnel<T, F>(
+    config: SshConfig,
+    status_callback: Arc<Mutex<F>>,
+    start_failure: Arc<Mutex<Option<SshStatus>>>,
+) -> Result<SshTunnel<T>>
 where
     T: ChildProc + Send + 'static,
     F: FnMut(SshStatus) + Send + 'static,
 {
+    let forwards = config.forwards().to_vec();
+    let dynamic_forwards = config.dynamic_forwards().to_vec();
+    let timeout = start_timeout(&config);
     let tunnel = T::new(config)?;
     let tunnel_sts = tunnel.clone();
 
     log::debug!("Spawning start watcher");
     thread::spawn(move || {
-        if let Err(status) = wait_for_start(tunnel_sts) {
-            log::debug!("Start status: {status}");
-            match status {
-                SshStatus::Ready => {}
-                _ => call_status_callback(status_callback, status),
+        match wait_for_connection(tunnel_sts.clone(), timeout, &forwards, &dynamic_forwards) {
+            Ok(true) => {
+                match lock_tunnel(&tunnel_sts) {
+                    Ok(mut tunnel) => tunnel.set_connected(),
+                    Err(err) => log::error!("Failed to mark the tunnel connected: {err}"),
+                }
+                call_status_callback(status_callback, SshStatus::Connected)
+            }
+            // The process exited, so the watcher thread will report its exit status
+            Ok(false) => {}
+            Err(Error::Ssh(status)) => {
+                log::debug!("Start failure: {status}");
+                fail_start(&tunnel_sts, &start_failure, status);
+            }
+            Err(err) => {
+                log::debug!("Start error: {err}");
+                call_status_callback(status_callback, err.to_status())
             }
-        } else {
-            call_status_callback(status_callback, SshStatus::Connected)
         }
     });
 
     Ok(tunnel)
 }
 
+/// Kills a tunnel that failed to start, leaving the failure for the watcher thread to report in place of the exit status
+///
+/// The failure is left before the process is killed, so the watcher can't miss it.
+fn fail_start<T: ChildProc>(
+    tunnel: &SshTunnel<T>,
+    start_failure: &Mutex<Option<SshStatus>>,
+    status: SshStatus,
+) {
+    // Only one lock is held at a time, so this can't deadlock with the watch loop
+    match start_failure.lock() {
+        Ok(mut failure) => *failure = Some(status),
+        Err(_) => log::error!("Failed to record the start failure"),
+    }
+    match tunnel.lock() {
+        Ok(mut tunnel) => tunnel.kill(),
+        Err(_) => log::error!("Failed to lock tunnel after it failed to start"),
+    }
+}
+
+/// Waits for the ssh process to connect, and then confirms that its forwards are up
+///
+/// # Returns
+///
+/// Returns true if the tunnel connected, or false if the process exited first. In that case, the reason can be read with
+/// the [ChildProc::exit_status()] method.
+///
+/// # Errors
+///
+/// * Returns an [Error::Ssh] with [SshStatus::TimedOut] if the process didn't connect within the timeout.
+/// * Returns an [Error::Ssh] with [SshStatus::ForwardFailed] if a forward isn't listening.
+/// * Returns the other errors of [wait_for_start].
+///
+/// In any case, the process is left running for the caller to kill.
+fn wait_for_connection<T>(
+    tunnel: SshTunnel<T>,
+    timeout: Option<Duration>,
+    forwards: &[Forward],
+    dynamic_forwards: &[DynamicForward],
+) -> Result<bool>
+where
+    T: ChildProc + Send + 'static,
+{
+    if !wait_for_start(tunnel, timeout)? {
+        return Ok(false);
+    }
+    check_local_listeners(forwards)?;
+    probe_dynamic_forwards(dynamic_forwards)?;
+    Ok(true)
+}
+
+/// How much longer than the config's connect timeout the tunnel is given to connect
+///
+/// ssh enforces the connect timeout itself while it connects, and says why it gave up (a host that drops the connection's
+/// packets is [unreachable](SshStatus::Unreachable), for instance). The library's deadline is only for a connection that
+/// stalls after that, so it mustn't beat ssh to it.
+const START_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// The time that the tunnel is given to connect, if the config limits it
+fn start_timeout(config: &SshConfig) -> Option<Duration> {
+    config
+        .connect_timeout()
+        .map(|timeout| Duration::from_secs(timeout.into()) + START_TIMEOUT_GRACE)
+}
+
 /// Waits for the ssh process to start
 ///
+/// ssh prints a marker on its stdout once it has connected (see [SshConfig::to_args]), so this waits for that line. The
+/// rest of the stdout stream (the output of the remote shell, if there is one) is read and logged until it closes, so that
+/// ssh never blocks on writing to it.
+///
 /// This is a utility function to allow the waiting to happen without holding a lock on the tunnel.
 ///
+/// # Returns
+///
+/// Returns true if the process started, or false if it exited first. In that case, the reason can be read with the
+/// [ChildProc::exit_status()] method.
+///
 /// # Errors
 ///
-/// * Returns [SshStatus::Ready] if the ssh process spawned, but there was an error. The details of this error
-///   can be read with the [ChildProc::exit_status()] method.
-/// * Returns [SshStatus::AppError] if there was an error in the process itself.
-fn wait_for_start<T>(tunnel: SshTunnel<T>) -> Result<()>
+/// * Returns [Error::Poisoned] if the tunnel can't be locked.
+/// * Returns [Error::Io] if the process's stdout can't be taken.
+/// * Returns an [Error::Ssh] with [SshStatus::TimedOut] if the process didn't start within the timeout. It's left running.
+fn wait_for_start<T>(tunnel: SshTunnel<T>, timeout: Option<Duration>) -> Result<bool>
 where
     T: ChildProc + Send + 'static,
 {
-    let mut stdout = {
-        tunnel
-            .lock()
-            .map_err(|err| SshStatus::AppError(format!("Failed to lock tunnel: {err}")))?
-            .stdout()?
-    };
+    let stdout = lock_tunnel(&tunnel)?.stdout()?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).split(b'\n') {
+            let line = match line {
+                Ok(line) => String::from_utf8_lossy(&line).trim_end().to_string(),
+                Err(err) => {
+                    log::debug!("Failed to read from stdout: {err}");
+                    break;
+                }
+            };
+            if line == config::READY_MARKER {
+                // The start watcher may have timed out already, in which case nobody is listening
+                let _ = sender.send(());
+            } else {
+                log::debug!("stdout: {line}");
+            }
+        }
+    });
 
-    let mut buffer = [0; 15];
-    let len = stdout
-        .read(&mut buffer)
-        .map_err(|err| SshStatus::AppError(format!("Failed to read from stdout: {err}")))?;
+    match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(_) => Ok(true),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                log::warn!("The tunnel didn't connect within {timeout:?}");
+                Err(Error::Ssh(SshStatus::TimedOut))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(false),
+        },
+        None => Ok(receiver.recv().is_ok()),
+    }
+}
 
-    log::debug!("stdout: {}", String::from_utf8_lossy(&buffer));
+/// Confirms that ssh is listening on the local port of each local forward
+///
+/// ssh only runs its LocalCommand (which prints the ready marker) once the forwards are set up, and ExitOnForwardFailure
+/// makes it exit if one of them can't listen, so this is a last check that nothing went wrong in between. Nothing is
+/// connected to the ports, since that would open a connection to the service on the other end. Instead, each port is bound
+/// on the forward's address, which fails if ssh is listening on it.
+///
+/// # Errors
+///
+/// Returns an [Error::Ssh] with [SshStatus::ForwardFailed] and the port of the first forward that isn't listening.
+fn check_local_listeners(forwards: &[Forward]) -> Result<()> {
+    let local_forwards = forwards
+        .iter()
+        .filter(|forward| forward.direction() == ForwardDirection::Local);
+    for forward in local_forwards {
+        let port = forward.local_port();
+        let address = forward.listen_address();
+        let bound = u16::try_from(port).map(|local_port| TcpListener::bind((address, local_port)));
+        match bound {
+            Ok(Err(err)) if err.kind() == io::ErrorKind::AddrInUse => {}
+            _ => {
+                log::debug!("Nothing is listening on local port {port} of {address}");
+                return Err(Error::Ssh(SshStatus::ForwardFailed(port)));
+            }
+        }
+    }
+    Ok(())
+}
 
-    if len >= 15 {
-        Ok(())
-    } else {
-        Err(SshStatus::Ready)
+/// Probes the SOCKS proxy of each dynamic forward
+///
+/// Each proxy is given a few chances to answer, in case ssh is still setting up its listeners.
+///
+/// # Errors
+///
+/// Returns an [Error::Ssh] with [SshStatus::ForwardFailed] and the port of the first proxy that fails to answer.
+fn probe_dynamic_forwards(forwards: &[DynamicForward]) -> Result<()> {
+    for forward in forwards {
+        let mut attempts = 0;
+        while let Err(err) = probe::socks5_handshake(forward, Duration::from_secs(1)) {
+            attempts += 1;
+            log::debug!("SOCKS5 probe of port {} failed: {err}", forward.port());
+            if attempts >= 3 {
+                return Err(Error::Ssh(SshStatus::ForwardFailed(forward.port())));
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
     }
+    Ok(())
 }
 
 /// Watches a tunnel process and calls the given callback when it exits.
 ///
-/// This function is meant to run in a thread and will not return until the tunnel process ends. When that happens, it will
-/// capture the [exit status](SshStatus) from the child process's stderr and call the exit_callback with that status.
+/// Any [warnings](ChildEvent::Warning) that the process reports while it's running are passed to the callback as well.
+///
+/// This function is meant to run in a thread and will not return until the tunnel process ends. It blocks on the process's
+/// [events](ChildProc::events), so the tunnel is only locked to take them and, once the process exits, to capture the
+/// [exit status](SshStatus) from the child process's stderr. The exit_callback is then called with that status. If the start
+/// watcher left a failure in `start_failure` (because it killed the process), that failure is reported instead.
 ///
 /// # Returns
 ///
@@ -274,37 +545,121 @@ where
 fn ssh_watch_loop<T, F>(
     tunnel: SshTunnel<T>,
     exit_callback: Arc<Mutex<F>>,
+    start_failure: Arc<Mutex<Option<SshStatus>>>,
 ) -> (SshStatus, ExitCondition)
 where
     T: ChildProc,
     F: FnMut(SshStatus) + Send,
 {
-    loop {
-        match tunnel.lock() {
-            Ok(mut tunnel) => {
-                if let Some(exit_cond) = tunnel.exited() {
-                    let ssh_status = tunnel.exit_status();
-                    call_status_callback(exit_callback, ssh_status.clone());
-                    return (ssh_status, exit_cond);
-                }
-            }
-            Err(err) => {
-                let ssh_status = SshStatus::AppError(format!("Failed to lock tunnel: {err}"));
-                call_status_callback(exit_callback, ssh_status.clone());
-                return (ssh_status, ExitCondition::ProcError);
+    let events = match lock_tunnel(&tunnel).and_then(|mut tunnel| tunnel.events()) {
+        Ok(events) => events,
+        Err(err) => {
+            let ssh_status = err.to_status();
+            call_status_callback(exit_callback, ssh_status.clone());
+            return (ssh_status, ExitCondition::ProcError);
+        }
+    };
+
+    let exit_cond = loop {
+        match events.recv() {
+            Ok(ChildEvent::Warning(warning)) => {
+                call_status_callback(exit_callback.clone(), warning)
             }
+            Ok(ChildEvent::Exited(exit_cond)) => break exit_cond,
+            // The process's threads are gone without reporting its exit, which only happens if they panicked
+            Err(_) => break ExitCondition::ProcError,
+        }
+    };
+
+    let ssh_status = match start_failure.lock().ok().and_then(|mut f| f.take()) {
+        Some(status) => status,
+        None => match lock_tunnel(&tunnel) {
+            Ok(mut tunnel) => tunnel.exit_status(),
+            Err(err) => err.to_status(),
+        },
+    };
+    // The process's stderr can still deliver warnings after it has exited, up to when its exit status is read
+    for event in events.try_iter() {
+        if let ChildEvent::Warning(warning) = event {
+            call_status_callback(exit_callback.clone(), warning);
         }
-        thread::sleep(Duration::from_millis(100));
     }
+    call_status_callback(exit_callback, ssh_status.clone());
+    (ssh_status, exit_cond)
+}
+
+/// Locks the tunnel, reporting a poisoned lock as an [Error::Poisoned]
+pub(crate) fn lock_tunnel<T>(tunnel: &SshTunnel<T>) -> Result<MutexGuard<'_, T>> {
+    tunnel
+        .lock()
+        .map_err(|_| Error::Poisoned("tunnel".to_string()))
 }
 
 /// Helper function to call the status callback
-fn call_status_callback<F>(status_callback: Arc<Mutex<F>>, status: SshStatus)
+///
+/// The call is isolated from panics in the callback: a panic is caught and logged, and the callback is called as usual for
+/// the next status. This also means that the callback's lock is never poisoned by the library, so a poisoned lock (from a
+/// panic while the caller was holding it) is used as is.
+pub(crate) fn call_status_callback<F>(status_callback: Arc<Mutex<F>>, status: SshStatus)
 where
     F: FnMut(SshStatus) + Send,
 {
-    match status_callback.lock() {
-        Ok(mut cb) => cb(status),
-        Err(err) => log::error!("Failed to get exit callback handle: {err}"),
+    let mut callback = status_callback
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if panic::catch_unwind(AssertUnwindSafe(|| callback(status))).is_err() {
+        log::error!("The status callback panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::call_status_callback;
+    #[cfg(unix)]
+    use super::start_and_watch_ssh_tunnel;
+    #[cfg(unix)]
+    use crate::config::{SshConfig, ANY_PORT};
+    #[cfg(unix)]
+    use crate::error::Error;
+    use crate::status::SshStatus;
+    #[cfg(unix)]
+    use crate::tunnel::mock::{self, MockChild};
+
+    #[test]
+    fn test_callback_panic() {
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let received = statuses.clone();
+        let callback = Arc::new(Mutex::new(move |status| {
+            if status == SshStatus::Connecting {
+                panic!("Bad subscriber");
+            }
+            received.lock().unwrap().push(status);
+        }));
+
+        call_status_callback(callback.clone(), SshStatus::Connecting);
+        assert!(!callback.is_poisoned());
+        call_status_callback(callback, SshStatus::Connected);
+        assert_eq!(*statuses.lock().unwrap(), vec![SshStatus::Connected]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_black_holed_host() {
+        // ssh gives up on a host that drops its packets once the connect timeout is up, which the library has to wait for
+        mock::set_script(
+            "blackhole.test",
+            "sleep 1; echo 'ssh: connect to host 203.0.113.10 port 22: Connection timed out' >&2; exit 255",
+        );
+        let config = SshConfig::builder("blackhole.test", "username", "keypath")
+            .local_forward(ANY_PORT, 5432)
+            .connect_timeout(1)
+            .build()
+            .unwrap();
+
+        let result =
+            start_and_watch_ssh_tunnel::<MockChild, _>(config, Arc::new(Mutex::new(|_| {})), true);
+        assert!(matches!(result, Err(Error::Ssh(SshStatus::Unreachable))));
     }
 }
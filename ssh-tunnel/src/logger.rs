@@ -32,12 +32,9 @@ pub fn configure_logger(path: &str) -> io::Result<()> {
     cycle_logs(path)?;
 
     // Logging to log file.
-    let logfile = FileAppender::builder().build(path).map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to create log file: {err}"),
-        )
-    })?;
+    let logfile = FileAppender::builder()
+        .build(path)
+        .map_err(|err| io::Error::other(format!("Failed to create log file: {err}")))?;
 
     // Log Trace level output to file where trace is the default level
     // and the programmatically specified level to stderr.
@@ -54,19 +51,10 @@ pub fn configure_logger(path: &str) -> io::Result<()> {
                 .appender("stderr")
                 .build(LevelFilter::Trace),
         )
-        .map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to build log config: {err}"),
-            )
-        })?;
+        .map_err(|err| io::Error::other(format!("Failed to build log config: {err}")))?;
 
-    let _handle = log4rs::init_config(config).map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to create log handler: {err}"),
-        )
-    })?;
+    let _handle = log4rs::init_config(config)
+        .map_err(|err| io::Error::other(format!("Failed to create log handler: {err}")))?;
 
     Ok(())
 }
@@ -93,8 +81,8 @@ fn cycle_logs(path: &str) -> io::Result<()> {
         .to_string_lossy();
 
     for ver in (0..5).rev() {
-        let old = dir.join(&format!("{stem}.{ver}.{ext}"));
-        let new = dir.join(&format!("{stem}.{}.{ext}", ver + 1));
+        let old = dir.join(format!("{stem}.{ver}.{ext}"));
+        let new = dir.join(format!("{stem}.{}.{ext}", ver + 1));
 
         if fs::metadata(&old).is_ok() {
             fs::rename(old, new)?;
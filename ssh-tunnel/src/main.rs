@@ -1,15 +1,25 @@
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use clap::Parser;
 use ssh_tunnel::{
-    config::SshConfig,
+    config::{DynamicForward, Forward, ForwardDirection, HostKeyPolicy, JumpHost, SshConfig},
+    error::Error,
+    health::HealthCheck,
+    host_keys::{self, HostKey},
     logger,
+    probe::ProbeKind,
+    reconnect::{self, ReconnectPolicy, Supervisor},
     status::{ExitCondition, SshStatus},
     tunnel::{ChildProc, SshTunnel, TunnelChild},
     SshHandle,
 };
 
+#[cfg(feature = "serde")]
+use ssh_tunnel::profiles::Profiles;
+
 fn main() -> Result<(), i32> {
     let args = Args::parse();
 
@@ -29,7 +39,14 @@ fn main() -> Result<(), i32> {
 
     log::debug!("Running SSH Tunnel CLI");
 
-    let config = args.to_config();
+    let config = args.to_config().map_err(|err| {
+        log::error!("Bad configuration: {err}");
+        ExitCondition::ProcError as i32
+    })?;
+
+    if args.ask_host_key {
+        host_keys::check_host_key(&config, ask_host_key).map_err(start_failed)?;
+    }
 
     let exit_callback = Arc::new(Mutex::new(|status| {
         log::info!("Status: {status}");
@@ -37,10 +54,30 @@ fn main() -> Result<(), i32> {
             SshStatus::Dropped => log::info!("Dropped connection"),
             SshStatus::Unreachable => log::warn!("Unreachable"),
             SshStatus::Ready => log::info!("Disconnected cleanly"),
+            SshStatus::PortsAssigned(ports) => {
+                log::info!("Listening on free local ports: {:?}", ports)
+            }
+            SshStatus::Degraded(warning) => log::warn!("Tunnel degraded: {warning}"),
+            SshStatus::Unhealthy(reason) => log::warn!("Tunnel unhealthy: {reason}"),
+            SshStatus::Healthy(report) => log::debug!("Health check passed: {:?}", report),
+            SshStatus::Connected => log::info!("Connected"),
+            SshStatus::Reconnecting(attempt, delay) => {
+                log::warn!("Reconnecting in {:?} (attempt {attempt})", delay)
+            }
             _ => log::error!("Unsupported status: {status}"),
         }
     }));
 
+    if args.reconnect > 0 || args.health_check.is_some() {
+        let health_check = args.health_check.map(|interval| HealthCheck {
+            interval: Duration::from_secs(interval.into()),
+            reconnect: args.reconnect > 0,
+            probe: Arc::new(args.probe.clone()),
+            ..Default::default()
+        });
+        return run_supervised(config, args.reconnect, health_check, exit_callback);
+    }
+
     let tunnel: SshTunnel<TunnelChild>;
     let handle: SshHandle;
     match ssh_tunnel::start_and_watch_ssh_tunnel(config, exit_callback, true) {
@@ -48,10 +85,7 @@ fn main() -> Result<(), i32> {
             tunnel = tnl;
             handle = hndl;
         }
-        Err(status) => {
-            log::error!("Failed to create tunnel: {status}");
-            return Err(ExitCondition::SshError as i32);
-        }
+        Err(err) => return Err(start_failed(err)),
     }
 
     ctrlc::set_handler(move || {
@@ -72,23 +106,115 @@ fn main() -> Result<(), i32> {
     }
 }
 
+/// Runs the tunnel under a supervisor, which reconnects it (up to the given number of attempts) when it drops, and checks
+/// its health if it's given a health check
+fn run_supervised<F>(
+    config: SshConfig,
+    attempts: u32,
+    health_check: Option<HealthCheck>,
+    callback: Arc<Mutex<F>>,
+) -> Result<(), i32>
+where
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    let policy = ReconnectPolicy {
+        max_attempts: Some(attempts),
+        ..Default::default()
+    };
+
+    let supervisor: Supervisor<TunnelChild>;
+    let handle: SshHandle;
+    let started = match health_check {
+        Some(check) => reconnect::supervise_with_health_check(config, policy, check, callback),
+        None => reconnect::supervise(config, policy, callback),
+    };
+    match started {
+        Ok((spvsr, hndl)) => {
+            supervisor = spvsr;
+            handle = hndl;
+        }
+        Err(err) => return Err(start_failed(err)),
+    }
+
+    ctrlc::set_handler(move || {
+        log::info!("Closing tunnel");
+        supervisor.stop();
+    })
+    .map_err(|err| {
+        log::error!("Failed to set handler: {:?}", err);
+        100
+    })?;
+
+    log::info!("SSH tunnel started");
+    let (ssh_status, exit_status) = handle.join().unwrap();
+    match ssh_status {
+        SshStatus::Ready => Ok(()),
+        _ => Err(exit_status as i32),
+    }
+}
+
+/// Asks on the terminal whether to trust a host key that isn't known yet
+fn ask_host_key(key: &HostKey) -> bool {
+    println!(
+        "The authenticity of host '{}' can't be established.",
+        key.host()
+    );
+    println!(
+        "{} key fingerprint is {}.",
+        key.key_type(),
+        key.fingerprint()
+    );
+    print!("Do you trust this key (yes/no)? ");
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("yes")
+}
+
+/// Logs a failure to start the tunnel, and returns the exit code for it
+fn start_failed(err: Error) -> i32 {
+    log::error!("Failed to create tunnel: {err}");
+    match err {
+        Error::Ssh(_) => ExitCondition::SshError as i32,
+        _ => ExitCondition::ProcError as i32,
+    }
+}
+
 #[derive(Parser)]
 #[clap(version = "ssh-tunnel 0.1.0", long_about = None)]
 #[clap(about = "Create ssh tunnel")]
 struct Args {
     /// Endhost name
-    end_host: String,
+    #[cfg_attr(feature = "serde", clap(required_unless_present = "profile"))]
+    #[cfg_attr(not(feature = "serde"), clap(required = true))]
+    end_host: Option<String>,
 
     /// Username
-    username: String,
+    #[cfg_attr(feature = "serde", clap(required_unless_present = "profile"))]
+    #[cfg_attr(not(feature = "serde"), clap(required = true))]
+    username: Option<String>,
 
     /// Path to the key file to use
-    key_path: String,
+    #[cfg_attr(feature = "serde", clap(required_unless_present = "profile"))]
+    #[cfg_attr(not(feature = "serde"), clap(required = true))]
+    key_path: Option<String>,
 
     /// Tohost name
-    to_host: String,
+    #[cfg_attr(feature = "serde", clap(required_unless_present = "profile"))]
+    #[cfg_attr(not(feature = "serde"), clap(required = true))]
+    to_host: Option<String>,
+
+    /// Start the tunnel from a saved profile, instead of the tunnel arguments
+    #[cfg(feature = "serde")]
+    #[clap(long, conflicts_with = "save-profile")]
+    profile: Option<String>,
 
-    /// Local port number
+    /// Save the tunnel arguments as a profile before starting the tunnel
+    #[cfg(feature = "serde")]
+    #[clap(long)]
+    save_profile: Option<String>,
+
+    /// Local port number (0 picks any free port)
     #[clap(short, long, default_value = "5432")]
     local_port: u32,
 
@@ -99,19 +225,144 @@ struct Args {
     /// Keepalive time (in seconds)
     #[clap(short, long, default_value = "10")]
     keepalive: u32,
+
+    /// Additional forward, given as local_port:to_host:remote_port (may be repeated)
+    #[clap(short, long = "forward", parse(try_from_str = parse_forward))]
+    forwards: Vec<Forward>,
+
+    /// Remote forward, given as remote_port:to_host:local_port (may be repeated)
+    #[clap(short = 'R', long = "remote-forward", parse(try_from_str = parse_remote_forward))]
+    remote_forwards: Vec<Forward>,
+
+    /// Dynamic (SOCKS proxy) forward, given as [bind_address:]port (may be repeated)
+    #[clap(short = 'D', long = "dynamic", parse(try_from_str = parse_dynamic_forward))]
+    dynamic_forwards: Vec<DynamicForward>,
+
+    /// Jump host, given as [user@]host[:port] (may be repeated, in hop order)
+    #[clap(short = 'J', long = "jump", parse(try_from_str = parse_jump_host))]
+    jump_hosts: Vec<JumpHost>,
+
+    /// SSH port of the end host
+    #[clap(short, long)]
+    port: Option<u32>,
+
+    /// Connect timeout (in seconds)
+    #[clap(long)]
+    connect_timeout: Option<u32>,
+
+    /// How the end host's key is verified: strict, accept-new, pin:<fingerprint>[,<fingerprint>...] or known-hosts:<path>
+    #[clap(long, default_value = "accept-new", parse(try_from_str = parse_host_key_policy))]
+    host_keys: HostKeyPolicy,
+
+    /// Show the fingerprint of an unknown end host's key, and ask whether to trust it before connecting
+    #[clap(long)]
+    ask_host_key: bool,
+
+    /// Extra ssh option, given as key=value (may be repeated)
+    #[clap(short, long = "option", parse(try_from_str = parse_option))]
+    options: Vec<(String, String)>,
+
+    /// Number of attempts to reconnect the tunnel after it drops (0 never reconnects)
+    #[clap(long, default_value = "0")]
+    reconnect: u32,
+
+    /// Check that the forwarded service answers every given number of seconds (restarting the tunnel when it doesn't, if
+    /// reconnecting)
+    #[clap(long)]
+    health_check: Option<u32>,
+
+    /// The health check's probe: tcp, postgres, redis or http[:path[:status]]
+    #[clap(long, default_value = "tcp", parse(try_from_str = parse_probe))]
+    probe: ProbeKind,
+}
+
+/// Parses a local forward specification from the command line
+fn parse_forward(spec: &str) -> Result<Forward, String> {
+    Forward::parse(spec, ForwardDirection::Local).map_err(|err| err.to_string())
+}
+
+/// Parses a dynamic forward specification from the command line
+fn parse_dynamic_forward(spec: &str) -> Result<DynamicForward, String> {
+    spec.parse().map_err(|err: Error| err.to_string())
+}
+
+/// Parses a health check probe from the command line
+fn parse_probe(spec: &str) -> Result<ProbeKind, String> {
+    spec.parse().map_err(|err: Error| err.to_string())
+}
+
+/// Parses a host key policy from the command line
+fn parse_host_key_policy(spec: &str) -> Result<HostKeyPolicy, String> {
+    spec.parse().map_err(|err: Error| err.to_string())
+}
+
+/// Parses a key=value ssh option from the command line
+fn parse_option(spec: &str) -> Result<(String, String), String> {
+    spec.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Bad option: {spec}"))
+}
+
+/// Parses a jump host specification from the command line
+fn parse_jump_host(spec: &str) -> Result<JumpHost, String> {
+    spec.parse().map_err(|err: Error| err.to_string())
+}
+
+/// Parses a remote forward specification from the command line
+fn parse_remote_forward(spec: &str) -> Result<Forward, String> {
+    Forward::parse(spec, ForwardDirection::Remote).map_err(|err| err.to_string())
 }
 
 impl Args {
-    fn to_config(&self) -> SshConfig {
-        SshConfig::new(
-            &self.end_host,
-            &self.username,
-            &self.key_path,
-            &self.to_host,
-            self.local_port,
-            self.remote_port,
-            self.keepalive,
-            &["-T"],
+    fn to_config(&self) -> Result<SshConfig, Error> {
+        #[cfg(feature = "serde")]
+        if let Some(name) = &self.profile {
+            return Profiles::load_default()?
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Config(format!("No such profile: {name}")));
+        }
+
+        let mut builder = SshConfig::builder(
+            self.end_host.as_deref().unwrap_or_default(),
+            self.username.as_deref().unwrap_or_default(),
+            self.key_path.as_deref().unwrap_or_default(),
         )
+        .to_host(self.to_host.as_deref().unwrap_or_default())
+        .local_forward(self.local_port, self.remote_port)
+        .keepalive(self.keepalive)
+        .host_key_policy(self.host_keys.clone())
+        .flag("-N");
+
+        for forward in self.forwards.iter().chain(&self.remote_forwards) {
+            builder = builder.forward(forward.clone());
+        }
+        for forward in &self.dynamic_forwards {
+            builder = builder.dynamic_forward(forward.clone());
+        }
+        for jump_host in &self.jump_hosts {
+            builder = builder.jump_host(jump_host.clone());
+        }
+        for (key, value) in &self.options {
+            builder = builder.option(key, value);
+        }
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        let config = builder.build()?;
+
+        #[cfg(feature = "serde")]
+        if let Some(name) = &self.save_profile {
+            let mut profiles = Profiles::load_default()?;
+            profiles.insert(name, config.clone());
+            profiles.save()?;
+            log::info!("Saved profile {name} to {}", profiles.path().display());
+        }
+
+        Ok(config)
     }
 }
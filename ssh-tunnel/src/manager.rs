@@ -0,0 +1,594 @@
+//! Running many tunnels at once
+//!
+//! A [TunnelManager] owns a set of named tunnels, each of which runs under its own [Supervisor], and keeps track of their
+//! latest statuses. It makes sure that no two running tunnels listen on the same local port, and it stops all of its
+//! tunnels when it's dropped.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::config::SshConfig;
+use crate::error::{Error, Result};
+use crate::health::HealthCheck;
+use crate::reconnect::{self, ReconnectPolicy, Supervisor};
+use crate::status::{ExitCondition, SshStatus};
+use crate::tunnel::{ChildProc, SshTunnel, TunnelChild};
+use crate::SshHandle;
+
+/// The callback that a [TunnelManager] calls with each status reported by one of its tunnels, along with the tunnel's name
+pub type ManagerCallback = Arc<Mutex<dyn FnMut(&str, SshStatus) + Send>>;
+
+/// A count of a [TunnelManager]'s tunnels by the category of their state (see [SshStatus])
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ManagerStatus {
+    /// Tunnels that are connected
+    pub connected: usize,
+
+    /// Tunnels that are connecting or waiting to reconnect
+    pub connecting: usize,
+
+    /// Tunnels that have failed, or are connected but [unhealthy](SshStatus::Unhealthy)
+    pub failed: usize,
+
+    /// Tunnels that haven't been started, or have been stopped
+    pub stopped: usize,
+}
+
+impl ManagerStatus {
+    /// Checks whether every tunnel that isn't stopped is connected
+    pub fn all_connected(&self) -> bool {
+        self.connecting == 0 && self.failed == 0
+    }
+}
+
+/// A tunnel owned by a [TunnelManager]
+struct ManagedTunnel<T> {
+    /// The config that the tunnel is started with
+    config: SshConfig,
+
+    /// The check of the tunnel's health while it's connected, if it has one
+    health_check: Option<HealthCheck>,
+
+    /// The tunnel's state, which is updated by its status callback
+    status: Arc<Mutex<SshStatus>>,
+
+    /// The supervisor of the running tunnel
+    running: Option<(Supervisor<T>, SshHandle)>,
+}
+
+impl<T: ChildProc> ManagedTunnel<T> {
+    /// The tunnel's state
+    fn status(&self) -> SshStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Checks whether the tunnel's supervisor is still running
+    fn is_running(&self) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|(_, handle)| !handle.is_finished())
+    }
+
+    /// The config that the tunnel is running with, if its supervisor is still running
+    ///
+    /// This is read from the supervisor each time, so it has any free ports that have been assigned and any forwards that
+    /// have been added or cancelled since the tunnel started.
+    fn running_config(&self) -> Option<SshConfig> {
+        match &self.running {
+            Some((supervisor, handle)) if !handle.is_finished() => supervisor.config(),
+            _ => None,
+        }
+    }
+}
+
+/// Owns a set of named tunnels, which can be started and stopped independently
+///
+/// Each tunnel runs under a [Supervisor] with the manager's [ReconnectPolicy]. Its state (see [SshStatus]) is kept by the
+/// manager, and each status it reports is passed on to the manager's callback, if it has one. The statuses that are reported
+/// without changing the tunnel's state ([SshStatus::PortsAssigned], [SshStatus::Degraded] and [SshStatus::Healthy]) are passed
+/// on, but not kept.
+///
+/// Dropping the manager stops all of its tunnels, and waits for them to exit.
+pub struct TunnelManager<T: ChildProc + Send + 'static = TunnelChild> {
+    tunnels: BTreeMap<String, ManagedTunnel<T>>,
+    policy: ReconnectPolicy,
+    callback: Option<ManagerCallback>,
+}
+
+impl<T: ChildProc + Send + 'static> Default for TunnelManager<T> {
+    fn default() -> Self {
+        TunnelManager::new(ReconnectPolicy::default())
+    }
+}
+
+impl<T: ChildProc + Send + 'static> TunnelManager<T> {
+    /// Creates a manager with no tunnels, which supervises its tunnels with the given policy
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        TunnelManager {
+            tunnels: BTreeMap::new(),
+            policy,
+            callback: None,
+        }
+    }
+
+    /// Sets the callback that is called with each status reported by the manager's tunnels
+    ///
+    /// The callback only applies to tunnels that are started after it's set.
+    pub fn with_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&str, SshStatus) + Send + 'static,
+    {
+        self.callback = Some(Arc::new(Mutex::new(callback)));
+        self
+    }
+
+    /// Adds a tunnel to the manager, without starting it
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager already has a tunnel with the given name.
+    pub fn add(&mut self, name: &str, config: SshConfig) -> Result<()> {
+        self.insert(name, config, None)
+    }
+
+    /// Adds a tunnel to the manager, without starting it, whose health is checked while it's connected
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager already has a tunnel with the given name.
+    pub fn add_with_health_check(
+        &mut self,
+        name: &str,
+        config: SshConfig,
+        health_check: HealthCheck,
+    ) -> Result<()> {
+        self.insert(name, config, Some(health_check))
+    }
+
+    /// Adds a tunnel to the manager
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager already has a tunnel with the given name.
+    fn insert(
+        &mut self,
+        name: &str,
+        config: SshConfig,
+        health_check: Option<HealthCheck>,
+    ) -> Result<()> {
+        if self.tunnels.contains_key(name) {
+            return Err(Error::Config(format!("Tunnel already exists: {name}")));
+        }
+
+        self.tunnels.insert(
+            name.to_string(),
+            ManagedTunnel {
+                config,
+                health_check,
+                status: Arc::new(Mutex::new(SshStatus::Ready)),
+                running: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stops a tunnel (if it's running) and removes it from the manager
+    ///
+    /// # Returns
+    ///
+    /// Returns the tunnel's final status.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager has no tunnel with the given name.
+    pub fn remove(&mut self, name: &str) -> Result<SshStatus> {
+        let status = self.stop(name)?;
+        self.tunnels.remove(name);
+        Ok(status)
+    }
+
+    /// Starts a tunnel
+    ///
+    /// The tunnel is started with [reconnect::supervise] (or [reconnect::supervise_with_health_check], if it has a health
+    /// check), so it's reconnected as directed by the manager's policy, and any free ports that are picked for it are kept
+    /// while it reconnects.
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [Error::Config] if the manager has no tunnel with the given name, if the tunnel is already running, or
+    ///   if another running tunnel listens on one of its local ports (on the same address, or on all addresses).
+    /// * Returns the same errors as [reconnect::supervise] if the tunnel fails to start.
+    pub fn start(&mut self, name: &str) -> Result<()> {
+        let tunnel = self.get(name)?;
+        if tunnel.is_running() {
+            return Err(Error::Config(format!("Tunnel is already running: {name}")));
+        }
+        let claims: Vec<_> = self
+            .tunnels
+            .iter()
+            .filter(|(other, _)| other.as_str() != name)
+            .filter_map(|(other, tunnel)| Some((other.as_str(), tunnel.running_config()?)))
+            .collect();
+        let claims = claims.iter().map(|(other, config)| (*other, config));
+        if let Some((port, other)) = find_port_conflict(&tunnel.config, claims) {
+            return Err(Error::Config(format!(
+                "Local port {port} is already used by tunnel {other}"
+            )));
+        }
+
+        let status = tunnel.status.clone();
+        let config = tunnel.config.clone();
+        let health_check = tunnel.health_check.clone();
+        let manager_callback = self.callback.clone();
+        let policy = self.policy.clone();
+        let tunnel_name = name.to_string();
+        let callback = Arc::new(Mutex::new(move |new_status: SshStatus| {
+            match new_status {
+                SshStatus::PortsAssigned(_) | SshStatus::Degraded(_) | SshStatus::Healthy(_) => {}
+                _ => *status.lock().unwrap_or_else(PoisonError::into_inner) = new_status.clone(),
+            }
+            if let Some(callback) = &manager_callback {
+                let mut callback = callback.lock().unwrap_or_else(PoisonError::into_inner);
+                callback(&tunnel_name, new_status);
+            }
+        }));
+
+        let tunnel = self.get_mut(name)?;
+        // Reap the supervisor of the previous run, which has finished
+        if let Some((_, handle)) = tunnel.running.take() {
+            let _ = handle.join();
+        }
+        *tunnel.status.lock().unwrap_or_else(PoisonError::into_inner) = SshStatus::Connecting;
+
+        let started = match health_check {
+            Some(check) => {
+                reconnect::supervise_with_health_check::<T, _>(config, policy, check, callback)
+            }
+            None => reconnect::supervise(config, policy, callback),
+        };
+        match started {
+            Ok((supervisor, handle)) => {
+                tunnel.running = Some((supervisor, handle));
+                Ok(())
+            }
+            Err(err) => {
+                *tunnel.status.lock().unwrap_or_else(PoisonError::into_inner) = err.to_status();
+                Err(err)
+            }
+        }
+    }
+
+    /// Stops a tunnel, and waits for it to exit
+    ///
+    /// Stopping a tunnel that isn't running does nothing.
+    ///
+    /// # Returns
+    ///
+    /// Returns the tunnel's final status.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager has no tunnel with the given name.
+    pub fn stop(&mut self, name: &str) -> Result<SshStatus> {
+        let tunnel = self.get_mut(name)?;
+        if let Some((supervisor, handle)) = tunnel.running.take() {
+            supervisor.stop();
+            let (status, _) = join_supervisor(handle);
+            *tunnel.status.lock().unwrap_or_else(PoisonError::into_inner) = status;
+        }
+        Ok(tunnel.status())
+    }
+
+    /// Stops a tunnel (if it's running) and starts it again
+    ///
+    /// The tunnel is started from its original config, so any free ports are picked again.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [TunnelManager::start].
+    pub fn restart(&mut self, name: &str) -> Result<()> {
+        self.stop(name)?;
+        self.start(name)
+    }
+
+    /// Stops all of the tunnels, and waits for them to exit
+    pub fn stop_all(&mut self) {
+        // All of the supervisors are told to stop before waiting for any of them
+        let running: Vec<_> = self
+            .tunnels
+            .values_mut()
+            .filter_map(|tunnel| Some((tunnel.status.clone(), tunnel.running.take()?)))
+            .collect();
+        for (_, (supervisor, _)) in &running {
+            supervisor.stop();
+        }
+        for (status, (_, handle)) in running {
+            let (final_status, _) = join_supervisor(handle);
+            *status.lock().unwrap_or_else(PoisonError::into_inner) = final_status;
+        }
+    }
+
+    /// The names of the manager's tunnels, in order
+    pub fn names(&self) -> Vec<String> {
+        self.tunnels.keys().cloned().collect()
+    }
+
+    /// The config that a tunnel is started with
+    pub fn config(&self, name: &str) -> Option<&SshConfig> {
+        self.tunnels.get(name).map(|tunnel| &tunnel.config)
+    }
+
+    /// The tunnel process that is currently running for a tunnel, if there is one
+    pub fn tunnel(&self, name: &str) -> Option<SshTunnel<T>> {
+        let (supervisor, _) = self.tunnels.get(name)?.running.as_ref()?;
+        supervisor.tunnel()
+    }
+
+    /// A tunnel's state, or [None] if the manager has no tunnel with the given name
+    pub fn status(&self, name: &str) -> Option<SshStatus> {
+        self.tunnels.get(name).map(ManagedTunnel::status)
+    }
+
+    /// The state of each of the tunnels, in order of their names
+    pub fn statuses(&self) -> Vec<(String, SshStatus)> {
+        self.tunnels
+            .iter()
+            .map(|(name, tunnel)| (name.clone(), tunnel.status()))
+            .collect()
+    }
+
+    /// Counts the tunnels by the category of their state
+    pub fn summary(&self) -> ManagerStatus {
+        let mut summary = ManagerStatus::default();
+        for tunnel in self.tunnels.values() {
+            match tunnel.status() {
+                SshStatus::Connected => summary.connected += 1,
+                SshStatus::Connecting | SshStatus::Reconnecting(_, _) => summary.connecting += 1,
+                SshStatus::Ready => summary.stopped += 1,
+                _ => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    /// Looks up a tunnel
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager has no tunnel with the given name.
+    fn get(&self, name: &str) -> Result<&ManagedTunnel<T>> {
+        self.tunnels
+            .get(name)
+            .ok_or_else(|| Error::Config(format!("No such tunnel: {name}")))
+    }
+
+    /// Looks up a tunnel for changing
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the manager has no tunnel with the given name.
+    fn get_mut(&mut self, name: &str) -> Result<&mut ManagedTunnel<T>> {
+        self.tunnels
+            .get_mut(name)
+            .ok_or_else(|| Error::Config(format!("No such tunnel: {name}")))
+    }
+}
+
+impl<T: ChildProc + Send + 'static> Drop for TunnelManager<T> {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+/// Waits for a supervisor thread to return its final status
+fn join_supervisor(handle: SshHandle) -> (SshStatus, ExitCondition) {
+    handle.join().unwrap_or_else(|_| {
+        (
+            SshStatus::AppError("The tunnel's supervisor thread panicked".to_string()),
+            ExitCondition::ProcError,
+        )
+    })
+}
+
+/// Finds a local port of the config that is already claimed by one of the given (named) configs
+///
+/// A port is only claimed on the address that it's bound to, unless either side listens on all addresses.
+///
+/// # Returns
+///
+/// Returns the first conflicting port, along with the name of the config that claims it.
+fn find_port_conflict<'a, 'b>(
+    config: &SshConfig,
+    claims: impl IntoIterator<Item = (&'a str, &'b SshConfig)>,
+) -> Option<(u32, &'a str)> {
+    let listeners = config.local_listeners();
+    claims.into_iter().find_map(|(name, claimed)| {
+        claimed
+            .local_listeners()
+            .into_iter()
+            .find(|&claimed| {
+                listeners
+                    .iter()
+                    .any(|&listener| listeners_overlap(listener, claimed))
+            })
+            .map(|(_, port)| (port, name))
+    })
+}
+
+/// Checks whether two (address, port) listeners would get in each other's way
+fn listeners_overlap(
+    (address, port): (&str, u32),
+    (other_address, other_port): (&str, u32),
+) -> bool {
+    let is_wildcard = |address: &str| address == "0.0.0.0" || address == "::";
+    port == other_port
+        && (address == other_address || is_wildcard(address) || is_wildcard(other_address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_port_conflict, ManagerStatus, TunnelManager};
+    use crate::config::{DynamicForward, Forward, SshConfig, ANY_PORT};
+    use crate::error::Error;
+    use crate::status::SshStatus;
+
+    fn config(local_port: u32) -> SshConfig {
+        SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(local_port, 5432)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_port_conflict() {
+        let postgres = config(5432);
+        let proxy = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(ANY_PORT, 6379)
+            .dynamic_forward(DynamicForward::new(None, 1080))
+            .build()
+            .unwrap();
+        let claims = [("postgres", &postgres), ("proxy", &proxy)];
+
+        assert_eq!(
+            find_port_conflict(&config(5432), claims),
+            Some((5432, "postgres"))
+        );
+        assert_eq!(
+            find_port_conflict(&config(1080), claims),
+            Some((1080, "proxy"))
+        );
+        assert_eq!(find_port_conflict(&config(5433), claims), None);
+        // Free ports don't claim anything until they're assigned
+        assert_eq!(find_port_conflict(&config(ANY_PORT), claims), None);
+
+        // The same port on another address is free, unless one of them listens on all addresses
+        let bound = |address: &str, port: u32| {
+            SshConfig::builder("endhost", "username", "keypath")
+                .forward(Forward::new(port, "localhost", 5432).with_bind_address(address))
+                .build()
+                .unwrap()
+        };
+        assert_eq!(find_port_conflict(&bound("127.0.0.2", 5432), claims), None);
+        assert_eq!(
+            find_port_conflict(&bound("*", 5432), claims),
+            Some((5432, "postgres"))
+        );
+        let everywhere = bound("0.0.0.0", 6379);
+        assert_eq!(
+            find_port_conflict(&bound("127.0.0.2", 6379), [("everywhere", &everywhere)]),
+            Some((6379, "everywhere"))
+        );
+        assert_eq!(
+            find_port_conflict(&bound("::", 6380), [("everywhere", &everywhere)]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let mut manager: TunnelManager = TunnelManager::default();
+        manager.add("postgres", config(5432)).unwrap();
+        manager.add("redis", config(6379)).unwrap();
+        assert!(matches!(
+            manager.add("postgres", config(5433)),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(manager.start("mysql"), Err(Error::Config(_))));
+
+        assert_eq!(manager.names(), vec!["postgres", "redis"]);
+        assert_eq!(manager.status("redis"), Some(SshStatus::Ready));
+        assert_eq!(manager.status("mysql"), None);
+        assert_eq!(
+            manager.summary(),
+            ManagerStatus {
+                stopped: 2,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(manager.remove("redis").unwrap(), SshStatus::Ready);
+        assert_eq!(manager.names(), vec!["postgres"]);
+    }
+
+    /// Runs tunnels with a [MockChild] in place of ssh
+    #[cfg(unix)]
+    mod running {
+        use std::thread;
+        use std::time::Duration;
+
+        use super::config;
+        use crate::config::{Forward, SshConfig, ANY_PORT};
+        use crate::error::Error;
+        use crate::manager::{ManagerStatus, TunnelManager};
+        use crate::reconnect::ReconnectPolicy;
+        use crate::status::SshStatus;
+        use crate::tunnel::mock::MockChild;
+        use crate::tunnel::ChildProc;
+
+        /// Waits for a tunnel to connect
+        fn wait_connected(manager: &TunnelManager<MockChild>, name: &str) {
+            for _ in 0..100 {
+                if manager.status(name) == Some(SshStatus::Connected) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            panic!("{name} didn't connect: {:?}", manager.status(name));
+        }
+
+        #[test]
+        fn test_start_stop() {
+            let mut manager: TunnelManager<MockChild> =
+                TunnelManager::new(ReconnectPolicy::never());
+            manager.add("postgres", config(ANY_PORT)).unwrap();
+            manager.add("redis", config(ANY_PORT)).unwrap();
+            manager.start("postgres").unwrap();
+            manager.start("redis").unwrap();
+            wait_connected(&manager, "postgres");
+            wait_connected(&manager, "redis");
+            assert!(matches!(manager.start("postgres"), Err(Error::Config(_))));
+            assert_eq!(
+                manager.summary(),
+                ManagerStatus {
+                    connected: 2,
+                    ..Default::default()
+                }
+            );
+
+            // The port that was picked for a tunnel is claimed while it runs, on any address
+            let port = manager
+                .tunnel("postgres")
+                .unwrap()
+                .lock()
+                .unwrap()
+                .config()
+                .local_ports()[0];
+            assert_ne!(port, ANY_PORT);
+            let everywhere = SshConfig::builder("endhost", "username", "keypath")
+                .forward(Forward::new(port, "localhost", 5432).with_bind_address("0.0.0.0"))
+                .build()
+                .unwrap();
+            manager.add("everywhere", everywhere).unwrap();
+            assert!(matches!(manager.start("everywhere"), Err(Error::Config(_))));
+            assert_eq!(manager.status("everywhere"), Some(SshStatus::Ready));
+
+            assert_eq!(manager.stop("postgres").unwrap(), SshStatus::Ready);
+            assert!(manager.tunnel("postgres").is_none());
+            manager.start("everywhere").unwrap();
+            wait_connected(&manager, "everywhere");
+
+            manager.stop_all();
+            assert_eq!(
+                manager.statuses(),
+                vec![
+                    ("everywhere".to_string(), SshStatus::Ready),
+                    ("postgres".to_string(), SshStatus::Ready),
+                    ("redis".to_string(), SshStatus::Ready)
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::config::DynamicForward;
+use crate::error::Error;
+
+/// Checks that the service behind a local forward is answering
+///
+/// Probes are used by [health checks](crate::health::HealthCheck). [ProbeKind] provides probes for some common services,
+/// and any other service can be checked by implementing this trait.
+pub trait Probe: fmt::Debug + Send + Sync {
+    /// Probes the service behind the given local port, within the given timeout
+    ///
+    /// # Errors
+    ///
+    /// Returns an [io::Error] if the service doesn't answer, or answers in a way that the probe doesn't expect.
+    fn probe(&self, port: u32, timeout: Duration) -> io::Result<ProbeReport>;
+}
+
+/// The result of a [Probe] that passed
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProbeReport {
+    /// How long the service took to answer, from the start of the connection
+    ///
+    /// If the service didn't [answer](ProbeReport::answered), this is only how long the connection took to open.
+    pub latency: Duration,
+
+    /// The version of the service, if it reported one
+    pub version: Option<String>,
+
+    /// Whether the service sent anything. A [ProbeKind::Tcp] probe passes without an answer (a banner) from services
+    /// that wait for the client to speak first.
+    pub answered: bool,
+}
+
+impl ProbeReport {
+    /// Creates a report for a probe that started at the given time, and got an answer just now
+    fn since(start: Instant, version: Option<String>) -> Self {
+        ProbeReport {
+            latency: start.elapsed(),
+            version,
+            answered: true,
+        }
+    }
+}
+
+/// The built-in probes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProbeKind {
+    /// Checks that the connection stays open (see [tcp_forward]). This works for any service, but it can't tell a service
+    /// that is answering from one that accepted the connection and hung.
+    Tcp,
+
+    /// Sends a Postgres SSLRequest, and expects the server to accept or decline it. Postgres only reports its version after
+    /// authenticating, so no version is given.
+    Postgres,
+
+    /// Sends a Redis `PING`, and expects a `PONG` (or an error asking for authentication). The version is read from
+    /// `INFO server`, if the server allows it.
+    Redis,
+
+    /// Sends an HTTP `GET` for the given path, and expects the given status. The version is read from the `Server` header.
+    Http {
+        /// The path to request
+        path: String,
+
+        /// The status that the server should answer with
+        expected_status: u16,
+    },
+}
+
+impl Probe for ProbeKind {
+    fn probe(&self, port: u32, timeout: Duration) -> io::Result<ProbeReport> {
+        let start = Instant::now();
+        match self {
+            ProbeKind::Tcp => tcp_forward(port, timeout),
+            ProbeKind::Postgres => {
+                let mut stream = connect(port, timeout)?;
+                postgres_ssl_request(&mut stream)?;
+                Ok(ProbeReport::since(start, None))
+            }
+            ProbeKind::Redis => {
+                let mut stream = BufReader::new(connect(port, timeout)?);
+                let authenticated = redis_ping(&mut stream)?;
+                let report = ProbeReport::since(start, None);
+                let version = match authenticated {
+                    // The version is a nice-to-have, so failing to read it doesn't fail the probe
+                    true => redis_version(&mut stream).unwrap_or_else(|err| {
+                        log::debug!("Failed to read the Redis version: {err}");
+                        None
+                    }),
+                    false => None,
+                };
+                Ok(ProbeReport { version, ..report })
+            }
+            ProbeKind::Http {
+                path,
+                expected_status,
+            } => {
+                let mut stream = BufReader::new(connect(port, timeout)?);
+                let (status, server) = http_get(&mut stream, path)?;
+                if status != *expected_status {
+                    return Err(io::Error::other(format!(
+                        "Expected HTTP status {expected_status}, got {status}"
+                    )));
+                }
+                Ok(ProbeReport::since(start, server))
+            }
+        }
+    }
+}
+
+impl FromStr for ProbeKind {
+    type Err = Error;
+
+    /// Parses a probe given as `tcp`, `postgres`, `redis` or `http[:path[:status]]` (which defaults to `/` and 200)
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let bad_spec = || Error::Config(format!("Bad probe specification: {spec}"));
+
+        let (kind, rest) = spec.split_once(':').unwrap_or((spec, ""));
+        match (kind, rest) {
+            ("tcp", "") => Ok(ProbeKind::Tcp),
+            ("postgres", "") => Ok(ProbeKind::Postgres),
+            ("redis", "") => Ok(ProbeKind::Redis),
+            ("http", rest) => {
+                let (path, status) = rest.split_once(':').unwrap_or((rest, ""));
+                Ok(ProbeKind::Http {
+                    path: if path.is_empty() { "/" } else { path }.to_string(),
+                    expected_status: match status {
+                        "" => 200,
+                        status => status.parse().map_err(|_| bad_spec())?,
+                    },
+                })
+            }
+            _ => Err(bad_spec()),
+        }
+    }
+}
+
+/// Converts a port from a config into a port number
+fn port_number(port: u32) -> io::Result<u16> {
+    u16::try_from(port)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid port: {port}")))
+}
+
+/// Connects to a local port, with the given timeout for the connection and for each read and write
+fn connect(port: u32, timeout: Duration) -> io::Result<TcpStream> {
+    let addr = ("127.0.0.1", port_number(port)?)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("Failed to resolve 127.0.0.1"))?;
+
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// The error for a connection that the other end closed before answering, which is what ssh does when it can't reach the
+/// service
+fn closed_early() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "The connection was closed before the service answered",
+    )
+}
+
+/// Sends a Postgres SSLRequest, and checks that the server either accepts or declines it
+fn postgres_ssl_request(stream: &mut TcpStream) -> io::Result<()> {
+    // The length of the message (8), then the SSLRequest code (80877103)
+    stream.write_all(&[0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f])?;
+
+    let mut reply = [0; 1];
+    match stream.read(&mut reply)? {
+        0 => Err(closed_early()),
+        _ if reply[0] == b'S' || reply[0] == b'N' => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected Postgres reply: {:?}", reply[0] as char),
+        )),
+    }
+}
+
+/// Reads a line (without its line ending) from the stream
+fn read_line(stream: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(closed_early());
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Sends a Redis `PING`, and checks the reply
+///
+/// Returns true if the server answered, or false if it's asking for authentication first.
+fn redis_ping(stream: &mut BufReader<TcpStream>) -> io::Result<bool> {
+    stream.get_mut().write_all(b"*1\r\n$4\r\nPING\r\n")?;
+
+    let reply = read_line(stream)?;
+    if reply == "+PONG" {
+        Ok(true)
+    } else if reply.starts_with("-NOAUTH") || reply.contains("AUTH") {
+        Ok(false)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected Redis reply: {reply}"),
+        ))
+    }
+}
+
+/// Reads the server's version from Redis `INFO server`
+fn redis_version(stream: &mut BufReader<TcpStream>) -> io::Result<Option<String>> {
+    stream
+        .get_mut()
+        .write_all(b"*2\r\n$4\r\nINFO\r\n$6\r\nserver\r\n")?;
+
+    // The info is a bulk string: its length, then the text
+    let header = read_line(stream)?;
+    let len: usize = match header.strip_prefix('$').and_then(|len| len.parse().ok()) {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut info = vec![0; len];
+    stream.read_exact(&mut info)?;
+
+    Ok(String::from_utf8_lossy(&info)
+        .lines()
+        .find_map(|line| line.strip_prefix("redis_version:"))
+        .map(|version| version.trim().to_string()))
+}
+
+/// Sends an HTTP `GET` for the given path
+///
+/// Returns the status of the response, and the `Server` header, if there is one.
+fn http_get(stream: &mut BufReader<TcpStream>, path: &str) -> io::Result<(u16, Option<String>)> {
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: ssh-tunnel\r\nConnection: close\r\n\r\n"
+    );
+    stream.get_mut().write_all(request.as_bytes())?;
+
+    let status_line = read_line(stream)?;
+    let status = match status_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/") => status.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected HTTP reply: {status_line}"),
+        )
+    })?;
+
+    let mut server = None;
+    loop {
+        let header = read_line(stream)?;
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("server") {
+                server = Some(value.trim().to_string());
+            }
+        }
+    }
+    Ok((status, server))
+}
+
+/// Confirms that a dynamic forward's SOCKS proxy is answering
+///
+/// This opens a connection to the proxy and performs the opening of a SOCKS5 handshake, offering only the "no
+/// authentication" method (which is the only one ssh supports). A proxy that answers by accepting that method is considered
+/// to be up. No connection is requested through the proxy, so nothing is sent to the end host.
+///
+/// # Errors
+///
+/// Returns an [io::Error] if the proxy can't be reached within the given timeout, or if it doesn't answer as a SOCKS5 proxy.
+pub fn socks5_handshake(forward: &DynamicForward, timeout: Duration) -> io::Result<()> {
+    // A proxy bound to all interfaces is still reachable on the loopback address
+    let host = match forward.bind_address() {
+        None | Some("") | Some("*") | Some("0.0.0.0") => "127.0.0.1",
+        Some("::") | Some("[::]") => "::1",
+        Some(addr) => addr.trim_start_matches('[').trim_end_matches(']'),
+    };
+
+    let addr = (host, port_number(forward.port())?)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("Failed to resolve {host}")))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // Version 5, one method offered: 0x00 (no authentication)
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+
+    match reply {
+        [0x05, 0x00] => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected SOCKS5 reply: {:?}", reply),
+        )),
+    }
+}
+
+/// Confirms that a local forward reaches a service that is answering
+///
+/// Connecting to the forward's local port only reaches ssh, which accepts the connection and then asks the end host to
+/// connect to the target. If the target can't be reached, ssh closes the connection straight away. So this opens a
+/// connection and waits up to the given timeout for anything to happen: the service sending something (or saying nothing,
+/// as services that wait for the client to speak first do) means that it's up, and the connection being closed means that
+/// it's not.
+///
+/// # Returns
+///
+/// Returns a report with the time until the service's first byte. A service that said nothing is reported as not having
+/// [answered](ProbeReport::answered), with the time that the connection took to open, since waiting out the timeout says
+/// nothing about the service's latency.
+///
+/// # Errors
+///
+/// Returns an [io::Error] if the port can't be connected to within the given timeout, or if the connection is closed before
+/// the timeout.
+pub fn tcp_forward(port: u32, timeout: Duration) -> io::Result<ProbeReport> {
+    let start = Instant::now();
+    let mut stream = connect(port, timeout)?;
+    let connected = start.elapsed();
+
+    let mut buffer = [0; 1];
+    match stream.read(&mut buffer) {
+        Ok(0) => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!(
+                "The connection to port {port} was closed by the other end after {:?}",
+                start.elapsed()
+            ),
+        )),
+        Ok(_) => Ok(ProbeReport::since(start, None)),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Ok(ProbeReport {
+                latency: connected,
+                version: None,
+                answered: false,
+            })
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{socks5_handshake, tcp_forward, Probe, ProbeKind};
+    use crate::config::DynamicForward;
+
+    /// Spawns a one-shot server that answers a SOCKS5 greeting with the given reply
+    fn serve_reply(reply: &'static [u8]) -> u32 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(reply).unwrap();
+        });
+        port as u32
+    }
+
+    /// Spawns a one-shot server that reads a request before sending each of the given replies
+    fn serve_replies(replies: &'static [&'static [u8]]) -> u32 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for reply in replies {
+                let mut request = [0; 256];
+                assert!(stream.read(&mut request).unwrap() > 0);
+                stream.write_all(reply).unwrap();
+            }
+        });
+        port as u32
+    }
+
+    #[test]
+    fn test_socks5_handshake() {
+        let timeout = Duration::from_secs(1);
+
+        let port = serve_reply(&[0x05, 0x00]);
+        assert!(socks5_handshake(&DynamicForward::new(None, port), timeout).is_ok());
+
+        let port = serve_reply(&[0x05, 0xff]);
+        assert!(socks5_handshake(&DynamicForward::new(Some("127.0.0.1"), port), timeout).is_err());
+    }
+
+    #[test]
+    fn test_tcp_forward() {
+        let timeout = Duration::from_millis(200);
+
+        // A service that waits for the client to speak first, which is given no latency beyond the connection's
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || listener.accept().unwrap());
+        let report = tcp_forward(port, timeout).unwrap();
+        assert!(!report.answered);
+        assert!(report.latency < timeout);
+        drop(server.join());
+
+        // A service that sends a banner
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.2\r\n").unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        assert!(tcp_forward(port, timeout).unwrap().answered);
+
+        assert!(tcp_forward(70000, timeout).is_err());
+
+        // A forward whose target is down, which ssh closes straight away
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        thread::spawn(move || drop(listener.accept().unwrap()));
+        assert!(tcp_forward(port, timeout).is_err());
+    }
+
+    #[test]
+    fn test_service_probes() {
+        let timeout = Duration::from_secs(1);
+
+        let port = serve_replies(&[b"N"]);
+        let report = ProbeKind::Postgres.probe(port, timeout).unwrap();
+        assert_eq!(report.version, None);
+        let port = serve_replies(&[b"HTTP/1.1 400 Bad Request\r\n\r\n"]);
+        assert!(ProbeKind::Postgres.probe(port, timeout).is_err());
+
+        let port = serve_replies(&[
+            b"+PONG\r\n",
+            b"$31\r\n# Server\r\nredis_version:7.2.4\r\n\r\n",
+        ]);
+        let report = ProbeKind::Redis.probe(port, timeout).unwrap();
+        assert_eq!(report.version.as_deref(), Some("7.2.4"));
+        let port = serve_replies(&[b"-NOAUTH Authentication required.\r\n"]);
+        assert_eq!(ProbeKind::Redis.probe(port, timeout).unwrap().version, None);
+
+        let http = "http:/health".parse::<ProbeKind>().unwrap();
+        let port = serve_replies(&[b"HTTP/1.1 200 OK\r\nServer: nginx/1.25.3\r\n\r\n"]);
+        let report = http.probe(port, timeout).unwrap();
+        assert_eq!(report.version.as_deref(), Some("nginx/1.25.3"));
+        let port = serve_replies(&[b"HTTP/1.1 503 Service Unavailable\r\n\r\n"]);
+        assert!(http.probe(port, timeout).is_err());
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        assert_eq!(
+            "postgres".parse::<ProbeKind>().unwrap(),
+            ProbeKind::Postgres
+        );
+        assert_eq!(
+            "http".parse::<ProbeKind>().unwrap(),
+            ProbeKind::Http {
+                path: "/".to_string(),
+                expected_status: 200
+            }
+        );
+        assert_eq!(
+            "http:/status:204".parse::<ProbeKind>().unwrap(),
+            ProbeKind::Http {
+                path: "/status".to_string(),
+                expected_status: 204
+            }
+        );
+        assert!("http:/:ok".parse::<ProbeKind>().is_err());
+        assert!("mysql".parse::<ProbeKind>().is_err());
+    }
+}
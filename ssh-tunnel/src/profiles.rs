@@ -0,0 +1,219 @@
+//! Named tunnel profiles, stored on disk
+//!
+//! Profiles are stored as a JSON object mapping each profile name to its [SshConfig], in the user's config directory (see
+//! [Profiles::default_path]). Since the file format is plain JSON, the CLI, GUI and any scripts can all share the same
+//! profile store.
+//!
+//! This module is only available with the `serde` feature.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::SshConfig;
+use crate::error::{Error, Result};
+
+/// The name of the directory, within the user's config directory, that holds the profile store
+const APP_DIR: &str = "eclo-ssh-client";
+
+/// The name of the profile store file
+const PROFILES_FILE: &str = "profiles.json";
+
+/// A store of named tunnel profiles
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ssh_tunnel::{config::SshConfig, error::Result, profiles::Profiles};
+/// # fn save() -> Result<()> {
+/// let mut profiles = Profiles::load_default()?;
+///
+/// let config = SshConfig::builder("bastion.example.com", "alice", "~/.ssh/id_ed25519")
+///     .local_forward(5432, 5432)
+///     .build()?;
+/// profiles.insert("prod-db", config);
+/// profiles.save()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    /// The file that the profiles are loaded from and saved to
+    path: PathBuf,
+
+    /// The profiles, by name
+    profiles: BTreeMap<String, SshConfig>,
+}
+
+impl Profiles {
+    /// Returns the default path of the profile store: `<config dir>/eclo-ssh-client/profiles.json`
+    ///
+    /// The config directory is platform dependent (see [dirs_next::config_dir]). If it can't be found, the home directory is
+    /// used instead, and failing that, the current directory.
+    pub fn default_path() -> PathBuf {
+        let mut path = dirs_next::config_dir()
+            .or_else(dirs_next::home_dir)
+            .unwrap_or_else(|| PathBuf::from("."));
+        path.push(APP_DIR);
+        path.push(PROFILES_FILE);
+        path
+    }
+
+    /// Loads the profile store from the [default path](Profiles::default_path)
+    ///
+    /// # Errors
+    ///
+    /// See [Profiles::load].
+    pub fn load_default() -> Result<Self> {
+        Profiles::load(Profiles::default_path())
+    }
+
+    /// Loads the profile store from the given path
+    ///
+    /// If the file doesn't exist yet, an empty store is returned, which will be created at that path when it's saved. Each
+    /// loaded profile is [validated](SshConfig::validate).
+    ///
+    /// # Errors
+    ///
+    /// * Returns an [Error::Io] if the file exists, but can't be read.
+    /// * Returns an [Error::Config] if the file isn't a valid profile store, or if any of its profiles are invalid.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let profiles: BTreeMap<String, SshConfig> = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|err| {
+                Error::Config(format!("Bad profile store {}: {err}", path.display()))
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(Error::Io(
+                    format!("Failed to read profile store {}", path.display()),
+                    err,
+                ))
+            }
+        };
+
+        for (name, config) in &profiles {
+            config.validate().map_err(|err| match err {
+                Error::Config(msg) => Error::Config(format!("Profile '{name}': {msg}")),
+                err => err,
+            })?;
+        }
+
+        log::debug!("Loaded {} profiles from {}", profiles.len(), path.display());
+        Ok(Profiles { path, profiles })
+    }
+
+    /// Saves the profile store to the path it was loaded from
+    ///
+    /// The store is written to a temporary file, which then replaces the old store, so that a failed save can't leave a
+    /// half-written store behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] if the store can't be written.
+    pub fn save(&self) -> Result<()> {
+        let save_error = |err| Error::io("Failed to save profile store", err);
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(save_error)?;
+        }
+
+        let text = serde_json::to_string_pretty(&self.profiles)
+            .map_err(|err| save_error(io::Error::other(err)))?;
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, text).map_err(save_error)?;
+        fs::rename(&tmp_path, &self.path).map_err(save_error)?;
+
+        log::debug!(
+            "Saved {} profiles to {}",
+            self.profiles.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// The path that the profile store is loaded from and saved to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the profile with the given name
+    pub fn get(&self, name: &str) -> Option<&SshConfig> {
+        self.profiles.get(name)
+    }
+
+    /// Adds a profile, replacing (and returning) any existing profile with the same name
+    pub fn insert(&mut self, name: &str, config: SshConfig) -> Option<SshConfig> {
+        self.profiles.insert(name.to_string(), config)
+    }
+
+    /// Removes (and returns) the profile with the given name
+    pub fn remove(&mut self, name: &str) -> Option<SshConfig> {
+        self.profiles.remove(name)
+    }
+
+    /// Returns the names of all of the profiles, in alphabetical order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Profiles;
+    use crate::config::{Forward, JumpHost, SshConfig};
+    use crate::error::Error;
+
+    #[test]
+    fn test_profiles_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ssh-tunnel-profiles-{}", std::process::id()));
+        let path = dir.join("profiles.json");
+
+        let mut profiles = Profiles::load(&path).unwrap();
+        assert_eq!(profiles.names().count(), 0);
+
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .forward(Forward::remote(9000, "localhost", 3000))
+            .jump_host(JumpHost::new("bastion", Some("alice"), Some(2222), None))
+            .connect_timeout(5)
+            .build()
+            .unwrap();
+        profiles.insert("prod", config.clone());
+        profiles.save().unwrap();
+
+        let loaded = Profiles::load(&path).unwrap();
+        assert_eq!(loaded.names().collect::<Vec<_>>(), vec!["prod"]);
+        assert_eq!(loaded.get("prod").unwrap().to_args(), config.to_args());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_profile() {
+        let dir =
+            std::env::temp_dir().join(format!("ssh-tunnel-bad-profiles-{}", std::process::id()));
+        let path = dir.join("profiles.json");
+        fs::create_dir_all(&dir).unwrap();
+
+        // Optional fields may be left out, but a profile still needs a forward
+        fs::write(
+            &path,
+            r#"{"prod": {"end_host": "endhost", "username": "username", "key_path": "keypath", "forwards": []}}"#,
+        )
+        .unwrap();
+
+        match Profiles::load(&path) {
+            Err(Error::Config(msg)) => {
+                assert_eq!(msg, "Profile 'prod': At least one port forward is required")
+            }
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+//! Reconnecting dropped tunnels
+//!
+//! A [Supervisor] runs a tunnel and, once the tunnel has connected, starts it again whenever it fails with a retryable
+//! status, following a [ReconnectPolicy]. Each attempt is announced with [SshStatus::Reconnecting], which gives the number
+//! of the attempt and how long the supervisor will wait before making it.
+//!
+//! A supervisor can also check the health of the tunnel while it's connected (see [health]).
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::SshConfig;
+use crate::error::Result;
+use crate::health::{self, HealthCheck};
+use crate::status::{ExitCondition, SshStatus};
+use crate::tunnel::{ChildProc, SshTunnel};
+use crate::{call_status_callback, lock_tunnel, SshHandle};
+
+/// Decides whether, and when, a [Supervisor] reconnects a tunnel
+///
+/// The delay before each attempt grows exponentially, from `initial_delay` (before the first attempt) by a factor of
+/// `multiplier` for each following attempt, up to `max_delay`. Each delay is then spread randomly by up to `jitter` (as a
+/// fraction of the delay) either way, so that tunnels that dropped together don't all reconnect at once.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The number of attempts to make before giving up, or [None] to keep trying forever. The count starts again each time
+    /// the tunnel connects.
+    pub max_attempts: Option<u32>,
+
+    /// The delay before the first attempt
+    pub initial_delay: Duration,
+
+    /// The longest delay between attempts
+    pub max_delay: Duration,
+
+    /// The factor that the delay grows by with each attempt
+    pub multiplier: f64,
+
+    /// How far each delay is randomly spread, as a fraction of the delay (between 0 and 1)
+    pub jitter: f64,
+
+    /// Decides which exit statuses are worth reconnecting after. Defaults to [is_retryable].
+    pub retryable: fn(&SshStatus) -> bool,
+}
+
+impl Default for ReconnectPolicy {
+    /// Five attempts, starting after 3 seconds and doubling up to a minute, with 20% jitter
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_secs(3),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retryable: is_retryable,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// A policy that never reconnects
+    pub fn never() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Checks whether the given attempt (counting from 1) may be made
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.map_or(true, |max| attempt <= max)
+    }
+
+    /// Checks whether a tunnel that exited with the given status should be reconnected
+    pub fn is_retryable(&self, status: &SshStatus) -> bool {
+        (self.retryable)(status)
+    }
+
+    /// The delay before the given attempt (counting from 1), without jitter
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let delay = self.initial_delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// The delay before the given attempt (counting from 1), with jitter
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let spread = 1.0 + jitter * (2.0 * random_fraction() - 1.0);
+        self.base_delay(attempt).mul_f64(spread)
+    }
+}
+
+/// The default test for which exit statuses are worth reconnecting after
+///
+/// These are the failures that can clear up on their own: the connection dropping, the server (or a jump host) being
+/// unreachable, refusing connections or stalling for a while (which is common while it restarts), and a
+/// [health check](crate::health::HealthCheck) restarting the tunnel. Failures that need the user to do something, such as
+/// fixing credentials or the config, are not retried.
+pub fn is_retryable(status: &SshStatus) -> bool {
+    matches!(
+        status,
+        SshStatus::Dropped
+            | SshStatus::Unreachable
+            | SshStatus::Refused
+            | SshStatus::TimedOut
+            | SshStatus::JumpUnreachable(_, _)
+            | SshStatus::Unhealthy(_)
+    )
+}
+
+/// Returns a random number between 0 (inclusive) and 1 (exclusive)
+///
+/// The jitter doesn't need a good random number generator, so this uses the random keys of the standard library's hasher
+/// rather than bringing in a dependency.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The state shared between a [Supervisor] and its thread
+struct SupervisorState<T> {
+    /// The tunnel that is currently running
+    tunnel: Option<SshTunnel<T>>,
+
+    /// The config of the last tunnel process, as of when it exited
+    config: Option<SshConfig>,
+
+    /// Whether the supervisor has been told to stop
+    stopped: bool,
+
+    /// The status to report in place of the exit status of a process that the health check killed
+    unhealthy: Option<SshStatus>,
+}
+
+/// A handle for a supervised tunnel
+///
+/// The supervisor's thread runs until the tunnel exits and won't be reconnected, or until the supervisor is
+/// [stopped](Supervisor::stop). Its [SshHandle] then returns the final status.
+pub struct Supervisor<T> {
+    shared: Arc<(Mutex<SupervisorState<T>>, Condvar)>,
+}
+
+// Deriving Clone would require T to be Clone
+impl<T> Clone for Supervisor<T> {
+    fn clone(&self) -> Self {
+        Supervisor {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: ChildProc> Supervisor<T> {
+    /// The tunnel process that is currently running, if there is one
+    pub fn tunnel(&self) -> Option<SshTunnel<T>> {
+        self.lock().tunnel.clone()
+    }
+
+    /// The config of the tunnel process that is currently running, or of the last one while the supervisor waits to
+    /// reconnect
+    ///
+    /// This gives the ports that the tunnel listens on as they are now, including any free ports that were picked for it and
+    /// any forwards that were added to it while it was running.
+    pub fn config(&self) -> Option<SshConfig> {
+        let state = self.lock();
+        match state.tunnel.as_ref() {
+            Some(tunnel) => match lock_tunnel(tunnel) {
+                Ok(tunnel) => Some(tunnel.config().clone()),
+                Err(err) => {
+                    log::error!("Failed to read the tunnel's config: {err}");
+                    state.config.clone()
+                }
+            },
+            None => state.config.clone(),
+        }
+    }
+
+    /// Stops the supervisor, and kills the tunnel process if it's running
+    ///
+    /// The final status is reported once the process has exited. If the supervisor was waiting to reconnect, it stops
+    /// waiting, and the final status is [SshStatus::Ready].
+    pub fn stop(&self) {
+        let mut state = self.lock();
+        state.stopped = true;
+        if let Some(tunnel) = state.tunnel.as_ref() {
+            match lock_tunnel(tunnel) {
+                Ok(mut tunnel) => tunnel.kill(),
+                Err(err) => log::error!("Failed to kill tunnel: {err}"),
+            }
+        }
+        self.shared.1.notify_all();
+    }
+
+    /// Waits for the given time, or until the supervisor is stopped
+    ///
+    /// Returns true if the supervisor has been stopped.
+    pub(crate) fn wait_stopped(&self, timeout: Duration) -> bool {
+        let (state, _) = self
+            .shared
+            .1
+            .wait_timeout_while(self.lock(), timeout, |state| !state.stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        state.stopped
+    }
+
+    /// Kills a tunnel process that failed its health check, so that it exits with the given status
+    ///
+    /// Nothing is done if the process has already been replaced.
+    pub(crate) fn restart_unhealthy(&self, tunnel: &SshTunnel<T>, status: SshStatus) {
+        let mut state = self.lock();
+        if !state
+            .tunnel
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, tunnel))
+        {
+            return;
+        }
+
+        log::info!("Restarting unhealthy tunnel: {status}");
+        state.unhealthy = Some(status);
+        match lock_tunnel(tunnel) {
+            Ok(mut tunnel) => tunnel.kill(),
+            Err(err) => log::error!("Failed to kill tunnel: {err}"),
+        }
+    }
+
+    /// Locks the shared state
+    ///
+    /// Nothing that holds the lock can panic, so a poisoned lock is used as is.
+    fn lock(&self) -> MutexGuard<'_, SupervisorState<T>> {
+        self.shared.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Starts a tunnel process, and a supervisor thread that reconnects it as directed by the policy
+///
+/// The tunnel is started with [start_and_watch_ssh_tunnel](crate::start_and_watch_ssh_tunnel), and its statuses are
+/// reported to the status_callback as usual, except for the exit status: when the tunnel exits with a
+/// [retryable](ReconnectPolicy::retryable) status after it has connected, [SshStatus::Reconnecting] is reported instead, and
+/// the tunnel is started again after the policy's delay. Any free ports that were picked for the first process are reused
+/// by the later ones.
+///
+/// The supervisor gives up when a reconnect attempt fails with a status that isn't retryable, which is then reported, or
+/// when the policy runs out of attempts, in which case the status that the tunnel originally exited with is reported.
+/// Tunnels that fail to connect in the first place are not retried.
+///
+/// # Errors
+///
+/// Returns the same errors as [start_and_watch_ssh_tunnel](crate::start_and_watch_ssh_tunnel), for the first process.
+pub fn supervise<T, F>(
+    config: SshConfig,
+    policy: ReconnectPolicy,
+    status_callback: Arc<Mutex<F>>,
+) -> Result<(Supervisor<T>, SshHandle)>
+where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    start_supervisor(config, policy, None, status_callback)
+}
+
+/// Starts a supervised tunnel, as [supervise] does, and checks its health while it's connected
+///
+/// Each time the tunnel connects, the health check starts probing it. When the probe keeps failing, the tunnel is reported
+/// as [SshStatus::Unhealthy], or restarted, as the check directs.
+///
+/// # Errors
+///
+/// Returns the same errors as [supervise].
+pub fn supervise_with_health_check<T, F>(
+    config: SshConfig,
+    policy: ReconnectPolicy,
+    health_check: HealthCheck,
+    status_callback: Arc<Mutex<F>>,
+) -> Result<(Supervisor<T>, SshHandle)>
+where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    start_supervisor(config, policy, Some(health_check), status_callback)
+}
+
+/// Starts the first tunnel process and the supervisor thread
+fn start_supervisor<T, F>(
+    config: SshConfig,
+    policy: ReconnectPolicy,
+    health_check: Option<HealthCheck>,
+    status_callback: Arc<Mutex<F>>,
+) -> Result<(Supervisor<T>, SshHandle)>
+where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    let supervisor = Supervisor {
+        shared: Arc::new((
+            Mutex::new(SupervisorState {
+                tunnel: None,
+                config: None,
+                stopped: false,
+                unhealthy: None,
+            }),
+            Condvar::new(),
+        )),
+    };
+
+    let connected = Arc::new(AtomicBool::new(false));
+    let mut state = supervisor.lock();
+    let (tunnel, watcher) =
+        start_attempt::<T, F>(config, status_callback.clone(), connected.clone())?;
+    let config = lock_tunnel(&tunnel)?.config().clone();
+    state.tunnel = Some(tunnel.clone());
+    drop(state);
+    if let Some(check) = &health_check {
+        health::spawn_monitor(
+            check.clone(),
+            supervisor.clone(),
+            tunnel,
+            connected.clone(),
+            status_callback.clone(),
+        );
+    }
+
+    let thread_supervisor = supervisor.clone();
+    log::debug!("Spawning supervisor thread");
+    let handle = thread::spawn(move || {
+        supervise_loop(
+            thread_supervisor,
+            config,
+            policy,
+            health_check,
+            status_callback,
+            watcher,
+            connected,
+        )
+    });
+
+    Ok((supervisor, handle))
+}
+
+/// Starts a tunnel process for the supervisor
+///
+/// The statuses that the tunnel reports while it's running are passed on to the status_callback, and `connected` is set
+/// when it connects. The exit status is left for the supervisor to report, once it has decided whether to reconnect.
+fn start_attempt<T, F>(
+    config: SshConfig,
+    status_callback: Arc<Mutex<F>>,
+    connected: Arc<AtomicBool>,
+) -> Result<(SshTunnel<T>, SshHandle)>
+where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    let callback = Arc::new(Mutex::new(move |status: SshStatus| match status {
+        SshStatus::Connected | SshStatus::PortsAssigned(_) | SshStatus::Degraded(_) => {
+            if status == SshStatus::Connected {
+                connected.store(true, Ordering::SeqCst);
+            }
+            call_status_callback(status_callback.clone(), status);
+        }
+        _ => log::debug!("Supervised tunnel reported {status}"),
+    }));
+
+    crate::start_and_watch_ssh_tunnel(config, callback, false)
+}
+
+/// Waits for the watcher thread of a tunnel to return its exit status
+fn join_watcher(watcher: SshHandle) -> (SshStatus, ExitCondition) {
+    watcher.join().unwrap_or_else(|_| {
+        (
+            SshStatus::AppError("The tunnel's watcher thread panicked".to_string()),
+            ExitCondition::ProcError,
+        )
+    })
+}
+
+/// Runs the supervisor, until the tunnel exits and won't be reconnected or the supervisor is stopped
+///
+/// # Returns
+///
+/// Returns a tuple containing the final SshStatus (which is also reported to the status_callback) and the ExitCondition
+/// of the last process.
+fn supervise_loop<T, F>(
+    supervisor: Supervisor<T>,
+    mut config: SshConfig,
+    policy: ReconnectPolicy,
+    health_check: Option<HealthCheck>,
+    status_callback: Arc<Mutex<F>>,
+    watcher: SshHandle,
+    connected: Arc<AtomicBool>,
+) -> (SshStatus, ExitCondition)
+where
+    T: ChildProc + Send + 'static,
+    F: FnMut(SshStatus) + Send + 'static,
+{
+    let mut ever_connected = false;
+    let mut attempt = 0;
+    // The status that the tunnel exited with before the current run of attempts
+    let mut dropped_status = None;
+    let mut outcome = join_watcher(watcher);
+
+    loop {
+        let (mut status, exit_cond) = outcome;
+        if connected.swap(false, Ordering::SeqCst) {
+            ever_connected = true;
+            attempt = 0;
+            dropped_status = None;
+        }
+
+        let mut state = supervisor.lock();
+        // Forwards may have been added or cancelled while the tunnel was running, and the next process should keep them
+        if let Some(tunnel) = state.tunnel.take() {
+            if let Ok(tunnel) = lock_tunnel(&tunnel) {
+                config = tunnel.config().clone();
+            }
+            state.config = Some(config.clone());
+        }
+        if let Some(unhealthy) = state.unhealthy.take() {
+            status = unhealthy;
+        }
+        attempt += 1;
+        let retryable = policy.is_retryable(&status);
+        if state.stopped || !ever_connected || !retryable || !policy.allows(attempt) {
+            let status = match dropped_status {
+                Some(dropped) if retryable && !state.stopped => dropped,
+                _ => status,
+            };
+            drop(state);
+            log::info!("Supervisor finished with {status}");
+            call_status_callback(status_callback, status.clone());
+            return (status, exit_cond);
+        }
+        dropped_status.get_or_insert(status);
+
+        let delay = policy.delay(attempt);
+        log::info!("Reconnecting in {delay:?} (attempt {attempt})");
+        drop(state);
+        call_status_callback(
+            status_callback.clone(),
+            SshStatus::Reconnecting(attempt, delay),
+        );
+
+        let (mut state, _) = supervisor
+            .shared
+            .1
+            .wait_timeout_while(supervisor.lock(), delay, |state| !state.stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.stopped {
+            drop(state);
+            call_status_callback(status_callback, SshStatus::Ready);
+            return (SshStatus::Ready, ExitCondition::Canceled);
+        }
+
+        // The lock is held while the process starts, so that a stop can't miss it
+        outcome = match start_attempt(config.clone(), status_callback.clone(), connected.clone()) {
+            Ok((tunnel, watcher)) => {
+                state.tunnel = Some(tunnel.clone());
+                drop(state);
+                if let Some(check) = &health_check {
+                    health::spawn_monitor(
+                        check.clone(),
+                        supervisor.clone(),
+                        tunnel,
+                        connected.clone(),
+                        status_callback.clone(),
+                    );
+                }
+                join_watcher(watcher)
+            }
+            Err(err) => {
+                log::error!("Failed to restart tunnel: {err}");
+                (err.to_status(), ExitCondition::ProcError)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{is_retryable, ReconnectPolicy};
+    use crate::status::SshStatus;
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(3));
+        assert_eq!(policy.delay(2), Duration::from_secs(6));
+        assert_eq!(policy.delay(3), Duration::from_secs(12));
+        assert_eq!(policy.delay(6), Duration::from_secs(60));
+
+        let policy = ReconnectPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_secs_f64(4.8) && delay <= Duration::from_secs_f64(7.2));
+        }
+    }
+
+    #[test]
+    fn test_attempts() {
+        let policy = ReconnectPolicy::default();
+        assert!(policy.allows(5));
+        assert!(!policy.allows(6));
+
+        assert!(!ReconnectPolicy::never().allows(1));
+
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            ..Default::default()
+        };
+        assert!(policy.allows(1000));
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(is_retryable(&SshStatus::Dropped));
+        assert!(is_retryable(&SshStatus::Unreachable));
+        assert!(is_retryable(&SshStatus::Unhealthy(String::new())));
+        assert!(!is_retryable(&SshStatus::Denied));
+        assert!(!is_retryable(&SshStatus::HostKeyChanged(String::new())));
+
+        let policy = ReconnectPolicy {
+            retryable: |status| *status == SshStatus::Denied,
+            ..Default::default()
+        };
+        assert!(policy.is_retryable(&SshStatus::Denied));
+        assert!(!policy.is_retryable(&SshStatus::Dropped));
+    }
+}
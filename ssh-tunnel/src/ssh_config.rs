@@ -0,0 +1,635 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Forward, JumpHost, SshConfig};
+use crate::error::{Error, Result};
+
+/// The maximum depth of nested `Include` directives, matching the limit used by ssh
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The identity files that ssh tries by default, in the order that they are used here when a host doesn't name one
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// A single line of an OpenSSH client config, with any `Include` directives already expanded
+#[derive(Debug, Clone)]
+enum Entry {
+    /// A `Host` line, starting a block that applies to hosts that match the patterns
+    Host(Vec<String>),
+
+    /// A `Match` line. These are not supported, so their blocks are never applied.
+    Match,
+
+    /// Any other keyword (lowercased), with its arguments
+    Option(String, Vec<String>),
+}
+
+/// A host imported from an OpenSSH client config
+///
+/// This holds the settings that ssh would use for the host alias, as far as the tunnel library is concerned. Settings that
+/// aren't given in the config are [None] (or empty), and fall back on ssh's defaults.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SshHost {
+    /// The host alias, as given in the `Host` line
+    pub alias: String,
+
+    /// The real host name (`HostName`)
+    pub host_name: Option<String>,
+
+    /// The username (`User`)
+    pub user: Option<String>,
+
+    /// The ssh port (`Port`)
+    pub port: Option<u32>,
+
+    /// The identity files (`IdentityFile`), in the order that they are given
+    pub identity_files: Vec<String>,
+
+    /// The jump hosts (`ProxyJump`), in hop order. Jump hosts that are themselves aliases in the config are resolved.
+    pub jump_hosts: Vec<JumpHost>,
+
+    /// The local forwards (`LocalForward`)
+    pub local_forwards: Vec<Forward>,
+}
+
+impl SshHost {
+    /// The address that ssh connects to for this host: the `HostName` if there is one, otherwise the alias itself
+    pub fn address(&self) -> &str {
+        self.host_name.as_deref().unwrap_or(&self.alias)
+    }
+
+    /// Converts the imported host to an [SshConfig] object
+    ///
+    /// The host's local forwards are included, followed by any `extra_forwards`. If the host doesn't give a username, the
+    /// current user's name is used. If it doesn't give an identity file, the first of ssh's default identity files
+    /// (`~/.ssh/id_ed25519`, `~/.ssh/id_ecdsa` or `~/.ssh/id_rsa`) that exists is used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the resulting config isn't valid (see [SshConfig::validate]). Most commonly,
+    /// this will be because there are no forwards, or because there's no identity file.
+    pub fn to_config(&self, extra_forwards: &[Forward]) -> Result<SshConfig> {
+        let user = self.user.clone().unwrap_or_else(current_user);
+        let key_path = self
+            .identity_files
+            .first()
+            .cloned()
+            .or_else(default_identity)
+            .unwrap_or_default();
+
+        let mut builder = SshConfig::builder(self.address(), &user, &key_path);
+        for forward in self.local_forwards.iter().chain(extra_forwards) {
+            builder = builder.forward(forward.clone());
+        }
+        for jump_host in &self.jump_hosts {
+            builder = builder.jump_host(jump_host.clone());
+        }
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        builder.build()
+    }
+}
+
+/// A parsed OpenSSH client config (`~/.ssh/config`)
+///
+/// This supports the subset of the [ssh_config(5)](https://linux.die.net/man/5/ssh_config) format needed to import hosts
+/// as tunnels: `Host` blocks (with `*` and `?` wildcards and `!` negation), `HostName`, `User`, `Port`, `IdentityFile`,
+/// `ProxyJump`, `LocalForward` and `Include`. As in ssh, the first value found for each setting is the one that's used,
+/// except for `IdentityFile` and `LocalForward`, which accumulate. `Match` blocks are not supported, and are skipped.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ssh_tunnel::{error::Result, ssh_config::OpenSshConfig};
+/// # fn import() -> Result<()> {
+/// let ssh_config = OpenSshConfig::load_default()?;
+/// for alias in ssh_config.hosts() {
+///     let host = ssh_config.host(&alias);
+///     println!("{alias}: {}", host.address());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct OpenSshConfig {
+    entries: Vec<Entry>,
+}
+
+impl OpenSshConfig {
+    /// Returns the path to the user's ssh config (`~/.ssh/config`), if the home directory can be found
+    pub fn default_path() -> Option<PathBuf> {
+        dirs_next::home_dir().map(|home| home.join(".ssh").join("config"))
+    }
+
+    /// Loads the user's ssh config
+    ///
+    /// If the user has no ssh config, an empty config is returned.
+    ///
+    /// # Errors
+    ///
+    /// See [OpenSshConfig::load].
+    pub fn load_default() -> Result<Self> {
+        match OpenSshConfig::default_path() {
+            Some(path) if path.exists() => OpenSshConfig::load(path),
+            _ => Ok(OpenSshConfig::default()),
+        }
+    }
+
+    /// Loads an ssh config from the given path
+    ///
+    /// Relative paths in `Include` directives are resolved against the directory of the given file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Io] if the file (or any included file) can't be read, or an [Error::Config] if it can't be parsed.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut entries = Vec::new();
+        read_entries(path, base_dir, 0, &mut entries)?;
+        Ok(OpenSshConfig { entries })
+    }
+
+    /// Parses an ssh config from text
+    ///
+    /// Relative paths in `Include` directives are resolved against `base_dir`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] if the text (or any included file) can't be parsed.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self> {
+        let mut entries = Vec::new();
+        parse_entries(text, "<text>", base_dir, 0, &mut entries)?;
+        Ok(OpenSshConfig { entries })
+    }
+
+    /// Returns the aliases of the hosts that can be imported, in the order that they appear
+    ///
+    /// These are the host patterns of all of the `Host` lines, except for those with wildcards or negations, which match
+    /// sets of hosts instead of naming them.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Host(patterns) => Some(patterns),
+                _ => None,
+            })
+            .flatten()
+            .filter(|pattern| !pattern.contains(['*', '?', '!']))
+            .filter(|pattern| seen.insert(pattern.to_string()))
+            .cloned()
+            .collect()
+    }
+
+    /// Resolves the settings for the given host alias
+    ///
+    /// Every block that matches the alias contributes its settings, in order, as ssh does. Jump hosts that are themselves
+    /// aliases in the config are resolved to their own host names, users, ports and identity files.
+    pub fn host(&self, alias: &str) -> SshHost {
+        self.resolve(alias, true)
+    }
+
+    /// Resolves the settings for the given host alias, optionally resolving its jump hosts
+    fn resolve(&self, alias: &str, resolve_jumps: bool) -> SshHost {
+        let mut host = SshHost {
+            alias: alias.to_string(),
+            host_name: None,
+            user: None,
+            port: None,
+            identity_files: Vec::new(),
+            jump_hosts: Vec::new(),
+            local_forwards: Vec::new(),
+        };
+        let mut proxy_jump: Option<String> = None;
+
+        // Options before the first Host line apply to all hosts
+        let mut applies = true;
+        for entry in &self.entries {
+            let (keyword, args) = match entry {
+                Entry::Host(patterns) => {
+                    applies = host_matches(alias, patterns);
+                    continue;
+                }
+                Entry::Match => {
+                    applies = false;
+                    continue;
+                }
+                Entry::Option(keyword, args) if applies => (keyword.as_str(), args),
+                Entry::Option(..) => continue,
+            };
+
+            let arg = match args.first() {
+                Some(arg) => arg,
+                None => continue,
+            };
+
+            match keyword {
+                "hostname" if host.host_name.is_none() => {
+                    host.host_name = Some(arg.replace("%h", alias).replace("%%", "%"))
+                }
+                "user" if host.user.is_none() => host.user = Some(arg.clone()),
+                "port" if host.port.is_none() => host.port = arg.parse().ok(),
+                "identityfile" => host.identity_files.push(expand_tilde(arg)),
+                "proxyjump" if proxy_jump.is_none() => proxy_jump = Some(arg.clone()),
+                "localforward" => match parse_local_forward(args) {
+                    Some(forward) => host.local_forwards.push(forward),
+                    None => log::warn!("Skipping bad LocalForward for {alias}: {:?}", args),
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(jumps) = proxy_jump.filter(|jumps| !jumps.eq_ignore_ascii_case("none")) {
+            for spec in jumps.split(',') {
+                match spec.parse::<JumpHost>() {
+                    Ok(jump) if resolve_jumps => host.jump_hosts.push(self.resolve_jump(&jump)),
+                    Ok(jump) => host.jump_hosts.push(jump),
+                    Err(err) => log::warn!("Skipping bad ProxyJump for {alias}: {err}"),
+                }
+            }
+        }
+
+        host
+    }
+
+    /// Resolves a jump host against the config, in case it's an alias
+    ///
+    /// Settings given explicitly in the `ProxyJump` specification take precedence over those from the config.
+    fn resolve_jump(&self, jump: &JumpHost) -> JumpHost {
+        let resolved = self.resolve(jump.host(), false);
+        JumpHost::new(
+            resolved.address(),
+            jump.username().or(resolved.user.as_deref()),
+            jump.port().or(resolved.port),
+            resolved.identity_files.first().map(|f| f.as_str()),
+        )
+    }
+}
+
+/// Reads the entries from a config file
+fn read_entries(
+    path: &Path,
+    base_dir: &Path,
+    depth: usize,
+    entries: &mut Vec<Entry>,
+) -> Result<()> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| Error::Io(format!("Failed to read ssh config {}", path.display()), err))?;
+    parse_entries(&text, &path.display().to_string(), base_dir, depth, entries)
+}
+
+/// Parses the entries from the text of a config file, expanding any `Include` directives in place
+fn parse_entries(
+    text: &str,
+    name: &str,
+    base_dir: &Path,
+    depth: usize,
+    entries: &mut Vec<Entry>,
+) -> Result<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(Error::Config(format!(
+            "Too many nested includes in ssh config {name}"
+        )));
+    }
+
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = split_keyword(line);
+        let args = split_args(rest).ok_or_else(|| {
+            Error::Config(format!(
+                "Bad quoting in ssh config {name}, line {}",
+                num + 1
+            ))
+        })?;
+
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => entries.push(Entry::Host(args)),
+            "match" => {
+                log::debug!("Skipping unsupported Match block in ssh config {name}");
+                entries.push(Entry::Match)
+            }
+            "include" => {
+                for pattern in args {
+                    for path in glob(&base_dir.join(expand_tilde(&pattern))) {
+                        read_entries(&path, base_dir, depth + 1, entries)?;
+                    }
+                }
+            }
+            keyword => entries.push(Entry::Option(keyword.to_string(), args)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a config line into its keyword and the rest of the line
+///
+/// The keyword may be separated from its arguments by whitespace, or an `=` with optional whitespace.
+fn split_keyword(line: &str) -> (&str, &str) {
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim_start();
+    (keyword, rest)
+}
+
+/// Splits the arguments of a config line on whitespace, respecting double quotes
+///
+/// Returns [None] if there is an unclosed quote.
+fn split_args(rest: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+
+    for c in rest.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Some(args)
+}
+
+/// Checks whether the host alias matches a `Host` line's patterns
+///
+/// The alias matches if it matches any of the patterns, and none of the negated (`!`) patterns.
+fn host_matches(alias: &str, patterns: &[String]) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if wildcard_match(pattern, alias) {
+                return false;
+            }
+        } else if wildcard_match(pattern, alias) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Matches text against a pattern with `*` (any sequence) and `?` (any single character) wildcards
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last star swallow one more character, and try again
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands a path with wildcards in its file name into the matching paths, sorted as ssh does
+///
+/// Paths without wildcards are returned as they are, if they exist. ssh ignores includes that match nothing, so this does
+/// too.
+fn glob(path: &Path) -> Vec<PathBuf> {
+    let file_pattern = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if !file_pattern.contains(['*', '?']) {
+        return if path.is_file() {
+            vec![path.to_path_buf()]
+        } else {
+            Vec::new()
+        };
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(dir_entries) => dir_entries
+            .filter_map(io::Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .filter(|path| {
+                path.file_name()
+                    .map(|name| wildcard_match(&file_pattern, &name.to_string_lossy()))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+/// Parses the arguments of a `LocalForward` line: `[bind_address:]port host:hostport`
+///
+/// The bind address is not supported by [Forward], so it's ignored.
+fn parse_local_forward(args: &[String]) -> Option<Forward> {
+    let (listen, target) = match args {
+        [listen, target] => (listen, target),
+        _ => return None,
+    };
+
+    let (bind_address, local_port) = match listen.rsplit_once(':') {
+        Some((bind_address, port)) => (Some(bind_address), port),
+        None => (None, listen.as_str()),
+    };
+    let (to_host, remote_port) = target
+        .rsplit_once(':')
+        .or_else(|| target.rsplit_once('/'))?;
+    let to_host = to_host.trim_start_matches('[').trim_end_matches(']');
+
+    let forward = Forward::new(local_port.parse().ok()?, to_host, remote_port.parse().ok()?);
+    Some(match bind_address {
+        Some(bind_address) => {
+            forward.with_bind_address(bind_address.trim_start_matches('[').trim_end_matches(']'))
+        }
+        None => forward,
+    })
+}
+
+/// Expands a leading `~` to the user's home directory
+fn expand_tilde(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs_next::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// The name of the current user
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+/// The first of ssh's default identity files that exists
+fn default_identity() -> Option<String> {
+    let ssh_dir = dirs_next::home_dir()?.join(".ssh");
+    DEFAULT_IDENTITIES
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{wildcard_match, OpenSshConfig};
+    use crate::config::{Forward, JumpHost};
+
+    const CONFIG: &str = r#"
+# Global settings
+User nobody
+
+Host bastion
+    HostName bastion.example.com
+    User alice
+    Port 2222
+    IdentityFile /keys/bastion
+
+Host db db-replica
+    HostName=%h.internal
+    ProxyJump bastion
+    IdentityFile "/keys/my db"
+    LocalForward 5432 localhost:5432
+    LocalForward 127.0.0.2:6379 [::1]:6379
+
+Host *.internal !secret.internal
+    Port 2200
+
+Host *
+    User fallback
+    PermitLocalCommand yes
+    LocalCommand notify-send connected
+"#;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("db-*", "db-replica"));
+        assert!(wildcard_match("db?", "db1"));
+        assert!(wildcard_match("*.internal", "api.internal"));
+        assert!(!wildcard_match("db?", "db12"));
+        assert!(!wildcard_match("*.internal", "internal"));
+    }
+
+    #[test]
+    fn test_hosts() {
+        let config = OpenSshConfig::parse(CONFIG, Path::new("/nonexistent")).unwrap();
+        assert_eq!(config.hosts(), vec!["bastion", "db", "db-replica"]);
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        let config = OpenSshConfig::parse(CONFIG, Path::new("/nonexistent")).unwrap();
+
+        let bastion = config.host("bastion");
+        assert_eq!(bastion.address(), "bastion.example.com");
+        // The global setting comes first, so it wins
+        assert_eq!(bastion.user.as_deref(), Some("nobody"));
+        assert_eq!(bastion.port, Some(2222));
+
+        let db = config.host("db-replica");
+        assert_eq!(db.address(), "db-replica.internal");
+        assert_eq!(db.port, None);
+        assert_eq!(db.identity_files, vec!["/keys/my db"]);
+        assert_eq!(
+            db.jump_hosts,
+            vec![JumpHost::new(
+                "bastion.example.com",
+                Some("nobody"),
+                Some(2222),
+                Some("/keys/bastion")
+            )]
+        );
+        assert_eq!(
+            db.local_forwards,
+            vec![
+                Forward::new(5432, "localhost", 5432),
+                Forward::new(6379, "::1", 6379).with_bind_address("127.0.0.2")
+            ]
+        );
+        assert_eq!(db.local_forwards[1].to_spec(), "127.0.0.2:6379:[::1]:6379");
+
+        let config = db.to_config(&[]).unwrap();
+        assert_eq!(config.end_host(), "db-replica.internal");
+        assert_eq!(config.forwards().len(), 2);
+        // The host's LocalCommand isn't imported, since it would override the library's own
+        let args = config.to_args();
+        assert!(!args.iter().any(|arg| arg.contains("notify-send")));
+    }
+
+    #[test]
+    fn test_wildcard_hosts() {
+        let config = OpenSshConfig::parse(CONFIG, Path::new("/nonexistent")).unwrap();
+        assert_eq!(config.host("api.internal").port, Some(2200));
+        assert_eq!(config.host("secret.internal").port, None);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("ssh-tunnel-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("config.d")).unwrap();
+        fs::write(
+            dir.join("config.d").join("10-web"),
+            "Host web\n  Port 8022\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("config.d").join("20-api"),
+            "Host api\n  Port 9022\n",
+        )
+        .unwrap();
+        fs::write(dir.join("config"), "Include config.d/*\nHost last\n").unwrap();
+
+        let config = OpenSshConfig::load(dir.join("config")).unwrap();
+        assert_eq!(config.hosts(), vec!["web", "api", "last"]);
+        assert_eq!(config.host("api").port, Some(9022));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
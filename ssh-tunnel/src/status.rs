@@ -1,22 +1,32 @@
 use num_derive::FromPrimitive;
 use regex::Regex;
 use std::fmt;
-use std::result;
+use std::time::{Duration, SystemTime};
 
-/// Standard [std::result::Result] type for most ssh-tunnel functions.
+use crate::config::{JumpHost, NOT_PINNED_MARKER};
+use crate::preflight::PreflightFailure;
+use crate::probe::ProbeReport;
+
+/// The library's former [std::result::Result] type
 ///
-/// We're following the [std::io::Result] practice here, which trades specificity for readability. Since the vast majority of
-/// the functions in this library return [result::Result<T, SshStatus>] values, this seems like a valid trade-off.
-pub type Result<T> = result::Result<T, SshStatus>;
+/// The library's functions now return [Error](crate::error::Error) values, which carry the [SshStatus] of ssh failures, so
+/// this is kept as an alias of [crate::error::Result] for existing code.
+#[deprecated(note = "Use ssh_tunnel::error::Result instead")]
+pub type Result<T> = crate::error::Result<T>;
 
 /// Defines the set of statuses that ssh tunnel can have
 ///
+/// This is the state of the tunnel, as shown to the user. Errors returned by the library's functions are
+/// [Error](crate::error::Error) values, which can be converted to the status that represents them.
+///
 /// Each state is considered either a **Success** state (if the system is working properly), an **Error** state if it's not,
-/// or a **Transition** state, if it's in the process of changing states (essentially, while it's waiting to connect). These
-/// categories are merely for conceptual purposes, they are not enforced in any way.
+/// or a **Transition** state, if it's in the process of changing states (essentially, while it's waiting to connect). A
+/// **Warning** state reports a problem that doesn't bring the tunnel down. These categories are merely for conceptual
+/// purposes, they are not enforced in any way.
 ///
 /// The transition states are not used internally in the library, but are provided as utility states for client applications.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SshStatus {
     /// The tunnel is ready to connect. It has either never connected, or it has disconnected cleanly.
     ///
@@ -33,6 +43,33 @@ pub enum SshStatus {
     /// This is a **Success** state
     Connected,
 
+    /// The library has picked free local ports for the forwards that asked for [any port](crate::config::ANY_PORT). Gives
+    /// each of those forwards (as its ssh cli option) along with its picked port, in the order of the forwards (see
+    /// [SshConfig::assign_free_ports](crate::config::SshConfig::assign_free_ports)).
+    ///
+    /// This is a **Transition** state, reported when the tunnel process starts, before it connects.
+    PortsAssigned(Vec<(String, u32)>),
+
+    /// ssh reported a problem while the tunnel is running, but the tunnel is still up. This is most often a forwarded
+    /// connection that failed because the service on the other end of it is down. Gives ssh's message.
+    ///
+    /// This is a **Warning** state, reported while the tunnel is connected. The tunnel stays connected afterwards.
+    Degraded(String),
+
+    /// The tunnel is connected, but its [health check](crate::health::HealthCheck) can't reach the service behind it. Gives
+    /// the reason that the check failed. The tunnel is reported as [SshStatus::Connected] again once the check passes.
+    ///
+    /// This is a **Warning** state, reported by a [Supervisor](crate::reconnect::Supervisor) while the tunnel is connected.
+    Unhealthy(String),
+
+    /// The tunnel's [health check](crate::health::HealthCheck) passed. Gives the probe's report, with how long the service
+    /// took to answer and the version it reported.
+    ///
+    /// This is a **Success** state, reported by a [Supervisor](crate::reconnect::Supervisor) when the check first passes, and
+    /// again when it passes after the tunnel was [SshStatus::Unhealthy] (following an [SshStatus::Connected]). It doesn't
+    /// change the state of the tunnel.
+    Healthy(ProbeReport),
+
     /// The server is unreachable
     ///
     /// This is an **Error** state
@@ -43,15 +80,86 @@ pub enum SshStatus {
     /// This is an **Error** state
     Denied,
 
+    /// The server refused the connection (nothing is listening on the ssh port)
+    ///
+    /// This is an **Error** state
+    Refused,
+
+    /// The tunnel didn't finish connecting within its [connect timeout](crate::config::SshConfig::connect_timeout). The
+    /// server was reached, but it stalled while setting up the session.
+    ///
+    /// This is an **Error** state
+    TimedOut,
+
+    /// The server's host key doesn't match the one in the known hosts file (or isn't one of the
+    /// [pinned](crate::config::HostKeyPolicy::Pinned) keys), which could mean that the connection is being intercepted.
+    /// Gives the fingerprint of the key that the server sent, if ssh reported it.
+    ///
+    /// This is an **Error** state
+    HostKeyChanged(String),
+
+    /// The server's host key couldn't be verified, most likely because it isn't in the known hosts file and ssh was told not
+    /// to add new keys
+    ///
+    /// This is an **Error** state
+    HostKeyVerificationFailed,
+
+    /// The server's key wasn't known, and the user didn't trust it when asked (see [host_keys](crate::host_keys)). Gives the
+    /// fingerprint of the key.
+    ///
+    /// This is an **Error** state
+    HostKeyRejected(String),
+
+    /// ssh ignored the identity file because other users can access it. Gives the path of the key file.
+    ///
+    /// This is an **Error** state
+    UnprotectedKey(String),
+
+    /// The server disconnected after too many failed authentication attempts, usually because the ssh agent offered too many
+    /// keys before the right one
+    ///
+    /// This is an **Error** state
+    TooManyAuthFailures,
+
+    /// A jump host is unreachable. Gives the number of the hop (counting from 1) and its address.
+    ///
+    /// This is an **Error** state
+    JumpUnreachable(usize, String),
+
+    /// A jump host has denied access. Gives the number of the hop (counting from 1) and its address.
+    ///
+    /// This is an **Error** state
+    JumpDenied(usize, String),
+
     /// The tunnel has dropped
     ///
     /// This is an **Error** state
     Dropped,
 
-    /// The tunnel is trying to reconnect
+    /// One of the tunnel's port forwards failed to bind to the given local port
     ///
-    /// This is a **Transition** state
-    Reconnecting,
+    /// This is an **Error** state
+    ForwardFailed(u32),
+
+    /// One of the tunnel's port forwards failed to bind to the given local port, because something else is already using it
+    ///
+    /// This is an **Error** state
+    PortInUse(u32),
+
+    /// The server (or a jump host) doesn't allow port forwarding
+    ///
+    /// This is an **Error** state
+    ForwardProhibited,
+
+    /// One of the tunnel's remote port forwards could not listen on the given port of the end host
+    ///
+    /// This is an **Error** state
+    RemoteForwardFailed(u32),
+
+    /// The tunnel is trying to reconnect. Gives the number of the attempt (counting from 1) and the delay before it's made.
+    ///
+    /// This is a **Transition** state, reported by a [Supervisor](crate::reconnect::Supervisor).
+    Reconnecting(u32, Duration),
 
     /// An unknown ssh error
     ///
@@ -63,6 +171,12 @@ pub enum SshStatus {
     /// This is an **Error** state
     ConfigError(String),
 
+    /// A preflight check failed, so the tunnel process was never started (see [preflight](crate::preflight)). Like
+    /// [SshStatus::ConfigError], this is a problem with the configuration, but it says exactly what the problem is.
+    ///
+    /// This is an **Error** state
+    PreflightFailed(PreflightFailure),
+
     /// There was an error with the process
     ///
     /// This is an **Error** state
@@ -74,28 +188,219 @@ fn stderr_is_dropped(msg: &str) -> bool {
     let re = Regex::new("Timeout, server .* not responding")
         .expect("This should not happen: invalid regex expression");
 
-    re.is_match(msg) || msg.contains("Connection reset")
+    re.is_match(msg) || msg.contains("Connection reset") || msg.contains("Broken pipe")
+}
+
+/// Checks whether a line that ssh printed while the tunnel is running reports a failed forwarded connection, and returns
+/// the reason if it does
+///
+/// A local or dynamic forward whose target can't be reached is reported as `channel 2: open failed: connect failed:
+/// Connection refused`, and a remote forward whose local target can't be reached as `connect_to localhost port 8080:
+/// failed.`.
+fn stderr_channel_failure(line: &str) -> Option<String> {
+    let re = Regex::new(r"channel \d+: open failed: (.*)|(connect_to .* port \d+: failed)")
+        .expect("This should not happen: invalid regex expression");
+
+    re.captures(line)
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|reason| reason.as_str().trim_end_matches('.').to_string())
+}
+
+/// Checks whether the stderr message means that the server accepted the connection, but stalled before setting up the
+/// session
+fn stderr_is_timed_out(msg: &str) -> bool {
+    msg.contains("timed out during banner exchange")
 }
 
 /// Checks whether the stderr message means that the server is unreachable
 fn stderr_is_unreachable(msg: &str) -> bool {
     msg.contains("timed out")
         || msg.contains("Network is unreachable")
+        || msg.contains("No route to host")
         || msg.contains("Unknown error")  // This error message sucks to handle here, but here we are
         || msg.contains("Could not resolve hostname")
 }
 
+/// Checks whether the stderr message means that the server refused the connection
+fn stderr_is_refused(msg: &str) -> bool {
+    let re = Regex::new(r"connect to host \S+ port \d+: Connection refused")
+        .expect("This should not happen: invalid regex expression");
+
+    re.is_match(msg)
+}
+
+/// Checks whether the stderr message means that the server has denied access
+///
+/// This only matches authentication failures, not the "Permission denied" errors that ssh reports for other reasons (such as
+/// failing to bind a privileged port).
+fn stderr_is_denied(msg: &str) -> bool {
+    msg.contains("Permission denied (") || msg.contains("Permission denied, please try again")
+}
+
+/// Checks whether the stderr message means that the server's host key has changed (or isn't pinned), and returns the
+/// fingerprint of the new key (or an empty string, if ssh didn't report it) if it has
+///
+/// A key that isn't pinned is only reported along with its fingerprint, since the pinning command may have been run
+/// without one (while ssh ordered its host key algorithms), whether or not the key turned out to be pinned.
+fn stderr_host_key_changed(msg: &str) -> Option<String> {
+    let not_pinned = Regex::new(&format!(
+        r"{} (SHA256:[A-Za-z0-9+/]{{43}})",
+        regex::escape(NOT_PINNED_MARKER)
+    ))
+    .expect("This should not happen: invalid regex expression");
+    if let Some(captures) = not_pinned.captures(msg) {
+        return Some(captures[1].to_string());
+    }
+    if !msg.contains("REMOTE HOST IDENTIFICATION HAS CHANGED") {
+        return None;
+    }
+
+    let re = Regex::new(r"SHA256:[A-Za-z0-9+/]{43}|MD5(?::[0-9a-f]{2}){16}")
+        .expect("This should not happen: invalid regex expression");
+
+    Some(
+        re.find(msg)
+            .map(|fingerprint| fingerprint.as_str().to_string())
+            .unwrap_or_default(),
+    )
+}
+
+/// Checks whether the stderr message means that the identity file was ignored for being too open, and returns its path if it
+/// was
+fn stderr_unprotected_key(msg: &str) -> Option<String> {
+    if !msg.contains("UNPROTECTED PRIVATE KEY FILE") {
+        return None;
+    }
+
+    let re = Regex::new(r"Permissions \d+ for '([^']+)' are too open")
+        .expect("This should not happen: invalid regex expression");
+
+    Some(
+        re.captures(msg)
+            .and_then(|caps| caps.get(1))
+            .map(|path| path.as_str().to_string())
+            .unwrap_or_default(),
+    )
+}
+
+/// Checks whether the stderr message means that a port forward failed to bind because the port is in use, and returns the
+/// port if it did
+///
+/// Newer versions of ssh give the address and port in the `bind` error, but older versions only give the port in the
+/// following `cannot listen` error.
+fn stderr_port_in_use(msg: &str) -> Option<u32> {
+    if !msg.contains("Address already in use") {
+        return None;
+    }
+
+    let re = Regex::new(r"bind \[[^\]]*\]:(\d+): Address already in use")
+        .expect("This should not happen: invalid regex expression");
+
+    re.captures(msg)
+        .and_then(|caps| caps.get(1))
+        .and_then(|port| port.as_str().parse().ok())
+        .or_else(|| stderr_forward_failure(msg))
+}
+
+/// Checks whether the stderr message means that a port forward failed to bind, and returns the failed port if it did
+fn stderr_forward_failure(msg: &str) -> Option<u32> {
+    let re = Regex::new(r"cannot listen to port: (\d+)")
+        .expect("This should not happen: invalid regex expression");
+
+    re.captures(msg)
+        .and_then(|caps| caps.get(1))
+        .and_then(|port| port.as_str().parse().ok())
+}
+
+/// Checks whether the stderr message means that a remote port forward failed, and returns the failed port if it did
+fn stderr_remote_forward_failure(msg: &str) -> Option<u32> {
+    let re = Regex::new(r"remote port forwarding failed for listen port (\d+)")
+        .expect("This should not happen: invalid regex expression");
+
+    re.captures(msg)
+        .and_then(|caps| caps.get(1))
+        .and_then(|port| port.as_str().parse().ok())
+}
+
+/// Checks whether the stderr message means that the given host is unreachable
+fn stderr_host_is_unreachable(msg: &str, host: &str) -> bool {
+    let host = regex::escape(host);
+    let re = Regex::new(&format!(
+        "connect to host {host} port \\d+|Could not resolve hostname {host}:"
+    ))
+    .expect("This should not happen: invalid regex expression");
+
+    re.is_match(msg)
+}
+
+/// Checks whether the stderr message means that the given host has denied access
+fn stderr_host_is_denied(msg: &str, host: &str) -> bool {
+    msg.contains(&format!("@{host}: Permission denied"))
+}
+
+/// Checks whether the stderr message means that a jump host failed to connect to the next hop, and returns the number of
+/// the hop that couldn't be reached (counting from 1, so the end host is one past the last jump host) if it does
+///
+/// The connection to each hop after the first is forwarded by the hop before it, which doesn't name the next hop when it
+/// can't reach it: it reports `channel 0: open failed: connect failed: <reason>` and `stdio forwarding failed`. Each ssh
+/// process from the unreachable hop onwards then reports that its connection closed during the identification exchange,
+/// so the number of those messages tells which hop it was.
+fn stderr_unreachable_hop(msg: &str, jump_count: usize) -> Option<usize> {
+    if !msg.contains("open failed: connect failed") || !msg.contains("stdio forwarding failed") {
+        return None;
+    }
+
+    // Older versions call it `ssh_exchange_identification`
+    let closed = msg.matches("exchange_identification: ").count();
+    (1..=jump_count)
+        .contains(&closed)
+        .then(|| jump_count + 2 - closed)
+}
+
 impl SshStatus {
+    /// Classifies a single line that ssh printed to stderr while the tunnel is running
+    ///
+    /// Returns [SshStatus::Degraded] if the line reports a problem that doesn't bring the tunnel down, or [None] if it
+    /// doesn't. Failures that end the tunnel are left for [SshStatus::from_stderr] to classify once the process exits.
+    ///
+    /// Only lines printed after the tunnel has connected should be classified this way: before that, a failure to open a
+    /// channel is a jump host failing to reach the next hop (see [SshStatus::from_stderr_with_jumps]).
+    pub fn from_runtime_stderr(line: &str) -> Option<Self> {
+        stderr_channel_failure(line).map(SshStatus::Degraded)
+    }
+
     /// Parses the stderr captured during the ssh process and parses it into an SshStatus
     pub fn from_stderr(msg: &str) -> Self {
+        // The more specific failures come first, since ssh often follows them up with more general ones (an unprotected key
+        // is ignored, which then leads to "Permission denied", for instance)
         if msg.is_empty() {
             SshStatus::Ready
+        } else if let Some(fingerprint) = stderr_host_key_changed(msg) {
+            SshStatus::HostKeyChanged(fingerprint)
+        } else if msg.contains("Host key verification failed") {
+            SshStatus::HostKeyVerificationFailed
+        } else if let Some(key_path) = stderr_unprotected_key(msg) {
+            SshStatus::UnprotectedKey(key_path)
+        } else if msg.contains("Too many authentication failures") {
+            SshStatus::TooManyAuthFailures
+        } else if msg.contains("administratively prohibited") {
+            SshStatus::ForwardProhibited
         } else if stderr_is_dropped(msg) {
             SshStatus::Dropped
+        } else if stderr_is_refused(msg) {
+            SshStatus::Refused
+        } else if stderr_is_timed_out(msg) {
+            SshStatus::TimedOut
         } else if stderr_is_unreachable(msg) {
             SshStatus::Unreachable
-        } else if msg.contains("Permission denied") || msg.contains("Connection refused") {
+        } else if stderr_is_denied(msg) {
             SshStatus::Denied
+        } else if let Some(port) = stderr_remote_forward_failure(msg) {
+            SshStatus::RemoteForwardFailed(port)
+        } else if let Some(port) = stderr_port_in_use(msg) {
+            SshStatus::PortInUse(port)
+        } else if let Some(port) = stderr_forward_failure(msg) {
+            SshStatus::ForwardFailed(port)
         } else if msg.contains("Bad local forwarding specification") {
             SshStatus::ConfigError(msg.to_string())
         } else {
@@ -104,6 +409,30 @@ impl SshStatus {
         }
     }
 
+    /// Parses the stderr captured during the ssh process into an SshStatus, checking for failures at any of the jump hosts
+    ///
+    /// When a tunnel hops through [jump hosts](JumpHost), ssh reports most failures at each hop with the hop's address, so
+    /// those are checked first to find the hop that failed. A hop that the previous hop can't reach isn't named, so it is
+    /// worked out from the shape of the message. Otherwise, the message is parsed with [SshStatus::from_stderr].
+    pub fn from_stderr_with_jumps(msg: &str, jump_hosts: &[JumpHost]) -> Self {
+        for (i, hop) in jump_hosts.iter().enumerate() {
+            if stderr_host_is_unreachable(msg, hop.host()) {
+                return SshStatus::JumpUnreachable(i + 1, hop.host().to_string());
+            } else if stderr_host_is_denied(msg, hop.host()) {
+                return SshStatus::JumpDenied(i + 1, hop.host().to_string());
+            }
+        }
+
+        if let Some(hop) = stderr_unreachable_hop(msg, jump_hosts.len()) {
+            return match jump_hosts.get(hop - 1) {
+                Some(jump_host) => SshStatus::JumpUnreachable(hop, jump_host.host().to_string()),
+                None if msg.contains("connect failed: Connection refused") => SshStatus::Refused,
+                None => SshStatus::Unreachable,
+            };
+        }
+        SshStatus::from_stderr(msg)
+    }
+
     /// Converts the status to a "signal" string for status event signaling
     ///
     /// This is not used internally in the library, but it provides a standard set of signals for client applications to use.
@@ -112,10 +441,46 @@ impl SshStatus {
             SshStatus::Ready => "READY".to_string(),
             SshStatus::Connecting => "CONNECTING".to_string(),
             SshStatus::Connected => "CONNECTED".to_string(),
+            SshStatus::PortsAssigned(ports) => {
+                let ports: Vec<String> = ports
+                    .iter()
+                    .map(|(forward, port)| format!("{port} ({forward})"))
+                    .collect();
+                format!("PORTS_ASSIGNED: {}", ports.join(","))
+            }
+            SshStatus::Degraded(msg) => {
+                log::warn!("Tunnel degraded: {msg}");
+                format!("DEGRADED: {msg}")
+            }
+            SshStatus::Unhealthy(reason) => {
+                log::warn!("Tunnel unhealthy: {reason}");
+                format!("UNHEALTHY: {reason}")
+            }
+            SshStatus::Healthy(report) => match (&report.version, report.answered) {
+                (Some(version), _) => {
+                    format!("HEALTHY: {}ms {version}", report.latency.as_millis())
+                }
+                (None, true) => format!("HEALTHY: {}ms", report.latency.as_millis()),
+                (None, false) => format!("HEALTHY: {}ms (no banner)", report.latency.as_millis()),
+            },
             SshStatus::Unreachable => "UNREACHABLE".to_string(),
             SshStatus::Denied => "DENIED".to_string(),
+            SshStatus::Refused => "REFUSED".to_string(),
+            SshStatus::TimedOut => "TIMED_OUT".to_string(),
+            SshStatus::HostKeyChanged(fingerprint) => format!("HOST_KEY_CHANGED: {fingerprint}"),
+            SshStatus::HostKeyVerificationFailed => "HOST_KEY_UNVERIFIED".to_string(),
+            SshStatus::HostKeyRejected(fingerprint) => format!("HOST_KEY_REJECTED: {fingerprint}"),
+            SshStatus::UnprotectedKey(key_path) => format!("UNPROTECTED_KEY: {key_path}"),
+            SshStatus::TooManyAuthFailures => "TOO_MANY_AUTH_FAILURES".to_string(),
+            SshStatus::JumpUnreachable(hop, host) => format!("JUMP_UNREACHABLE: {hop} {host}"),
+            SshStatus::JumpDenied(hop, host) => format!("JUMP_DENIED: {hop} {host}"),
             SshStatus::Dropped => "DROPPED".to_string(),
-            SshStatus::Reconnecting => "RETRYING".to_string(),
+            SshStatus::ForwardFailed(port) => format!("FORWARD_FAILED: {port}"),
+            SshStatus::PortInUse(port) => format!("PORT_IN_USE: {port}"),
+            SshStatus::ForwardProhibited => "FORWARD_PROHIBITED".to_string(),
+            SshStatus::RemoteForwardFailed(port) => format!("REMOTE_FORWARD_FAILED: {port}"),
+            // The attempt is left out of the signal, so the front end sees a single reconnecting state
+            SshStatus::Reconnecting(_, _) => "RETRYING".to_string(),
             SshStatus::Unknown(msg) => {
                 log::error!("Unknown error: {msg}");
                 format!("UNKNOWN: {msg}")
@@ -124,6 +489,10 @@ impl SshStatus {
                 log::error!("Config error: {msg}");
                 format!("BAD_CONFIG: {msg}")
             }
+            SshStatus::PreflightFailed(failure) => {
+                log::error!("Preflight check failed: {failure}");
+                format!("BAD_CONFIG: {failure}")
+            }
             SshStatus::AppError(msg) => {
                 log::error!("App error: {msg}");
                 format!("ERROR: {msg}")
@@ -139,11 +508,39 @@ impl fmt::Display for SshStatus {
     }
 }
 
+/// A status reported by a tunnel, as delivered by [start_and_watch_ssh_tunnel_with_events](crate::start_and_watch_ssh_tunnel_with_events)
+///
+/// Several tunnels can report to the same channel, so each event says which tunnel it came from.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatusEvent {
+    /// The id of the tunnel that reported the status. Ids are unique within the process.
+    pub tunnel_id: u64,
+
+    /// The reported status
+    pub status: SshStatus,
+
+    /// When the status was reported
+    pub timestamp: SystemTime,
+}
+
+impl StatusEvent {
+    /// Creates an event for a status that the tunnel has just reported
+    pub fn new(tunnel_id: u64, status: SshStatus) -> Self {
+        StatusEvent {
+            tunnel_id,
+            status,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
 /// Defines the set of exit conditions for the tunnel process
 ///
 /// These are minimally useful. In most cases, the [SshStatus], parsed from the child's stderr will provide all of the
 /// necessary information.
 #[derive(FromPrimitive, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ExitCondition {
     /// The tunnel exited cleanly. This actually will only happen if something
     /// goes wrong. A successful tunnel must be killed, which will result in
@@ -159,3 +556,136 @@ pub enum ExitCondition {
     /// No exit code was returned, probably because the process was canceled
     Canceled = -1,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SshStatus;
+    use crate::config::JumpHost;
+
+    #[test]
+    fn test_forward_failure() {
+        let msg = "bind [127.0.0.1]:6379: Address already in use\
+                   channel_setup_fwd_listener_tcpip: cannot listen to port: 6379\
+                   Could not request local forwarding.";
+        assert_eq!(SshStatus::from_stderr(msg), SshStatus::PortInUse(6379));
+
+        // Binding a privileged port is denied locally, which must not be mistaken for the server denying access
+        let msg = "bind [127.0.0.1]:80: Permission denied\
+                   channel_setup_fwd_listener_tcpip: cannot listen to port: 80\
+                   Could not request local forwarding.";
+        assert_eq!(SshStatus::from_stderr(msg), SshStatus::ForwardFailed(80));
+    }
+
+    #[test]
+    fn test_remote_forward_failure() {
+        let msg = "Error: remote port forwarding failed for listen port 9000";
+        assert_eq!(
+            SshStatus::from_stderr(msg),
+            SshStatus::RemoteForwardFailed(9000)
+        );
+    }
+
+    #[test]
+    fn test_jump_failures() {
+        let hops = vec![
+            JumpHost::new("bastion1", Some("alice"), None, None),
+            JumpHost::new("bastion2", Some("alice"), None, None),
+        ];
+
+        let msg = "ssh: connect to host bastion1 port 22: Connection timed out\
+                   kex_exchange_identification: Connection closed by remote host\
+                   Connection closed by UNKNOWN port 65535\
+                   kex_exchange_identification: Connection closed by remote host\
+                   Connection closed by UNKNOWN port 65535";
+        assert_eq!(
+            SshStatus::from_stderr_with_jumps(msg, &hops),
+            SshStatus::JumpUnreachable(1, "bastion1".to_string())
+        );
+
+        // bastion1 can't reach bastion2, so the connections to bastion2 and to the end host both close
+        let msg = "channel 0: open failed: connect failed: Connection timed out\
+                   stdio forwarding failed\
+                   kex_exchange_identification: Connection closed by remote host\
+                   Connection closed by UNKNOWN port 65535\
+                   kex_exchange_identification: Connection closed by remote host\
+                   Connection closed by UNKNOWN port 65535";
+        assert_eq!(
+            SshStatus::from_stderr_with_jumps(msg, &hops),
+            SshStatus::JumpUnreachable(2, "bastion2".to_string())
+        );
+
+        // bastion2 can't reach the end host
+        let msg = "channel 0: open failed: connect failed: Connection refused\
+                   stdio forwarding failed\
+                   kex_exchange_identification: Connection closed by remote host\
+                   Connection closed by UNKNOWN port 65535";
+        assert_eq!(
+            SshStatus::from_stderr_with_jumps(msg, &hops),
+            SshStatus::Refused
+        );
+
+        let msg = "alice@bastion1: Permission denied (publickey).\
+                   Connection closed by UNKNOWN port 65535";
+        assert_eq!(
+            SshStatus::from_stderr_with_jumps(msg, &hops),
+            SshStatus::JumpDenied(1, "bastion1".to_string())
+        );
+
+        let msg = "alice@endhost: Permission denied (publickey).";
+        assert_eq!(
+            SshStatus::from_stderr_with_jumps(msg, &hops),
+            SshStatus::Denied
+        );
+    }
+
+    #[test]
+    fn test_runtime_stderr() {
+        let line = "channel 2: open failed: connect failed: Connection refused";
+        assert_eq!(
+            SshStatus::from_runtime_stderr(line),
+            Some(SshStatus::Degraded(
+                "connect failed: Connection refused".to_string()
+            ))
+        );
+
+        let line = "connect_to localhost port 8080: failed.";
+        assert_eq!(
+            SshStatus::from_runtime_stderr(line),
+            Some(SshStatus::Degraded(
+                "connect_to localhost port 8080: failed".to_string()
+            ))
+        );
+
+        let line = "Warning: Permanently added 'endhost' (ED25519) to the list of known hosts.";
+        assert_eq!(SshStatus::from_runtime_stderr(line), None);
+    }
+
+    #[test]
+    fn test_pinned_host_key() {
+        let fingerprint = "SHA256:EKktra0VRHZoUCDyNjy3s0c680r/jEIGe9vfSOQRf4s";
+        assert_eq!(
+            SshStatus::from_stderr(&format!(
+                "Host key is not pinned: NONE\nHost key is not pinned: {fingerprint}\nHost key verification failed."
+            )),
+            SshStatus::HostKeyChanged(fingerprint.to_string())
+        );
+
+        // A pinned tunnel that connected and then dropped, where the pinning command was also run to order the host key
+        // algorithms
+        assert_eq!(
+            SshStatus::from_stderr(
+                "Host key is not pinned: NONE\nclient_loop: send disconnect: Broken pipe"
+            ),
+            SshStatus::Dropped
+        );
+        assert_eq!(
+            SshStatus::from_stderr("client_loop: send disconnect: Broken pipe"),
+            SshStatus::Dropped
+        );
+    }
+
+    #[test]
+    fn test_clean_exit() {
+        assert_eq!(SshStatus::from_stderr(""), SshStatus::Ready);
+    }
+}
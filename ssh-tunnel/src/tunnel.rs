@@ -1,24 +1,52 @@
-use std::io::Read;
+use std::io::{self, BufRead, BufReader};
 use std::process;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 
 use num_traits::FromPrimitive;
+use shared_child::SharedChild;
 
-use crate::config::SshConfig;
-use crate::status::{ExitCondition, Result, SshStatus};
+use crate::config::{Forward, SshConfig};
+use crate::control;
+use crate::error::{Error, Result};
+use crate::preflight;
+use crate::status::{ExitCondition, SshStatus};
+
+#[cfg(target_os = "windows")]
+use crate::config::windows_quote;
 
 /// Defines the necessary interface that a child process type must support to be used by the tunnel library
 pub trait ChildProc {
+    /// Checks that a process could be started with the given config, before any ports are picked for it
+    ///
+    /// This does nothing by default. [TunnelChild] runs the [preflight] checks.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Preflight] if the process can't be started.
+    fn preflight(_config: &SshConfig) -> Result<()> {
+        Ok(())
+    }
+
     /// Spawns a new ssh child process using the given [config](SshConfig) object.
     ///
     /// # Errors
     ///
-    /// If the process fails to spawn, [SshStatus::AppError] will be returned.
+    /// If the process fails to spawn, [Error::Spawn] will be returned.
     fn new(config: SshConfig) -> Result<SshTunnel<Self>>;
 
+    /// The config that the process was started with
+    ///
+    /// Any [free ports](SshConfig::assign_free_ports) have been assigned by the time the process starts, so this gives the
+    /// ports that the tunnel is actually listening on.
+    fn config(&self) -> &SshConfig;
+
     /// Retrieves the stdout stream from the child
     ///
     /// The caller takes ownership of the stdout object, so this function may only be called once. Subsequent calls will
@@ -26,123 +54,322 @@ pub trait ChildProc {
     ///
     /// # Errors
     ///
-    /// Will return an [SshStatus::AppError] if there is a failure to get a handle to the stdout stream.
+    /// Will return an [Error::Io] if there is a failure to get a handle to the stdout stream.
     fn stdout(&mut self) -> Result<process::ChildStdout>;
 
+    /// Retrieves the stream of [events](ChildEvent) from the child
+    ///
+    /// The stream reports the warnings that ssh prints while the tunnel is running, as they arrive, and ends with the
+    /// process's exit as soon as it happens. Receiving from it doesn't need the tunnel's lock, so the watcher can block on
+    /// it without getting in the way of [ChildProc::kill] or any other use of the tunnel.
+    ///
+    /// The caller takes ownership of the receiver, so this function may only be called once. Subsequent calls will return an
+    /// [Err].
+    ///
+    /// # Errors
+    ///
+    /// Will return an [Error::Io] if the events have already been taken.
+    fn events(&mut self) -> Result<mpsc::Receiver<ChildEvent>>;
+
     /// Checks whether the process has exited. If it has, then it returns the ExitCondition.
     fn exited(&mut self) -> Option<ExitCondition>;
 
     /// Captures the exit reason from the process and returns the corresponding SshStatus.
     ///
     /// This function will consume the text from the stderr stream, so it should only be called once. Subsequent calls will
-    /// return [SshStatus::AppError].
+    /// return [SshStatus::AppError]. It should be called once the process has exited, and it doesn't block for long: if the
+    /// stream is still open (because another process, such as a `ProxyCommand`, inherited it), the text that ssh printed so
+    /// far is used.
     fn exit_status(&mut self) -> SshStatus;
 
     /// Kills the child process.
     ///
     /// This function may be called multiple times, but it will only have an effect on the first call (for obvious reasons).
     fn kill(&mut self);
+
+    /// Tells the process that the tunnel has connected
+    ///
+    /// Until then, a failure to open a channel is part of a failure to connect (it's how a jump host reports that it can't
+    /// reach the next hop), so [TunnelChild] only reports those as [warnings](ChildEvent::Warning) once it has connected.
+    /// This does nothing by default.
+    fn set_connected(&mut self) {}
+
+    /// Records a port forward that was added to the running tunnel through its ControlMaster session (see
+    /// [control::add_forward]) in the tunnel's [config](ChildProc::config), so a supervised tunnel keeps it when it
+    /// reconnects
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] by default, since a process can only have its forwards changed if it supports it.
+    fn add_forward(&mut self, _forward: Forward) -> Result<()> {
+        Err(Error::Config(
+            "The tunnel doesn't support adding forwards".to_string(),
+        ))
+    }
+
+    /// Removes a port forward that was cancelled through the running tunnel's ControlMaster session (see
+    /// [control::cancel_forward]) from the tunnel's [config](ChildProc::config)
+    ///
+    /// # Errors
+    ///
+    /// Returns an [Error::Config] by default, since a process can only have its forwards changed if it supports it.
+    fn cancel_forward(&mut self, _forward: &Forward) -> Result<()> {
+        Err(Error::Config(
+            "The tunnel doesn't support cancelling forwards".to_string(),
+        ))
+    }
 }
 
 /// A thread-safe wrapper for a tunnel process
 pub type SshTunnel<T> = Arc<Mutex<T>>;
 
+/// How long [TunnelChild::exit_status] waits for ssh's stderr to close once the process has exited
+///
+/// A `ProxyCommand` (or anything else that ssh spawned) may inherit the stream and keep it open after ssh exits.
+const STDERR_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An event reported by a running tunnel process (see [ChildProc::events])
+#[derive(Debug)]
+pub enum ChildEvent {
+    /// ssh reported a problem that doesn't bring the tunnel down. This is always an [SshStatus::Degraded].
+    Warning(SshStatus),
+
+    /// The process exited. This is always the last event.
+    Exited(ExitCondition),
+}
+
 /// Wraps the standard process::Child struct
+///
+/// Two background threads watch the child, so that nothing has to poll it: one blocks until the process exits, and the
+/// other reads its stderr continuously, classifying each line as it arrives (see [SshStatus::from_runtime_stderr]) and
+/// keeping the rest of the text for the exit status.
 pub struct TunnelChild {
-    child: process::Child,
+    child: Arc<SharedChild>,
+
+    /// The config that the process was started with
+    config: SshConfig,
+
+    /// The text that ssh has printed on its stderr so far, which the stderr reader thread adds to as it arrives
+    stderr: Arc<Mutex<StderrText>>,
+
+    /// Receives the result of the stderr reader thread once the stream closes, until it's taken by
+    /// [ChildProc::exit_status]
+    stderr_closed: Option<mpsc::Receiver<io::Result<()>>>,
+
+    /// Receives the events from the stderr reader and waiter threads, until it's taken by [ChildProc::events]
+    events: Option<mpsc::Receiver<ChildEvent>>,
+
+    /// Whether the tunnel has connected, which the stderr reader checks before reporting a line as a warning
+    connected: Arc<AtomicBool>,
+}
+
+impl TunnelChild {
+    /// Wraps a newly spawned child, and starts the threads that watch it
+    fn start(child: SharedChild, config: SshConfig) -> SshTunnel<Self> {
+        log::debug!("New child pid: {}", child.id());
+        let child = Arc::new(child);
+        let (sender, events) = mpsc::channel();
+        let connected = Arc::new(AtomicBool::new(false));
+
+        let stderr = Arc::new(Mutex::new(StderrText::default()));
+        let stderr_closed = child.take_stderr().map(|child_stderr| {
+            let (closed_sender, closed) = mpsc::channel();
+            let sender = sender.clone();
+            let text = stderr.clone();
+            let connected = connected.clone();
+            // The thread is detached: it may outlive the process, if something else holds the stream open
+            thread::spawn(move || {
+                let _ = closed_sender.send(read_stderr(child_stderr, sender, connected, text));
+            });
+            closed
+        });
+
+        let waited_child = child.clone();
+        thread::spawn(move || {
+            let exit_cond = exit_condition(waited_child.wait());
+            // The watcher may have stopped already, in which case nobody is listening
+            let _ = sender.send(ChildEvent::Exited(exit_cond));
+        });
+
+        Arc::new(Mutex::new(TunnelChild {
+            child,
+            config,
+            stderr,
+            stderr_closed,
+            events: Some(events),
+            connected,
+        }))
+    }
+}
+
+/// Converts the result of waiting for the ssh process into its ExitCondition
+fn exit_condition(result: io::Result<process::ExitStatus>) -> ExitCondition {
+    match result {
+        Ok(status) => {
+            log::debug!("Exited with {status}");
+            if let Some(code) = status.code() {
+                match FromPrimitive::from_i32(code) {
+                    Some(cond) => cond,
+                    None => {
+                        log::warn!("Unknown exit code: {code}");
+                        ExitCondition::ProcError
+                    }
+                }
+            } else {
+                ExitCondition::Canceled
+            }
+        }
+        Err(e) => {
+            log::error!("Error attempting to wait for the ssh process: {e}");
+            ExitCondition::ProcError
+        }
+    }
+}
+
+/// The text that ssh printed on its stderr, with the lines joined together
+#[derive(Debug, Default)]
+struct StderrText {
+    /// The lines that weren't reported as warnings, which explain the exit
+    exit_msg: String,
+
+    /// All of the lines
+    full_msg: String,
+}
+
+/// Reads the stderr of the ssh process until it closes
+///
+/// Once the tunnel has connected, each line that reports a runtime problem is sent as a warning as soon as it arrives. The
+/// lines (apart from the noise about adding hosts to the known hosts file) are added to the text as they arrive, both
+/// without and with the warnings, to be classified as the exit status.
+fn read_stderr(
+    stderr: process::ChildStderr,
+    sender: mpsc::Sender<ChildEvent>,
+    connected: Arc<AtomicBool>,
+    text: Arc<Mutex<StderrText>>,
+) -> io::Result<()> {
+    for line in BufReader::new(stderr).split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).trim_end().to_string();
+        if line.contains("Warning: Permanently added") {
+            continue;
+        }
+        log::debug!("stderr: {line}");
+
+        let warning = if connected.load(Ordering::SeqCst) {
+            SshStatus::from_runtime_stderr(&line)
+        } else {
+            None
+        };
+        let mut text = text.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(warning) = warning {
+            // The watcher may have stopped already, in which case nobody is listening
+            let _ = sender.send(ChildEvent::Warning(warning));
+        } else {
+            text.exit_msg.push_str(&line);
+        }
+        text.full_msg.push_str(&line);
+    }
+    Ok(())
 }
 
 impl ChildProc for TunnelChild {
+    fn preflight(config: &SshConfig) -> Result<()> {
+        preflight::preflight(config)
+    }
+
     // On non-windows platforms, the arguments are give directly.
     #[cfg(not(target_os = "windows"))]
     fn new(config: SshConfig) -> Result<SshTunnel<Self>> {
         log::debug!("Starting ssh process");
-        let child = process::Command::new("ssh")
-            .args(config.to_args())
+        let mut cmd = process::Command::new("ssh");
+        cmd.args(config.to_args())
             .stdout(process::Stdio::piped())
-            .stderr(process::Stdio::piped())
-            .spawn()
-            .map_err(|err| SshStatus::AppError(err.to_string()))?;
+            .stderr(process::Stdio::piped());
+        let child = SharedChild::spawn(&mut cmd).map_err(Error::Spawn)?;
 
-        log::debug!("New child address: {:p}", &child);
-        Ok(Arc::new(Mutex::new(TunnelChild { child })))
+        Ok(TunnelChild::start(child, config))
     }
 
-    // On windows, all arguments need to be given as raw args.
+    // On windows, all arguments need to be given as raw args, quoted the way that ssh splits its command line.
     #[cfg(target_os = "windows")]
     fn new(config: SshConfig) -> Result<SshTunnel<Self>> {
         let mut cmd = process::Command::new("ssh");
 
         for arg in config.to_args() {
-            cmd.raw_arg(arg);
+            cmd.raw_arg(windows_quote(&arg));
         }
 
         log::debug!("Starting ssh process");
-        let child = cmd
-            .stdout(process::Stdio::piped())
+        cmd.stdout(process::Stdio::piped())
             .stderr(process::Stdio::piped())
-            .creation_flags(0x08000000) // Suppresses terminal window - CREATE_NO_WINDOW
-            .spawn()
-            .map_err(|err| SshStatus::AppError(err.to_string()))?;
+            .creation_flags(0x08000000); // Suppresses terminal window - CREATE_NO_WINDOW
+        let child = SharedChild::spawn(&mut cmd).map_err(Error::Spawn)?;
 
-        log::debug!("New child address: {:p}", &child);
-        Ok(Arc::new(Mutex::new(TunnelChild { child })))
+        Ok(TunnelChild::start(child, config))
+    }
+
+    fn config(&self) -> &SshConfig {
+        &self.config
     }
 
     fn stdout(&mut self) -> Result<process::ChildStdout> {
-        log::debug!("Getting stdout from {:p}", &self.child);
-        if let Some(stdout) = self.child.stdout.take() {
+        log::debug!("Getting stdout from {}", self.child.id());
+        if let Some(stdout) = self.child.take_stdout() {
             Ok(stdout)
         } else {
-            Err(SshStatus::AppError(
-                "Failed to capture stdout of ssh process".to_string(),
+            Err(Error::io(
+                "Failed to capture stdout of ssh process",
+                io::Error::new(io::ErrorKind::NotFound, "stdout has already been taken"),
             ))
         }
     }
 
+    fn events(&mut self) -> Result<mpsc::Receiver<ChildEvent>> {
+        self.events.take().ok_or_else(|| {
+            Error::io(
+                "Failed to watch ssh process",
+                io::Error::new(io::ErrorKind::NotFound, "events have already been taken"),
+            )
+        })
+    }
+
     fn exited(&mut self) -> Option<ExitCondition> {
         match self.child.try_wait() {
-            Ok(Some(status)) => {
-                log::debug!("{:p} Exited with {status}", &self.child);
-                if let Some(code) = status.code() {
-                    match FromPrimitive::from_i32(code) {
-                        Some(cond) => Some(cond),
-                        None => {
-                            log::warn!("Unknown exit code: {code}");
-                            Some(ExitCondition::ProcError)
-                        }
-                    }
-                } else {
-                    Some(ExitCondition::Canceled)
-                }
-            }
+            Ok(Some(status)) => Some(exit_condition(Ok(status))),
             Ok(None) => None,
-            Err(e) => {
-                log::error!("Error attempting to wait for {:p}: {e}", &self.child);
-                Some(ExitCondition::ProcError)
-            }
+            Err(e) => Some(exit_condition(Err(e))),
         }
     }
 
     //Capture stderr to discover exit reason
     fn exit_status(&mut self) -> SshStatus {
-        log::debug!("Getting exit status from {:p}", &self.child);
-        let mut stderr = if let Some(stderr) = self.child.stderr.take() {
-            stderr
+        log::debug!("Getting exit status from {}", self.child.id());
+        let closed = if let Some(closed) = self.stderr_closed.take() {
+            closed
         } else {
             return SshStatus::AppError("Failed to capture stderr of ssh process".to_string());
         };
 
-        let mut stderr_msg = String::new();
-        if stderr.read_to_string(&mut stderr_msg).is_err() {
-            SshStatus::AppError("Failed to read from stderr".to_string())
-        } else {
-            let stderr_msg: String = stderr_msg
-                .split('\n')
-                .filter(|s| !s.contains("Warning: Permanently added") && !s.is_empty())
-                .collect();
-            log::info!("exit status: {stderr_msg}");
-            SshStatus::from_stderr(&stderr_msg)
+        match closed.recv_timeout(STDERR_CLOSE_TIMEOUT) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                return SshStatus::AppError(format!("Failed to read from stderr: {err}"))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                log::warn!("stderr is still open after the ssh process exited");
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return SshStatus::AppError("The stderr reader thread panicked".to_string())
+            }
+        }
+
+        let text = self.stderr.lock().unwrap_or_else(PoisonError::into_inner);
+        log::info!("exit status: {}", text.full_msg);
+        let jump_hosts = self.config.jump_hosts();
+        match SshStatus::from_stderr_with_jumps(&text.exit_msg, jump_hosts) {
+            // The warnings don't usually explain the exit (they were reported while the tunnel was still up), but
+            // they're the only clue when a jump host refuses to forward the connection
+            SshStatus::Unknown(_) => SshStatus::from_stderr_with_jumps(&text.full_msg, jump_hosts),
+            status => status,
         }
     }
 
@@ -152,7 +379,7 @@ impl ChildProc for TunnelChild {
     // it returns an error, but that's totally fine, since the process died anyway. I have yet to see a child process left
     // dangling after the parent dies.
     fn kill(&mut self) {
-        log::debug!("Killing {:p}", &self.child);
+        log::debug!("Killing {}", self.child.id());
         match self.child.kill() {
             Ok(_) => {
                 log::debug!("killed");
@@ -162,4 +389,222 @@ impl ChildProc for TunnelChild {
             }
         }
     }
+
+    fn set_connected(&mut self) {
+        self.connected.store(true, Ordering::SeqCst);
+    }
+
+    fn add_forward(&mut self, forward: Forward) -> Result<()> {
+        self.config.add_forward(forward);
+        Ok(())
+    }
+
+    fn cancel_forward(&mut self, forward: &Forward) -> Result<()> {
+        control::remove_forward(&mut self.config, forward);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use shared_child::SharedChild;
+
+    use super::{read_stderr, ChildEvent, ChildProc, StderrText, TunnelChild};
+    use crate::config::SshConfig;
+    use crate::status::SshStatus;
+
+    /// Reads the stderr of a shell that prints the given text, returning the warnings and the exit message
+    fn read_shell_stderr(text: &str, connected: bool) -> (Vec<SshStatus>, String) {
+        let mut child = process::Command::new("sh")
+            .args(["-c", &format!("printf '{text}' >&2")])
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let (sender, events) = mpsc::channel();
+        let stderr_text = Arc::new(Mutex::new(StderrText::default()));
+        read_stderr(
+            child.stderr.take().unwrap(),
+            sender,
+            Arc::new(AtomicBool::new(connected)),
+            stderr_text.clone(),
+        )
+        .unwrap();
+        child.wait().unwrap();
+
+        let warnings = events
+            .try_iter()
+            .map(|event| match event {
+                ChildEvent::Warning(warning) => warning,
+                ChildEvent::Exited(_) => panic!("Unexpected exit event"),
+            })
+            .collect();
+        let exit_msg = stderr_text.lock().unwrap().exit_msg.clone();
+        (warnings, exit_msg)
+    }
+
+    #[test]
+    fn test_read_stderr() {
+        let refused = "channel 2: open failed: connect failed: Connection refused\\n";
+        let (warnings, exit_msg) = read_shell_stderr(refused, true);
+        assert_eq!(
+            warnings,
+            vec![SshStatus::Degraded(
+                "connect failed: Connection refused".to_string()
+            )]
+        );
+        assert!(exit_msg.is_empty());
+
+        // Before the tunnel connects, it's a jump host that can't reach the next hop
+        let jump_failure = "channel 0: open failed: connect failed: Connection timed out\\n\
+                            stdio forwarding failed\\n";
+        let (warnings, exit_msg) = read_shell_stderr(jump_failure, false);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            exit_msg,
+            "channel 0: open failed: connect failed: Connection timed outstdio forwarding failed"
+        );
+    }
+
+    #[test]
+    fn test_exit_status_stderr_held_open() {
+        // The background process inherits stderr, and keeps it open after the shell exits
+        let mut cmd = process::Command::new("sh");
+        cmd.args([
+            "-c",
+            "echo 'ssh: connect to host endhost port 22: Connection refused' >&2; sleep 5 &",
+        ])
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+        let child = SharedChild::spawn(&mut cmd).unwrap();
+        let config = SshConfig::builder("endhost", "username", "keypath")
+            .local_forward(5432, 5432)
+            .build()
+            .unwrap();
+        let tunnel = TunnelChild::start(child, config);
+
+        let events = tunnel.lock().unwrap().events().unwrap();
+        while !matches!(events.recv().unwrap(), ChildEvent::Exited(_)) {}
+
+        let start = Instant::now();
+        assert_eq!(tunnel.lock().unwrap().exit_status(), SshStatus::Refused);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}
+
+/// A stand-in for ssh in tests, which runs a shell script instead
+#[cfg(all(test, unix))]
+pub(crate) mod mock {
+    use std::collections::BTreeMap;
+    use std::io::{self, Read};
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::{mpsc, Arc, Mutex, PoisonError};
+    use std::thread;
+
+    use shared_child::SharedChild;
+
+    use super::{ChildEvent, ChildProc, SshTunnel};
+    use crate::config::{SshConfig, READY_MARKER};
+    use crate::error::{Error, Result};
+    use crate::status::{ExitCondition, SshStatus};
+
+    /// The scripts that are run for the tunnels to each end host
+    static SCRIPTS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+    /// Makes the tunnels to the given end host run the given script, whose stderr is classified as ssh's would be
+    ///
+    /// The tunnels to any other end host print the ready marker, and then run until they're killed.
+    pub(crate) fn set_script(end_host: &str, script: &str) {
+        SCRIPTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(end_host.to_string(), script.to_string());
+    }
+
+    /// Runs a script in place of ssh, listening on the tunnel's local ports (as ssh would) while it runs
+    pub(crate) struct MockChild {
+        child: Arc<SharedChild>,
+        config: SshConfig,
+        events: Option<mpsc::Receiver<ChildEvent>>,
+        listeners: Vec<TcpListener>,
+    }
+
+    impl ChildProc for MockChild {
+        fn new(config: SshConfig) -> Result<SshTunnel<Self>> {
+            let listeners = config
+                .local_listeners()
+                .into_iter()
+                .map(|(address, port)| {
+                    TcpListener::bind((address, u16::try_from(port).unwrap()))
+                        .map_err(|err| Error::io("Failed to listen", err))
+                })
+                .collect::<Result<_>>()?;
+            let script = SCRIPTS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(config.end_host())
+                .cloned()
+                .unwrap_or_else(|| format!("echo {READY_MARKER}; exec sleep 60"));
+
+            let mut cmd = process::Command::new("sh");
+            cmd.args(["-c", &script])
+                .stdout(process::Stdio::piped())
+                .stderr(process::Stdio::piped());
+            let child = Arc::new(SharedChild::spawn(&mut cmd).map_err(Error::Spawn)?);
+
+            let (sender, events) = mpsc::channel();
+            let waited_child = child.clone();
+            thread::spawn(move || {
+                let exit_cond = super::exit_condition(waited_child.wait());
+                let _ = sender.send(ChildEvent::Exited(exit_cond));
+            });
+            Ok(Arc::new(Mutex::new(MockChild {
+                child,
+                config,
+                events: Some(events),
+                listeners,
+            })))
+        }
+
+        fn config(&self) -> &SshConfig {
+            &self.config
+        }
+
+        fn stdout(&mut self) -> Result<process::ChildStdout> {
+            self.child
+                .take_stdout()
+                .ok_or_else(|| Error::io("No stdout", io::Error::from(io::ErrorKind::NotFound)))
+        }
+
+        fn events(&mut self) -> Result<mpsc::Receiver<ChildEvent>> {
+            self.events
+                .take()
+                .ok_or_else(|| Error::io("No events", io::Error::from(io::ErrorKind::NotFound)))
+        }
+
+        fn exited(&mut self) -> Option<ExitCondition> {
+            match self.child.try_wait() {
+                Ok(Some(status)) => Some(super::exit_condition(Ok(status))),
+                _ => None,
+            }
+        }
+
+        fn exit_status(&mut self) -> SshStatus {
+            let mut stderr = String::new();
+            if let Some(mut child_stderr) = self.child.take_stderr() {
+                let _ = child_stderr.read_to_string(&mut stderr);
+            }
+            SshStatus::from_stderr(stderr.trim_end())
+        }
+
+        fn kill(&mut self) {
+            let _ = self.child.kill();
+            self.listeners.clear();
+        }
+    }
 }
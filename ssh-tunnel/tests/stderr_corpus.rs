@@ -0,0 +1,94 @@
+//! Classifies the stderr samples in `tests/stderr_corpus`, which were captured from real OpenSSH clients
+//!
+//! Each sample starts with `# openssh: <version>` and `# expect: <signal>` header lines, followed by ssh's stderr as it was
+//! printed. The tunnel joins the stderr lines together before classifying them, so each sample is checked both as printed
+//! and joined.
+
+use std::fs;
+use std::path::Path;
+
+use ssh_tunnel::status::SshStatus;
+
+struct Sample {
+    name: String,
+    version: String,
+    expect: String,
+    stderr: String,
+}
+
+fn load_samples() -> Vec<Sample> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/stderr_corpus");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .expect("Failed to read the stderr corpus")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let text = fs::read_to_string(path).unwrap();
+            let header = |key: &str| {
+                text.lines()
+                    .find_map(|line| line.strip_prefix(&format!("# {key}: ")))
+                    .unwrap_or_else(|| panic!("{} has no '{key}' header", path.display()))
+                    .to_string()
+            };
+
+            Sample {
+                name: path.file_name().unwrap().to_string_lossy().to_string(),
+                version: header("openssh"),
+                expect: header("expect"),
+                stderr: text
+                    .lines()
+                    .filter(|line| !line.starts_with("# "))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_stderr_corpus() {
+    let samples = load_samples();
+    assert!(!samples.is_empty());
+
+    for sample in samples {
+        let joined: String = sample.stderr.lines().collect();
+        for stderr in [&sample.stderr, &joined] {
+            assert_eq!(
+                SshStatus::from_stderr(stderr).to_signal(),
+                sample.expect,
+                "{} (OpenSSH {})",
+                sample.name,
+                sample.version
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stderr_corpus_versions() {
+    // Each failure that has changed its wording between versions should be covered by more than one of them
+    let samples = load_samples();
+    for signal in [
+        "HOST_KEY_CHANGED",
+        "UNPROTECTED_KEY",
+        "PORT_IN_USE",
+        "DENIED",
+    ] {
+        let mut versions: Vec<_> = samples
+            .iter()
+            .filter(|sample| sample.expect.split(':').next() == Some(signal))
+            .map(|sample| sample.version.as_str())
+            .collect();
+        versions.sort();
+        versions.dedup();
+        assert!(
+            versions.len() > 1,
+            "{signal} is only covered by {versions:?}"
+        );
+    }
+}